@@ -1,9 +1,410 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use secp256k1::ecdsa::Signature;
 use secp256k1::rand::rngs::OsRng;
-use secp256k1::Secp256k1;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use server::{
+    sign, verify, Authentication, AUTH_HEADER, EXPIRES_HEADER, IDENTITY_HEADER, MAX_EXPIRY_SECONDS,
+};
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
 
-fn main() {
-    let secp = Secp256k1::new();
-    let (secret_key, public_key) = secp.generate_keypair(&mut OsRng);
-    println!("Secret Key: {:?}", secret_key.display_secret().to_string());
-    println!("Public Key: {:?}", public_key.to_string());
+/// Manages the keys used to sign the companion's requests
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Generates a new keypair
+    Generate {
+        /// Writes the secret key to this file, readable only by the current user, instead of
+        /// printing it
+        #[arg(long)]
+        out: Option<PathBuf>,
+        /// Overwrites the file if it already exists
+        #[arg(long, requires = "out")]
+        force: bool,
+    },
+    /// Prints the public key of an existing secret key
+    Pubkey(SecretArgs),
+    /// Signs a request and prints its headers, ready to be passed to `curl -H`
+    Sign {
+        #[command(flatten)]
+        secret: SecretArgs,
+        #[command(flatten)]
+        request: RequestArgs,
+        /// Identity the key belongs to, adds the identity header
+        #[arg(long)]
+        identity: Option<String>,
+    },
+    /// Verifies a request's signature, fails when it's not valid
+    Verify {
+        /// Public key the request should be signed by
+        #[arg(long)]
+        pubkey: String,
+        /// Value of the request's auth header
+        #[arg(long)]
+        signature: String,
+        #[command(flatten)]
+        request: RequestArgs,
+    },
+    /// Prints the server and client options for a secret key
+    Env {
+        #[command(flatten)]
+        secret: SecretArgs,
+        #[arg(long, value_enum, default_value_t = Format::Env)]
+        format: Format,
+        /// Server url the client connects to
+        #[arg(long)]
+        client_url: Option<String>,
+        #[arg(long)]
+        pair: Option<String>,
+        #[arg(long)]
+        identity: Option<String>,
+    },
+}
+
+#[derive(Args)]
+struct SecretArgs {
+    /// Hex encoded secret key
+    #[arg(
+        long,
+        env = "SECRET_KEY",
+        hide_env_values = true,
+        required_unless_present = "secret_file"
+    )]
+    secret_key: Option<String>,
+    /// File holding the secret key, as written by `generate --out`
+    #[arg(long, conflicts_with = "secret_key")]
+    secret_file: Option<PathBuf>,
+}
+
+impl SecretArgs {
+    fn load(&self) -> Result<SecretKey, String> {
+        let secret_key = match (&self.secret_key, &self.secret_file) {
+            (_, Some(path)) => std::fs::read_to_string(path)
+                .map_err(|e| format!("could not read {}: {e}", path.display()))?,
+            (Some(secret_key), None) => secret_key.clone(),
+            (None, None) => return Err("missing secret key".to_string()),
+        };
+        SecretKey::from_str(secret_key.trim()).map_err(|e| format!("invalid secret key: {e}"))
+    }
+}
+
+/// The parts of a request that end up in its `Authentication` envelope
+#[derive(Args)]
+struct RequestArgs {
+    #[arg(long, default_value = "POST")]
+    method: String,
+    /// Path including the query, ex: /pair/default/message
+    #[arg(long)]
+    path: String,
+    /// Raw request body
+    #[arg(long, default_value = "", conflicts_with = "body_file")]
+    body: String,
+    /// Reads the raw body from a file, `-` reads it from stdin
+    #[arg(long)]
+    body_file: Option<PathBuf>,
+    /// The identity's current sequence, see `/pair/{pair}/sequence`
+    #[arg(long)]
+    sequence: u64,
+    /// Unix timestamp the signature expires at, a minute from now when signing
+    #[arg(long)]
+    expires: Option<i64>,
+}
+
+impl RequestArgs {
+    fn authentication(&self) -> Result<Authentication, String> {
+        let method = self
+            .method
+            .to_uppercase()
+            .parse()
+            .map_err(|_| format!("invalid method `{}`", self.method))?;
+        let body = match &self.body_file {
+            Some(path) if path == Path::new("-") => {
+                let mut body = vec![];
+                std::io::stdin()
+                    .read_to_end(&mut body)
+                    .map_err(|e| format!("could not read stdin: {e}"))?;
+                body
+            }
+            Some(path) => std::fs::read(path)
+                .map_err(|e| format!("could not read {}: {e}", path.display()))?,
+            None => self.body.as_bytes().to_vec(),
+        };
+        let expires = self
+            .expires
+            .unwrap_or_else(|| chrono::Utc::now().timestamp() + 60);
+
+        Ok(Authentication::new(
+            &method,
+            &self.path,
+            &body,
+            self.sequence,
+            expires,
+        ))
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// `.env` file
+    Env,
+    /// TOML config file
+    Toml,
+}
+
+/// Writes the secret key so only the current user can read it
+fn write_secret(path: &Path, secret_key: &SecretKey, force: bool) -> Result<(), String> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    match force {
+        true => options.create(true).truncate(true),
+        false => options.create_new(true),
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options
+        .open(path)
+        .map_err(|e| format!("could not create {}: {e}", path.display()))?;
+    // The mode is only applied to new files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .map_err(|e| format!("could not restrict {}: {e}", path.display()))?;
+    }
+    writeln!(file, "{}", secret_key.display_secret())
+        .map_err(|e| format!("could not write {}: {e}", path.display()))
+}
+
+fn headers(auth: &Authentication, signature: &Signature, identity: Option<&str>) -> String {
+    let mut headers = format!(
+        "{AUTH_HEADER}: {signature}\n{EXPIRES_HEADER}: {}\n",
+        auth.expires
+    );
+    if let Some(identity) = identity {
+        headers.push_str(&format!("{IDENTITY_HEADER}: {identity}\n"));
+    }
+    headers
+}
+
+fn snippet(
+    secret_key: &SecretKey,
+    format: Format,
+    client_url: Option<&str>,
+    pair: Option<&str>,
+    identity: Option<&str>,
+) -> String {
+    let public_key = PublicKey::from_secret_key(&Secp256k1::new(), secret_key);
+    let secret_key = secret_key.display_secret().to_string();
+    let server = [("public_key", Some(public_key.to_string()))];
+    let client = [
+        ("client_url", client_url.map(str::to_string)),
+        ("secret_key", Some(secret_key)),
+        ("pair", pair.map(str::to_string)),
+        ("identity", identity.map(str::to_string)),
+    ];
+
+    let mut snippet = String::new();
+    for (title, options) in [("server", &server[..]), ("client", &client[..])] {
+        snippet.push_str(&format!("# {title}\n"));
+        for (name, value) in options {
+            let Some(value) = value else {
+                continue;
+            };
+            let line = match format {
+                Format::Env => format!("{}=\"{value}\"\n", name.to_uppercase()),
+                Format::Toml => format!("{name} = \"{value}\"\n"),
+            };
+            snippet.push_str(&line);
+        }
+    }
+    snippet
+}
+
+fn run(cli: Cli) -> Result<(), String> {
+    match cli.command {
+        Command::Generate { out, force } => {
+            let (secret_key, public_key) = Secp256k1::new().generate_keypair(&mut OsRng);
+            match out {
+                Some(path) => {
+                    write_secret(&path, &secret_key, force)?;
+                    println!("Secret Key: written to {}", path.display());
+                }
+                None => println!("Secret Key: {}", secret_key.display_secret()),
+            }
+            println!("Public Key: {public_key}");
+        }
+        Command::Pubkey(secret) => {
+            let secret_key = secret.load()?;
+            println!(
+                "{}",
+                PublicKey::from_secret_key(&Secp256k1::new(), &secret_key)
+            );
+        }
+        Command::Sign {
+            secret,
+            request,
+            identity,
+        } => {
+            let auth = request.authentication()?;
+            let now = chrono::Utc::now().timestamp();
+            if auth.expires > now + MAX_EXPIRY_SECONDS {
+                eprintln!("warning: the server rejects signatures expiring more than {MAX_EXPIRY_SECONDS} seconds from now");
+            }
+            let signature = sign(&secret.load()?, &auth);
+            print!("{}", headers(&auth, &signature, identity.as_deref()));
+        }
+        Command::Verify {
+            pubkey,
+            signature,
+            request,
+        } => {
+            let pubkey =
+                PublicKey::from_str(&pubkey).map_err(|e| format!("invalid pubkey: {e}"))?;
+            let signature =
+                Signature::from_str(&signature).map_err(|e| format!("invalid signature: {e}"))?;
+            if request.expires.is_none() {
+                return Err("missing the expires header's value (`--expires`)".to_string());
+            }
+            if !verify(&pubkey, &request.authentication()?, &signature) {
+                return Err("signature does not match".to_string());
+            }
+            println!("valid");
+        }
+        Command::Env {
+            secret,
+            format,
+            client_url,
+            pair,
+            identity,
+        } => print!(
+            "{}",
+            snippet(
+                &secret.load()?,
+                format,
+                client_url.as_deref(),
+                pair.as_deref(),
+                identity.as_deref()
+            )
+        ),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::remove_file;
+
+    #[test]
+    fn cli() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn secret_file() {
+        let path = PathBuf::from("./secret_file_key");
+        let secret_key = SecretKey::from_byte_array(&[1; 32]).unwrap();
+        write_secret(&path, &secret_key, false).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // Existing keys are only replaced when forced
+        let other = SecretKey::from_byte_array(&[2; 32]).unwrap();
+        assert!(write_secret(&path, &other, false).is_err());
+        let loaded = SecretArgs {
+            secret_key: None,
+            secret_file: Some(path.clone()),
+        }
+        .load()
+        .unwrap();
+        assert_eq!(loaded, secret_key);
+        write_secret(&path, &other, true).unwrap();
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let secret_key = SecretKey::from_byte_array(&[1; 32]).unwrap();
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+        let request = RequestArgs {
+            method: "post".to_string(),
+            path: "/pair/default/active".to_string(),
+            body: r#"{"active":true}"#.to_string(),
+            body_file: None,
+            sequence: 3,
+            expires: Some(100),
+        };
+
+        let auth = request.authentication().unwrap();
+        assert_eq!(auth.method, "POST");
+        let signature = sign(&secret_key, &auth);
+        assert!(verify(&public_key, &auth, &signature));
+
+        let other = RequestArgs {
+            sequence: 4,
+            ..request
+        };
+        assert!(!verify(
+            &public_key,
+            &other.authentication().unwrap(),
+            &signature
+        ));
+
+        assert_eq!(
+            headers(&auth, &signature, Some("partner")),
+            format!("auth: {signature}\nexpires: 100\nidentity: partner\n")
+        );
+    }
+
+    #[test]
+    fn config_snippets() {
+        let secret_key = SecretKey::from_byte_array(&[1; 32]).unwrap();
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+        let secret = "01".repeat(32);
+
+        assert_eq!(
+            snippet(&secret_key, Format::Env, None, Some("default"), None),
+            format!("# server\nPUBLIC_KEY=\"{public_key}\"\n# client\nSECRET_KEY=\"{secret}\"\nPAIR=\"default\"\n")
+        );
+        assert_eq!(
+            snippet(
+                &secret_key,
+                Format::Toml,
+                Some("http://0.0.0.0:3000"),
+                None,
+                Some("partner")
+            ),
+            format!("# server\npublic_key = \"{public_key}\"\n# client\nclient_url = \"http://0.0.0.0:3000\"\nsecret_key = \"{secret}\"\nidentity = \"partner\"\n")
+        );
+    }
 }
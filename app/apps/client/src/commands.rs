@@ -0,0 +1,232 @@
+//! Non-interactive commands so the pair can be driven from scripts, cron or shortcuts.
+//!
+//! Every command exits with an error when the server rejects the request.
+
+use crate::{tick_to_string, undo};
+use chrono::{DateTime, Utc};
+use clap::Subcommand;
+use companion_client::CompanionClient;
+use serde::Serialize;
+use server::{Message, Tick, TickType};
+use std::error::Error;
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Reads or sets the pair's message
+    #[command(subcommand)]
+    Message(MessageCommand),
+    /// Ticks a tick type by name or id
+    Tick { name: String },
+    /// Deletes the latest tick if it was made in the last few minutes
+    Undo,
+    /// Prints today's ticks, newest first
+    History {
+        /// Prints the ticks as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Prints whether the server is up, if the pair is active and its message
+    Status {
+        /// Prints the status as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Pauses the pair
+    Pause,
+    /// Resumes a paused pair
+    Resume,
+}
+
+#[derive(Subcommand)]
+pub enum MessageCommand {
+    /// Prints the current message
+    Get,
+    /// Sets the message
+    Set {
+        message: String,
+        /// When the message stops being shown, RFC 3339
+        #[arg(long)]
+        expires_at: Option<DateTime<Utc>>,
+    },
+}
+
+/// A tick along with its type's name
+#[derive(Serialize)]
+struct HistoryEntry {
+    id: u64,
+    tick: u8,
+    name: Option<String>,
+    time: String,
+    author: String,
+}
+
+/// Everything but `healthy` is missing when the server couldn't be reached
+#[derive(Serialize)]
+struct Status {
+    healthy: bool,
+    active: Option<bool>,
+    message: Option<String>,
+    ticks_today: Option<usize>,
+}
+
+/// Finds a tick type that can be ticked by its name, ignoring case, or its id
+fn find_tick<'a>(tick_types: &'a [TickType], name: &str) -> Option<&'a TickType> {
+    let active = || tick_types.iter().filter(|tick| !tick.archived);
+    active()
+        .find(|tick| tick.tick.eq_ignore_ascii_case(name))
+        .or_else(|| active().find(|tick| name.parse() == Ok(tick.id)))
+}
+
+fn history_entries(tick_types: &[TickType], history: Vec<Tick>) -> Vec<HistoryEntry> {
+    history
+        .into_iter()
+        .rev()
+        .map(|t| HistoryEntry {
+            name: tick_types
+                .iter()
+                .find(|tick| tick.id == t.tick)
+                .map(|tick| tick.tick.clone()),
+            id: t.id,
+            tick: t.tick,
+            time: t.time,
+            author: t.author,
+        })
+        .collect()
+}
+
+impl Command {
+    pub async fn run(self, client: &CompanionClient) -> Result<(), Box<dyn Error>> {
+        match self {
+            Command::Message(MessageCommand::Get) => println!("{}", client.message().await?),
+            Command::Message(MessageCommand::Set {
+                message,
+                expires_at,
+            }) => {
+                let message = Message {
+                    message,
+                    expires_at,
+                };
+                client.set_message(&message).await?
+            }
+            Command::Tick { name } => {
+                let tick_types = client.ticks(false).await?;
+                let Some(tick_type) = find_tick(&tick_types, &name) else {
+                    let names: Vec<&str> = tick_types
+                        .iter()
+                        .filter(|tick| !tick.archived)
+                        .map(|tick| tick.tick.as_str())
+                        .collect();
+                    return Err(format!(
+                        "unknown tick `{name}`, expected one of: {}",
+                        names.join(", ")
+                    )
+                    .into());
+                };
+                client.tick(tick_type.id).await?
+            }
+            Command::Undo => {
+                client.delete_tick(&undo()).await?;
+            }
+            Command::History { json } => {
+                // Archived types are still needed to name old ticks
+                let tick_types = client.ticks(true).await?;
+                let history = client.tick_history().await?;
+                match json {
+                    true => println!(
+                        "{}",
+                        serde_json::to_string(&history_entries(&tick_types, history)).unwrap()
+                    ),
+                    false => {
+                        for line in tick_to_string(&tick_types, history) {
+                            println!("{line}");
+                        }
+                    }
+                }
+            }
+            Command::Status { json } => {
+                let status = Status {
+                    healthy: client.healthy().await,
+                    active: client.active().await.ok(),
+                    message: client.message().await.ok(),
+                    ticks_today: client.tick_history().await.ok().map(|ticks| ticks.len()),
+                };
+                match json {
+                    true => println!("{}", serde_json::to_string(&status).unwrap()),
+                    false => {
+                        let unknown = || "unknown".to_string();
+                        println!("Server: {}", if status.healthy { "Ok" } else { "Error" });
+                        let active = status.active.map(|active| match active {
+                            true => "yes".to_string(),
+                            false => "paused".to_string(),
+                        });
+                        println!("Active: {}", active.unwrap_or_else(unknown));
+                        println!("Message: {}", status.message.unwrap_or_else(unknown));
+                        let ticks_today = status.ticks_today.map(|ticks| ticks.to_string());
+                        println!("Ticks today: {}", ticks_today.unwrap_or_else(unknown));
+                    }
+                }
+            }
+            Command::Pause => client.set_active(false).await?,
+            Command::Resume => client.set_active(true).await?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tick_type(id: u8, tick: &str, archived: bool) -> TickType {
+        TickType {
+            id,
+            tick: tick.to_string(),
+            icon: None,
+            archived,
+        }
+    }
+
+    #[test]
+    fn tick_lookup() {
+        let tick_types = vec![
+            tick_type(1, "Coffee", false),
+            tick_type(2, "Walk", true),
+            tick_type(3, "1", false),
+        ];
+        let found = |name| find_tick(&tick_types, name).map(|tick| tick.id);
+
+        assert_eq!(found("coffee"), Some(1));
+        assert_eq!(found("COFFEE"), Some(1));
+        // Names win over ids
+        assert_eq!(found("1"), Some(3));
+        assert_eq!(found("3"), Some(3));
+        // Archived types can't be ticked by name or id
+        assert_eq!(found("walk"), None);
+        assert_eq!(found("2"), None);
+        assert_eq!(found("tea"), None);
+    }
+
+    #[test]
+    fn history_names() {
+        let tick_types = vec![tick_type(1, "Coffee", false), tick_type(2, "Walk", true)];
+        let tick = |id, tick| Tick {
+            id,
+            tick,
+            time: format!("2024-11-04 0{id}:00:00"),
+            author: "default".to_string(),
+        };
+        let entries = history_entries(&tick_types, vec![tick(1, 1), tick(2, 2), tick(3, 9)]);
+
+        // Newest first, archived types keep their name and unknown ones have none
+        let names: Vec<(u64, Option<&str>)> = entries
+            .iter()
+            .map(|entry| (entry.id, entry.name.as_deref()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![(3, None), (2, Some("Walk")), (1, Some("Coffee"))]
+        );
+        assert_eq!(entries[0].tick, 9);
+        assert_eq!(entries[2].time, "2024-11-04 01:00:00");
+    }
+}
@@ -1,18 +1,27 @@
-use dotenv_codegen::dotenv;
+use clap::Parser;
+use commands::Command;
+use companion_client::CompanionClient;
+use dotenv::dotenv;
 use ratatui::crossterm::event;
 use ratatui::crossterm::event::{Event, KeyCode};
 use ratatui::crossterm::terminal::{disable_raw_mode, enable_raw_mode};
-use ratatui::prelude::{
-    Color, Constraint, CrosstermBackend, Direction, Layout, Style, Stylize, Text,
-};
+use ratatui::prelude::{Color, Constraint, CrosstermBackend, Direction, Layout, Style, Text};
 use ratatui::widgets::{Block, Borders, List, ListItem, Paragraph};
+use ratatui::Frame;
 use ratatui::Terminal;
-use reqwest::{Client, Response, Url};
-use secp256k1::SecretKey;
-use serde::Serialize;
-use server::{sign, Active, Message, Tick, TickType, TriggerTick};
+use server::options::ClientArgs;
+use server::{AddTickType, DeleteTick, Message, StoredMessage, Tick, TickType, UpdateTickType};
 use std::io;
-use std::str::FromStr;
+use std::process::exit;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+mod commands;
+
+/// How far back undoing a tick reaches
+const UNDO_MINUTES: u32 = 10;
+/// Seconds to wait before reconnecting to the server's events
+const RECONNECT_SECONDS: u64 = 5;
 //
 // #[tokio::main]
 // async fn main() -> io::Result<()> {
@@ -48,100 +57,60 @@ use std::str::FromStr;
 //     // dbg!(get_active(&url).await);
 // }
 
-async fn get_sequence(url: &Url) -> u64 {
-    reqwest::get(url.join("/sequence").unwrap())
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap()
-        .parse()
-        .unwrap()
-}
-
-async fn get_message(url: &Url) -> String {
-    reqwest::get(url.join("/message").unwrap())
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap()
-}
-
-async fn get_active(url: &Url) -> bool {
-    reqwest::get(url.join("/active").unwrap())
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap()
-        .parse()
-        .unwrap()
-}
-
-async fn post<T: Serialize>(url: &Url, path: &str, privkey: &SecretKey, message: T) -> Response {
-    let sequence = get_sequence(&url).await;
-    Client::builder()
-        .build()
-        .unwrap()
-        .post(url.join(path).unwrap())
-        .json(&message)
-        .header("auth", sign(privkey, message, sequence).to_string())
-        .send()
-        .await
-        .unwrap()
-}
-
-async fn set_message(url: &Url, privkey: &SecretKey, message: String) {
-    post(url, "/message", privkey, Message { message }).await;
-}
-
-async fn set_active(url: &Url, privkey: &SecretKey, active: bool) {
-    post(url, "/active", privkey, Active { active }).await;
-}
-
-async fn get_ticks(url: &Url) -> Vec<TickType> {
-    reqwest::get(url.join("/ticks").unwrap())
-        .await
-        .unwrap()
-        .json()
-        .await
-        .unwrap()
-}
-
-async fn get_tick_history(url: &Url) -> Vec<Tick> {
-    reqwest::get(url.join("/tick_history").unwrap())
-        .await
-        .unwrap()
-        .json()
-        .await
-        .unwrap()
+/// Notifies every time the server sends an event for the pair
+async fn listen(client: CompanionClient, changes: mpsc::UnboundedSender<()>) {
+    loop {
+        if let Ok(mut response) = client.events().await {
+            while let Ok(Some(chunk)) = response.chunk().await {
+                // Keep alive comments don't change anything
+                let event = chunk
+                    .split(|byte| *byte == b'\n')
+                    .any(|line| line.starts_with(b"event:"));
+                if event && changes.send(()).is_err() {
+                    return;
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(RECONNECT_SECONDS)).await;
+    }
 }
 
-async fn tick(url: &Url, privkey: &SecretKey, tick: u8) {
-    post(url, "/tick", privkey, TriggerTick { ty: tick }).await;
+enum Screen {
+    Main,
+    /// Tick type management
+    TickTypes,
 }
 
-async fn healthy(url: &Url) -> bool {
-    reqwest::get(url.join("/").unwrap())
-        .await
-        .unwrap()
-        .status()
-        .is_success()
+/// What the text typed in the tick type management screen is for
+enum TickTypeInput {
+    None,
+    Add,
+    Rename,
+    Icon,
 }
 
 enum SelectedWindow {
     Text,
     Tick,
     TickHistory,
+    Messages,
 }
 
 struct App {
-    url: Url,
-    priv_key: SecretKey,
+    client: CompanionClient,
     server_message: String,
+    /// Tick types that can be ticked
     ticks: Vec<TickType>,
+    /// Every tick type including archived ones
+    tick_types: Vec<TickType>,
     status: bool,
+    /// Whether the pair is active, it's paused otherwise
+    active: bool,
+
+    screen: Screen,
+    selected_tick_type: usize,
+    tick_type_input: TickTypeInput,
+    tick_type_text: String,
 
     selected: SelectedWindow,
 
@@ -150,14 +119,25 @@ struct App {
 
     scroll_offset: usize,
     tick_history: Vec<String>,
+
+    message_scroll: usize,
+    messages: Vec<String>,
 }
 
-fn tick_to_string(ticks: &Vec<TickType>, tick_history: Vec<Tick>) -> Vec<String> {
+/// Deletes the latest tick if it was ticked recently
+fn undo() -> DeleteTick {
+    DeleteTick {
+        within_minutes: Some(UNDO_MINUTES),
+        ..Default::default()
+    }
+}
+
+fn tick_to_string(ticks: &[TickType], tick_history: Vec<Tick>) -> Vec<String> {
     tick_history
         .iter()
         .map(|t| {
             format!(
-                "{} - {}",
+                "{} - {} by {}",
                 ticks
                     .iter()
                     .find_map(|tick| if t.tick == tick.id {
@@ -166,56 +146,215 @@ fn tick_to_string(ticks: &Vec<TickType>, tick_history: Vec<Tick>) -> Vec<String>
                         None
                     })
                     .unwrap(),
-                t.time
+                t.time,
+                t.author
             )
         })
         .rev()
         .collect()
 }
 
+fn message_to_string(messages: Vec<StoredMessage>) -> Vec<String> {
+    messages
+        .into_iter()
+        .map(|m| format!("{} - {}: {}", m.time, m.author, m.message))
+        .collect()
+}
+
+fn active_ticks(tick_types: &[TickType]) -> Vec<TickType> {
+    tick_types
+        .iter()
+        .filter(|tick| !tick.archived)
+        .cloned()
+        .collect()
+}
+
 impl App {
-    async fn new(url: Url, priv_key: SecretKey) -> App {
-        let status = healthy(&url).await;
-        let server_message = get_message(&url).await;
-        let ticks = get_ticks(&url).await;
-        let tick_history = tick_to_string(&ticks, get_tick_history(&url).await);
+    async fn new(client: CompanionClient) -> App {
+        let status = client.healthy().await;
+        let server_message = client.message().await.unwrap_or_default();
+        let active = client.active().await.unwrap_or_default();
+        let tick_types = client.ticks(true).await.unwrap_or_default();
+        let tick_history =
+            tick_to_string(&tick_types, client.tick_history().await.unwrap_or_default());
+        let messages = message_to_string(client.messages().await.unwrap_or_default());
 
         App {
-            url,
-            priv_key,
-            ticks,
+            client,
+            ticks: active_ticks(&tick_types),
+            tick_types,
+            screen: Screen::Main,
+            selected_tick_type: 0,
+            tick_type_input: TickTypeInput::None,
+            tick_type_text: String::new(),
             tick_history,
             status,
+            active,
             server_message,
             local_message: String::new(),
             selected_action: 0,
             selected: SelectedWindow::Text,
             scroll_offset: 0,
+            messages,
+            message_scroll: 0,
         }
     }
 
+    /// Fetches the server's state again and clears any input
     pub async fn reload(&mut self) {
-        self.status = healthy(&self.url).await;
-        self.ticks = get_ticks(&self.url).await;
         self.local_message.clear();
-        self.server_message = get_message(&self.url).await;
-        self.tick_history = tick_to_string(&self.ticks, get_tick_history(&self.url).await);
         self.scroll_offset = 0;
+        self.message_scroll = 0;
+        self.refresh().await;
+    }
+
+    /// Fetches the server's state again without touching what's being typed
+    pub async fn refresh(&mut self) {
+        self.status = self.client.healthy().await;
+        self.active = self.client.active().await.unwrap_or_default();
+        // Archived types are still needed to show old ticks
+        self.tick_types = self.client.ticks(true).await.unwrap_or_default();
+        self.ticks = active_ticks(&self.tick_types);
+        self.selected_action = self.selected_action.min(self.ticks.len().saturating_sub(1));
+        self.selected_tick_type = self
+            .selected_tick_type
+            .min(self.tick_types.len().saturating_sub(1));
+        self.server_message = self.client.message().await.unwrap_or_default();
+        let tick_history = self.client.tick_history().await.unwrap_or_default();
+        self.tick_history = tick_to_string(&self.tick_types, tick_history);
+        self.scroll_offset = self
+            .scroll_offset
+            .min(self.tick_history.len().saturating_sub(1));
+        self.messages = message_to_string(self.client.messages().await.unwrap_or_default());
+        self.message_scroll = self
+            .message_scroll
+            .min(self.messages.len().saturating_sub(1));
+    }
+
+    /// Whether keys are being typed into a text field
+    fn typing(&self) -> bool {
+        match self.screen {
+            Screen::Main => matches!(self.selected, SelectedWindow::Text),
+            Screen::TickTypes => !matches!(self.tick_type_input, TickTypeInput::None),
+        }
+    }
+
+    /// Moves the selected tick type up or down among the ones that aren't archived
+    async fn move_tick_type(&mut self, up: bool) {
+        let Some(selected) = self.tick_types.get(self.selected_tick_type) else {
+            return;
+        };
+        let mut ids: Vec<u8> = self.ticks.iter().map(|tick| tick.id).collect();
+        let Some(position) = ids.iter().position(|id| *id == selected.id) else {
+            return;
+        };
+        let target = match up {
+            true if position > 0 => position - 1,
+            false if position + 1 < ids.len() => position + 1,
+            _ => return,
+        };
+        ids.swap(position, target);
+        let _ = self.client.reorder_tick_types(ids).await;
+        self.reload().await;
+        self.selected_tick_type = target;
+    }
+
+    async fn handle_tick_type_input(&mut self, key: KeyCode) {
+        if !matches!(self.tick_type_input, TickTypeInput::None) {
+            match key {
+                KeyCode::Enter => {
+                    let text = std::mem::take(&mut self.tick_type_text);
+                    let selected = self.tick_types.get(self.selected_tick_type).map(|t| t.id);
+                    match (&self.tick_type_input, selected) {
+                        (TickTypeInput::Add, _) => {
+                            let add = AddTickType {
+                                tick: text,
+                                icon: None,
+                            };
+                            let _ = self.client.add_tick_type(&add).await;
+                        }
+                        (TickTypeInput::Rename, Some(id)) => {
+                            let update = UpdateTickType {
+                                tick: Some(text),
+                                ..Default::default()
+                            };
+                            let _ = self.client.update_tick_type(id, &update).await;
+                        }
+                        (TickTypeInput::Icon, Some(id)) => {
+                            let update = UpdateTickType {
+                                icon: Some(text),
+                                ..Default::default()
+                            };
+                            let _ = self.client.update_tick_type(id, &update).await;
+                        }
+                        _ => {}
+                    }
+                    self.tick_type_input = TickTypeInput::None;
+                    self.reload().await;
+                }
+                KeyCode::Esc => {
+                    self.tick_type_text.clear();
+                    self.tick_type_input = TickTypeInput::None;
+                }
+                KeyCode::Char(c) => self.tick_type_text.push(c),
+                KeyCode::Backspace => {
+                    self.tick_type_text.pop();
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        match key {
+            KeyCode::Up => {
+                self.selected_tick_type = self.selected_tick_type.saturating_sub(1);
+            }
+            KeyCode::Down if self.selected_tick_type + 1 < self.tick_types.len() => {
+                self.selected_tick_type += 1;
+            }
+            KeyCode::Char('a') => self.tick_type_input = TickTypeInput::Add,
+            KeyCode::Char('r') => self.tick_type_input = TickTypeInput::Rename,
+            KeyCode::Char('i') => self.tick_type_input = TickTypeInput::Icon,
+            KeyCode::Char('x') => {
+                if let Some(selected) = self.tick_types.get(self.selected_tick_type) {
+                    let update = UpdateTickType {
+                        archived: Some(!selected.archived),
+                        ..Default::default()
+                    };
+                    let _ = self.client.update_tick_type(selected.id, &update).await;
+                    self.reload().await;
+                }
+            }
+            KeyCode::Char('k') => self.move_tick_type(true).await,
+            KeyCode::Char('j') => self.move_tick_type(false).await,
+            KeyCode::Esc | KeyCode::Char('m') => self.screen = Screen::Main,
+            _ => {}
+        }
     }
 
     fn next_mode(&mut self) {
         self.selected = match self.selected {
             SelectedWindow::Text => SelectedWindow::Tick,
             SelectedWindow::Tick => SelectedWindow::TickHistory,
-            SelectedWindow::TickHistory => SelectedWindow::Text,
+            SelectedWindow::TickHistory => SelectedWindow::Messages,
+            SelectedWindow::Messages => SelectedWindow::Text,
         }
     }
 
     async fn handle_input(&mut self, key: KeyCode) {
+        if matches!(self.screen, Screen::TickTypes) {
+            self.handle_tick_type_input(key).await;
+            return;
+        }
+
         match self.selected {
             SelectedWindow::Text => match key {
                 KeyCode::Enter => {
-                    set_message(&self.url, &self.priv_key, self.local_message.clone()).await;
+                    let message = Message {
+                        message: self.local_message.clone(),
+                        expires_at: None,
+                    };
+                    let _ = self.client.set_message(&message).await;
                     self.reload().await;
                 }
                 KeyCode::Char(c) => self.local_message.push(c),
@@ -229,28 +368,52 @@ impl App {
                 KeyCode::Up => {
                     self.selected_action = self.selected_action.saturating_sub(1);
                 }
-                KeyCode::Down => {
-                    if self.selected_action < self.ticks.len() - 1 {
-                        self.selected_action += 1;
-                    }
+                KeyCode::Down if self.selected_action + 1 < self.ticks.len() => {
+                    self.selected_action += 1;
                 }
                 KeyCode::Tab => self.next_mode(),
                 KeyCode::Enter => {
-                    tick(&self.url, &self.priv_key, self.selected_action as u8 + 1).await;
+                    if let Some(selected) = self.ticks.get(self.selected_action) {
+                        let _ = self.client.tick(selected.id).await;
+                        self.reload().await;
+                    }
+                }
+                KeyCode::Char('m') => self.screen = Screen::TickTypes,
+                KeyCode::Char('u') => {
+                    let _ = self.client.delete_tick(&undo()).await;
+                    self.reload().await;
+                }
+                KeyCode::Char('p') => {
+                    let _ = self.client.set_active(!self.active).await;
                     self.reload().await;
                 }
                 _ => {}
             },
             SelectedWindow::TickHistory => match key {
+                KeyCode::Up if self.scroll_offset > 0 => {
+                    self.scroll_offset -= 1;
+                }
+                KeyCode::Down if self.scroll_offset < self.tick_history.len().saturating_sub(1) => {
+                    self.scroll_offset += 1;
+                }
+                KeyCode::Tab => self.next_mode(),
+                KeyCode::Char('m') => self.screen = Screen::TickTypes,
+                KeyCode::Char('u') => {
+                    let _ = self.client.delete_tick(&undo()).await;
+                    self.reload().await;
+                }
+                KeyCode::Char('p') => {
+                    let _ = self.client.set_active(!self.active).await;
+                    self.reload().await;
+                }
+                _ => {}
+            },
+            SelectedWindow::Messages => match key {
                 KeyCode::Up => {
-                    if self.scroll_offset > 0 {
-                        self.scroll_offset -= 1;
-                    }
+                    self.message_scroll = self.message_scroll.saturating_sub(1);
                 }
-                KeyCode::Down => {
-                    if self.scroll_offset < self.tick_history.len().saturating_sub(1) {
-                        self.scroll_offset += 1;
-                    }
+                KeyCode::Down if self.message_scroll + 1 < self.messages.len() => {
+                    self.message_scroll += 1;
                 }
                 KeyCode::Tab => self.next_mode(),
                 _ => {}
@@ -259,10 +422,88 @@ impl App {
     }
 }
 
+fn draw_tick_types(frame: &mut Frame, app: &App) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Min(3),
+            Constraint::Length(3),
+            Constraint::Length(3),
+        ])
+        .split(frame.area());
+
+    let items: Vec<ListItem> = app
+        .tick_types
+        .iter()
+        .map(|tick| {
+            let text = format!(
+                "{} {} {}{}",
+                tick.id,
+                tick.icon.as_deref().unwrap_or(" "),
+                tick.tick,
+                if tick.archived { " (archived)" } else { "" }
+            );
+            ListItem::new(Text::from(text))
+        })
+        .collect();
+    let tick_types = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title("Tick Types"))
+        .highlight_style(Style::default().fg(Color::Yellow))
+        .highlight_symbol("> ");
+    frame.render_stateful_widget(
+        tick_types,
+        chunks[0],
+        &mut ratatui::widgets::ListState::default().with_selected(Some(app.selected_tick_type)),
+    );
+
+    let title = match app.tick_type_input {
+        TickTypeInput::None => "Input",
+        TickTypeInput::Add => "New Tick Type",
+        TickTypeInput::Rename => "Rename",
+        TickTypeInput::Icon => "Icon",
+    };
+    let input = Paragraph::new(app.tick_type_text.as_str())
+        .style(Style::default().fg(Color::Yellow))
+        .block(Block::default().borders(Borders::ALL).title(title));
+    frame.render_widget(input, chunks[1]);
+
+    let help = Paragraph::new(
+        "a: add  r: rename  i: icon  x: archive/restore  k/j: move up/down  esc: back",
+    )
+    .block(Block::default().borders(Borders::ALL).title("Keys"));
+    frame.render_widget(help, chunks[2]);
+}
+
+#[derive(Parser)]
+#[command(about = "Long Distance Companion client")]
+struct Cli {
+    #[command(flatten)]
+    args: ClientArgs,
+    /// Runs a single command instead of the interactive client
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), io::Error> {
-    let url = Url::parse(dotenv!("CLIENT_URL")).unwrap();
-    let priv_key = SecretKey::from_str(dotenv!("SECRET_KEY")).unwrap();
+    dotenv().ok();
+    let cli = Cli::parse();
+    let options = match cli.args.resolve() {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {err}");
+            exit(2);
+        }
+    };
+    let client = CompanionClient::from_options(options);
+
+    if let Some(command) = cli.command {
+        if let Err(err) = command.run(&client).await {
+            eprintln!("error: {err}");
+            exit(1);
+        }
+        return Ok(());
+    }
 
     enable_raw_mode()?;
     let stdout = io::stdout();
@@ -270,10 +511,18 @@ async fn main() -> Result<(), io::Error> {
     let mut terminal = Terminal::new(backend)?;
     terminal.clear()?;
 
-    let mut app = App::new(url, priv_key).await;
+    let (changes_sender, mut changes) = mpsc::unbounded_channel();
+    tokio::spawn(listen(client.clone(), changes_sender));
+
+    let mut app = App::new(client).await;
 
     loop {
         terminal.draw(|frame| {
+            if matches!(app.screen, Screen::TickTypes) {
+                draw_tick_types(frame, &app);
+                return;
+            }
+
             let chunks = Layout::default()
                 .direction(Direction::Vertical)
                 .constraints([
@@ -282,10 +531,14 @@ async fn main() -> Result<(), io::Error> {
                     Constraint::Length(3),
                     Constraint::Min(3),
                 ])
-                .split(frame.size());
+                .split(frame.area());
 
             // Status display
-            let status_text = format!("Status: {}", if app.status { "Ok" } else { "Error" });
+            let status_text = format!(
+                "Status: {}{}",
+                if app.status { "Ok" } else { "Error" },
+                if app.active { "" } else { " (paused)" }
+            );
             let status = Paragraph::new(status_text)
                 .block(Block::default().borders(Borders::ALL).title("Status"));
             frame.render_widget(status, chunks[0]);
@@ -306,9 +559,9 @@ async fn main() -> Result<(), io::Error> {
             // Local message input
             let input = Paragraph::new(app.local_message.as_str())
                 .style(if matches!(app.selected, SelectedWindow::Text) {
-                    selected_style.clone()
+                    selected_style
                 } else {
-                    style.clone()
+                    style
                 })
                 .block(
                     Block::default()
@@ -317,10 +570,14 @@ async fn main() -> Result<(), io::Error> {
                 );
             frame.render_widget(input, chunks[2]);
 
-            // Split bottom section into two columns
+            // Split bottom section into three columns
             let bottom_chunks = Layout::default()
                 .direction(Direction::Horizontal)
-                .constraints([Constraint::Percentage(30), Constraint::Percentage(70)])
+                .constraints([
+                    Constraint::Percentage(20),
+                    Constraint::Percentage(40),
+                    Constraint::Percentage(40),
+                ])
                 .split(chunks[3]);
 
             // Action selection
@@ -331,11 +588,15 @@ async fn main() -> Result<(), io::Error> {
                 .collect();
             let actions = List::new(items)
                 .style(if matches!(app.selected, SelectedWindow::Tick) {
-                    selected_style.clone()
+                    selected_style
                 } else {
-                    style.clone()
+                    style
                 })
-                .block(Block::default().borders(Borders::ALL).title("Ticks"))
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title("Ticks (m: manage, u: undo, p: pause)"),
+                )
                 .highlight_style(Style::default().fg(Color::Yellow))
                 .highlight_symbol("> ");
             frame.render_stateful_widget(
@@ -352,9 +613,9 @@ async fn main() -> Result<(), io::Error> {
                 .collect();
             let items_list = List::new(items)
                 .style(if matches!(app.selected, SelectedWindow::TickHistory) {
-                    selected_style.clone()
+                    selected_style
                 } else {
-                    style.clone()
+                    style
                 })
                 .block(Block::default().borders(Borders::ALL).title("Tick History"))
                 .highlight_style(Style::default().fg(Color::Yellow))
@@ -364,13 +625,44 @@ async fn main() -> Result<(), io::Error> {
                 bottom_chunks[1],
                 &mut ratatui::widgets::ListState::default().with_selected(Some(app.scroll_offset)),
             );
+
+            let items: Vec<ListItem> = app
+                .messages
+                .iter()
+                .map(|item| ListItem::new(Text::from(item.clone())))
+                .collect();
+            let messages = List::new(items)
+                .style(if matches!(app.selected, SelectedWindow::Messages) {
+                    selected_style
+                } else {
+                    style
+                })
+                .block(Block::default().borders(Borders::ALL).title("Messages"))
+                .highlight_style(Style::default().fg(Color::Yellow))
+                .highlight_symbol("> ");
+            frame.render_stateful_widget(
+                messages,
+                bottom_chunks[2],
+                &mut ratatui::widgets::ListState::default().with_selected(Some(app.message_scroll)),
+            );
         })?;
 
-        if let Event::Key(key) = event::read()? {
-            if key.code == KeyCode::Char('q') && !matches!(app.selected, SelectedWindow::Text) {
-                break;
+        if event::poll(Duration::from_millis(200))? {
+            if let Event::Key(key) = event::read()? {
+                if key.code == KeyCode::Char('q') && !app.typing() {
+                    break;
+                }
+                app.handle_input(key.code).await;
             }
-            app.handle_input(key.code).await;
+        }
+
+        // Several events arriving together only need a single refresh
+        let mut changed = false;
+        while changes.try_recv().is_ok() {
+            changed = true;
+        }
+        if changed {
+            app.refresh().await;
         }
     }
 
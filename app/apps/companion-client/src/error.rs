@@ -0,0 +1,55 @@
+use reqwest::StatusCode;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The request could not be sent or its response could not be read
+    Http(reqwest::Error),
+    /// The server rejected the request
+    Server {
+        status: StatusCode,
+        /// Stable error code, see `AppError::code`
+        code: String,
+        message: String,
+    },
+    /// The response is not what the endpoint returns
+    InvalidResponse(String),
+    InvalidUrl(url::ParseError),
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+impl ClientError {
+    /// The server's error code when it rejected the request
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            ClientError::Server { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Http(err) => write!(f, "request failed: {err}"),
+            ClientError::Server { code, message, .. } => write!(f, "{message} ({code})"),
+            ClientError::InvalidResponse(err) => write!(f, "invalid response: {err}"),
+            ClientError::InvalidUrl(err) => write!(f, "invalid url: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        ClientError::Http(err)
+    }
+}
+
+impl From<url::ParseError> for ClientError {
+    fn from(err: url::ParseError) -> Self {
+        ClientError::InvalidUrl(err)
+    }
+}
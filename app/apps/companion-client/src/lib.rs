@@ -0,0 +1,281 @@
+//! Typed client for a pair's endpoints.
+//!
+//! Signed requests reuse the identity's last known sequence, when another client consumed it
+//! the server answers `401 unauthorized` and the request is signed again with a fresh one.
+
+mod error;
+
+pub use error::{ClientError, ClientResult};
+
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Method, Response, Url};
+use secp256k1::SecretKey;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use server::options::ClientOptions;
+use server::{
+    sign, Active, AddTickType, Authentication, DeleteTick, ErrorBody, Message, ReorderTickTypes,
+    StoredMessage, Tick, TickType, TriggerTick, UpdateTickType, AUTH_HEADER, EXPIRES_HEADER,
+    IDENTITY_HEADER,
+};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds a signed request stays valid for
+pub const SIGNATURE_LIFETIME: i64 = 60;
+/// How many times a request is signed again after its sequence was already used
+pub const DEFAULT_RETRIES: u32 = 2;
+/// Server error codes that are fixed by signing the request again
+const RETRY_CODES: [&str; 2] = ["unauthorized", "expired"];
+
+#[derive(Clone)]
+pub struct CompanionClient {
+    /// Shared so every request reuses the same connections
+    http: Client,
+    /// The pair's base url, every pair endpoint is relative to it
+    url: Url,
+    secret_key: SecretKey,
+    /// The pair's own identity when not set
+    identity: Option<String>,
+    retries: u32,
+    /// The next sequence to sign with, fetched again when unknown
+    sequence: Arc<Mutex<Option<u64>>>,
+}
+
+impl CompanionClient {
+    /// `url` is the pair's base url, ex: `http://localhost:3000/pair/default/`
+    pub fn new(url: Url, secret_key: SecretKey) -> Self {
+        Self {
+            http: Client::new(),
+            url,
+            secret_key,
+            identity: None,
+            retries: DEFAULT_RETRIES,
+            sequence: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn from_options(options: ClientOptions) -> Self {
+        let client = Self::new(options.url, options.secret_key);
+        match options.identity {
+            Some(identity) => client.with_identity(identity),
+            None => client,
+        }
+    }
+
+    /// Signs requests as another of the pair's identities
+    pub fn with_identity(mut self, identity: String) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Turns error responses into the server's error
+    async fn check(response: Response) -> ClientResult<Response> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        match response.json::<ErrorBody>().await {
+            Ok(ErrorBody { code, message }) => Err(ClientError::Server {
+                status,
+                code,
+                message,
+            }),
+            Err(_) => Err(ClientError::InvalidResponse(format!(
+                "request failed with {status}"
+            ))),
+        }
+    }
+
+    async fn get(&self, path: &str) -> ClientResult<Response> {
+        let response = self.http.get(self.url.join(path)?).send().await?;
+        Self::check(response).await
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> ClientResult<T> {
+        Ok(self.get(path).await?.json().await?)
+    }
+
+    async fn get_parsed<T: std::str::FromStr>(&self, path: &str) -> ClientResult<T> {
+        let text = self.get(path).await?.text().await?;
+        text.parse()
+            .map_err(|_| ClientError::InvalidResponse(format!("unexpected `{text}`")))
+    }
+
+    /// Signs and sends a request, signing it again with a fresh sequence when the server
+    /// rejects the one it was signed with
+    async fn post<T: Serialize>(&self, path: &str, payload: &T) -> ClientResult<Response> {
+        let endpoint = self.url.join(path)?;
+        let body = serde_json::to_vec(payload).unwrap();
+
+        let mut attempt = 0;
+        loop {
+            let cached = *self.sequence.lock().unwrap();
+            let sequence = match cached {
+                Some(sequence) => sequence,
+                None => self.sequence().await?,
+            };
+
+            let response = self.send_signed(&endpoint, &body, sequence).await?;
+            match Self::check(response).await {
+                Ok(response) => {
+                    *self.sequence.lock().unwrap() = Some(sequence + 1);
+                    return Ok(response);
+                }
+                Err(err) => {
+                    // The sequence may have been consumed before the request failed
+                    *self.sequence.lock().unwrap() = None;
+                    let retry = err.code().is_some_and(|code| RETRY_CODES.contains(&code));
+                    if !retry || attempt >= self.retries {
+                        return Err(err);
+                    }
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    async fn send_signed(
+        &self,
+        endpoint: &Url,
+        body: &[u8],
+        sequence: u64,
+    ) -> ClientResult<Response> {
+        let expires = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+            + SIGNATURE_LIFETIME;
+        let path = match endpoint.query() {
+            Some(query) => format!("{}?{query}", endpoint.path()),
+            None => endpoint.path().to_string(),
+        };
+        let auth = Authentication::new(&Method::POST, &path, body, sequence, expires);
+
+        let mut request = self.http.post(endpoint.clone());
+        if let Some(identity) = &self.identity {
+            request = request.header(IDENTITY_HEADER, identity);
+        }
+        Ok(request
+            .header(CONTENT_TYPE, "application/json")
+            .header(AUTH_HEADER, sign(&self.secret_key, &auth).to_string())
+            .header(EXPIRES_HEADER, expires.to_string())
+            .body(body.to_vec())
+            .send()
+            .await?)
+    }
+
+    /// Whether the server is up
+    pub async fn healthy(&self) -> bool {
+        match self.url.join("/") {
+            Ok(url) => self
+                .http
+                .get(url)
+                .send()
+                .await
+                .is_ok_and(|response| response.status().is_success()),
+            Err(_) => false,
+        }
+    }
+
+    /// The signing identity's current sequence
+    pub async fn sequence(&self) -> ClientResult<u64> {
+        match &self.identity {
+            Some(identity) => {
+                let mut endpoint = self.url.join("sequence")?;
+                endpoint.query_pairs_mut().append_pair("identity", identity);
+                let text = Self::check(self.http.get(endpoint).send().await?)
+                    .await?
+                    .text()
+                    .await?;
+                text.parse()
+                    .map_err(|_| ClientError::InvalidResponse(format!("unexpected `{text}`")))
+            }
+            None => self.get_parsed("sequence").await,
+        }
+    }
+
+    /// The current message, empty when there's none or the pair is paused
+    pub async fn message(&self) -> ClientResult<String> {
+        Ok(self.get("message").await?.text().await?)
+    }
+
+    /// The latest page of messages, newest first
+    pub async fn messages(&self) -> ClientResult<Vec<StoredMessage>> {
+        self.get_json("messages").await
+    }
+
+    pub async fn set_message(&self, message: &Message) -> ClientResult<()> {
+        self.post("message", message).await?;
+        Ok(())
+    }
+
+    /// Whether the pair is active right now
+    pub async fn active(&self) -> ClientResult<bool> {
+        self.get_parsed("active").await
+    }
+
+    pub async fn set_active(&self, active: bool) -> ClientResult<()> {
+        self.post("active", &Active { active }).await?;
+        Ok(())
+    }
+
+    /// The pair's tick types in order, optionally including archived ones
+    pub async fn ticks(&self, archived: bool) -> ClientResult<Vec<TickType>> {
+        match archived {
+            true => self.get_json("ticks?archived=true").await,
+            false => self.get_json("ticks").await,
+        }
+    }
+
+    /// Today's ticks, oldest first
+    pub async fn tick_history(&self) -> ClientResult<Vec<Tick>> {
+        self.get_json("tick_history").await
+    }
+
+    pub async fn tick(&self, ty: u8) -> ClientResult<()> {
+        self.post("tick", &TriggerTick { ty }).await?;
+        Ok(())
+    }
+
+    /// Returns the deleted tick
+    pub async fn delete_tick(&self, delete: &DeleteTick) -> ClientResult<Tick> {
+        Ok(self.post("tick/delete", delete).await?.json().await?)
+    }
+
+    pub async fn add_tick_type(&self, add: &AddTickType) -> ClientResult<TickType> {
+        Ok(self.post("tick_types", add).await?.json().await?)
+    }
+
+    pub async fn update_tick_type(
+        &self,
+        id: u8,
+        update: &UpdateTickType,
+    ) -> ClientResult<TickType> {
+        Ok(self
+            .post(&format!("tick_types/{id}"), update)
+            .await?
+            .json()
+            .await?)
+    }
+
+    pub async fn reorder_tick_types(&self, ids: Vec<u8>) -> ClientResult<()> {
+        self.post("tick_types/order", &ReorderTickTypes { ids })
+            .await?;
+        Ok(())
+    }
+
+    /// The pair's server sent events stream, read it with `Response::chunk`
+    pub async fn events(&self) -> ClientResult<Response> {
+        self.get("events").await
+    }
+}
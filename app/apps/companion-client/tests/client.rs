@@ -0,0 +1,128 @@
+use companion_client::{ClientError, CompanionClient};
+use reqwest::{StatusCode, Url};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use server::{initialize_db, router, AddTickType, Config, DefaultPair, Message, UpdateTickType};
+use std::fs::remove_file;
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+use tokio_rusqlite::Connection;
+
+/// Serves a fresh database on a random port and returns a client for the default pair
+async fn serve(db_path: &PathBuf) -> CompanionClient {
+    let secret_key = SecretKey::from_byte_array(&[1; 32]).unwrap();
+    let default_pair = DefaultPair {
+        pubkey: PublicKey::from_secret_key(&Secp256k1::new(), &secret_key),
+        ticks: vec!["first".to_string(), "second".to_string()],
+    };
+    let conn = Connection::open(db_path).await.unwrap();
+    initialize_db(&conn, &default_pair).await.unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, router(Config::new(conn)))
+            .await
+            .unwrap()
+    });
+
+    let url = Url::parse(&format!("http://{address}/pair/default/")).unwrap();
+    CompanionClient::new(url, secret_key)
+}
+
+#[tokio::test]
+async fn typed_requests() {
+    let db_path = PathBuf::from("./typed_requests_db");
+    let client = serve(&db_path).await;
+
+    assert!(client.healthy().await);
+    assert_eq!(client.sequence().await.unwrap(), 0);
+
+    client
+        .set_message(&Message {
+            message: "hello".to_string(),
+            expires_at: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(client.message().await.unwrap(), "hello");
+    assert_eq!(client.messages().await.unwrap()[0].message, "hello");
+
+    let added = client
+        .add_tick_type(&AddTickType {
+            tick: "third".to_string(),
+            icon: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(added.id, 3);
+    let update = UpdateTickType {
+        archived: Some(true),
+        ..Default::default()
+    };
+    assert!(client.update_tick_type(3, &update).await.unwrap().archived);
+    assert_eq!(client.ticks(false).await.unwrap().len(), 2);
+    assert_eq!(client.ticks(true).await.unwrap().len(), 3);
+
+    client.tick(1).await.unwrap();
+    let history = client.tick_history().await.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].tick, 1);
+
+    // Rejected requests carry the server's error
+    match client.tick(3).await {
+        Err(ClientError::Server { status, code, .. }) => {
+            assert_eq!(status, StatusCode::CONFLICT);
+            assert_eq!(code, "tick_type_archived");
+        }
+        res => panic!("unexpected {res:?}"),
+    }
+    client.set_active(false).await.unwrap();
+    assert!(!client.active().await.unwrap());
+    assert_eq!(
+        client.tick(1).await.unwrap_err().code(),
+        Some("pair_inactive")
+    );
+    client.set_active(true).await.unwrap();
+    client.tick(2).await.unwrap();
+
+    // A failed request may have consumed its sequence, the next one still goes through
+    assert_eq!(client.sequence().await.unwrap(), 9);
+
+    remove_file(db_path).unwrap();
+}
+
+#[tokio::test]
+async fn sequence_refresh() {
+    let db_path = PathBuf::from("./sequence_refresh_db");
+    let client = serve(&db_path).await;
+    let other = CompanionClient::new(
+        client.url().clone(),
+        SecretKey::from_byte_array(&[1; 32]).unwrap(),
+    )
+    .with_retries(0);
+
+    // Both clients know the sequence, then one of them uses it
+    client.set_active(true).await.unwrap();
+    other.set_active(true).await.unwrap();
+    other.set_active(true).await.unwrap();
+
+    // The stale sequence is refreshed and the request signed again
+    client.set_active(true).await.unwrap();
+    assert_eq!(client.sequence().await.unwrap(), 4);
+
+    // Without retries the rejection is returned, and the next request refreshes the sequence
+    assert_eq!(
+        other.set_active(true).await.unwrap_err().code(),
+        Some("unauthorized")
+    );
+    other.set_active(true).await.unwrap();
+
+    // Requests signed with an unknown identity aren't retried
+    let stranger = other.with_identity("stranger".to_string());
+    assert_eq!(
+        stranger.set_active(true).await.unwrap_err().code(),
+        Some("identity_not_found")
+    );
+
+    remove_file(db_path).unwrap();
+}
@@ -0,0 +1,34 @@
+use core::fmt::{Display, Formatter};
+
+/// Why a payload couldn't be decoded
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// The payload was encoded with another `FORMAT_VERSION`
+    UnsupportedVersion(u8),
+    /// The payload ended before everything it announced
+    Truncated,
+    /// A name isn't valid UTF-8
+    InvalidName,
+    /// The hour or minute is out of range
+    InvalidTime { hour: u8, minute: u8 },
+    /// The payload announces more entries than there's room for
+    TooMany { count: usize, max: usize },
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {version}")
+            }
+            DecodeError::Truncated => write!(f, "payload is truncated"),
+            DecodeError::InvalidName => write!(f, "name is not valid UTF-8"),
+            DecodeError::InvalidTime { hour, minute } => {
+                write!(f, "invalid time {hour:02}:{minute:02}")
+            }
+            DecodeError::TooMany { count, max } => {
+                write!(f, "{count} entries don't fit in {max}")
+            }
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::reader::{check_count, Reader};
+use crate::DecodeError;
+use heapless::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Time {
+    pub hour: u8,
+    pub minute: u8,
+}
+
+impl Time {
+    pub fn new(hour: u8, minute: u8) -> Result<Self, DecodeError> {
+        if hour > 23 || minute > 59 {
+            return Err(DecodeError::InvalidTime { hour, minute });
+        }
+        Ok(Self { hour, minute })
+    }
+}
+
+/// A tick of today's history, its time is in the viewer's timezone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryTick {
+    pub type_id: u8,
+    pub time: Time,
+}
+
+/// Whether the pair is active and today's ticks, fails before reading any tick when there are
+/// more than `N`
+pub fn decode_tick_history<const N: usize>(
+    bytes: &[u8],
+) -> Result<(bool, Vec<HistoryTick, N>), DecodeError> {
+    let mut reader = Reader::new(bytes)?;
+    let active = reader.bool()?;
+    let count = reader.u16()? as usize;
+    check_count(count, N)?;
+
+    let mut ticks = Vec::new();
+    for _ in 0..count {
+        let type_id = reader.byte()?;
+        let time = Time::new(reader.byte()?, reader.byte()?)?;
+        ticks
+            .push(HistoryTick { type_id, time })
+            .map_err(|_| DecodeError::TooMany { count, max: N })?;
+    }
+    Ok((active, ticks))
+}
+
+/// WARNING: the tick count is a big endian `u16` and each tick is its type, hour and minute, a
+/// byte each
+#[cfg(feature = "alloc")]
+pub fn encode_tick_history(active: bool, ticks: &[HistoryTick]) -> alloc::vec::Vec<u8> {
+    let ticks = &ticks[..ticks.len().min(u16::MAX as usize)];
+    let mut res = alloc::vec::Vec::with_capacity(4 + ticks.len() * 3);
+    res.push(crate::FORMAT_VERSION);
+    res.push(active as u8);
+    res.extend_from_slice(&(ticks.len() as u16).to_be_bytes());
+    for tick in ticks {
+        res.extend_from_slice(&[tick.type_id, tick.time.hour, tick.time.minute]);
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FORMAT_VERSION;
+    use proptest::prelude::*;
+
+    const SIZE: usize = 64;
+
+    fn tick(type_id: u8, hour: u8, minute: u8) -> HistoryTick {
+        HistoryTick {
+            type_id,
+            time: Time { hour, minute },
+        }
+    }
+
+    fn valid_tick() -> impl Strategy<Value = HistoryTick> {
+        (any::<u8>(), 0..24u8, 0..60u8)
+            .prop_map(|(type_id, hour, minute)| tick(type_id, hour, minute))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<(bool, Vec<HistoryTick, SIZE>), DecodeError> {
+        decode_tick_history(bytes)
+    }
+
+    #[test]
+    fn tick_history() {
+        let ticks = [tick(2, 19, 0), tick(1, 23, 59)];
+        let mut bytes = encode_tick_history(false, &ticks);
+        assert_eq!(bytes, [FORMAT_VERSION, 0, 0, 2, 2, 19, 0, 1, 23, 59]);
+        // Responses are padded with zeros up to the device's buffer
+        bytes.extend_from_slice(&[0; 8]);
+        assert_eq!(
+            decode(&bytes).unwrap(),
+            (false, ticks.as_slice().try_into().unwrap())
+        );
+
+        assert!(decode(&encode_tick_history(true, &[]))
+            .unwrap()
+            .1
+            .is_empty());
+        assert_eq!(decode(&[]).unwrap_err(), DecodeError::Truncated);
+        assert_eq!(
+            decode(&[FORMAT_VERSION + 1, 1, 0, 0]).unwrap_err(),
+            DecodeError::UnsupportedVersion(FORMAT_VERSION + 1)
+        );
+        assert_eq!(
+            decode(&encode_tick_history(true, &[tick(1, 24, 0)])).unwrap_err(),
+            DecodeError::InvalidTime {
+                hour: 24,
+                minute: 0
+            }
+        );
+        assert_eq!(
+            decode(&encode_tick_history(true, &[tick(1, 12, 60)])).unwrap_err(),
+            DecodeError::InvalidTime {
+                hour: 12,
+                minute: 60
+            }
+        );
+        // The count alone is enough to refuse a history that doesn't fit
+        assert_eq!(
+            decode(&[FORMAT_VERSION, 1, 0xff, 0xff]).unwrap_err(),
+            DecodeError::TooMany {
+                count: u16::MAX as usize,
+                max: SIZE
+            }
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(active: bool, ticks in prop::collection::vec(valid_tick(), 0..=SIZE)) {
+            let (decoded_active, decoded) = decode(&encode_tick_history(active, &ticks)).unwrap();
+            prop_assert_eq!(decoded_active, active);
+            prop_assert_eq!(decoded.as_slice(), ticks.as_slice());
+        }
+
+        #[test]
+        fn truncated(
+            ticks in prop::collection::vec(valid_tick(), 1..SIZE),
+            cut in any::<prop::sample::Index>(),
+        ) {
+            let bytes = encode_tick_history(true, &ticks);
+            let end = cut.index(bytes.len());
+            prop_assert_eq!(decode(&bytes[..end]).unwrap_err(), DecodeError::Truncated);
+        }
+
+        #[test]
+        fn oversized(extra in 1..64usize) {
+            let ticks = std::vec![tick(1, 0, 0); SIZE + extra];
+            prop_assert_eq!(
+                decode(&encode_tick_history(true, &ticks)).unwrap_err(),
+                DecodeError::TooMany { count: ticks.len(), max: SIZE }
+            );
+        }
+
+        #[test]
+        fn arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..512)) {
+            // Anything is either decoded or refused, it never panics
+            if let Some(&version) = bytes.first().filter(|version| **version != FORMAT_VERSION) {
+                prop_assert_eq!(decode(&bytes).unwrap_err(), DecodeError::UnsupportedVersion(version));
+            }
+            let bytes = [&[FORMAT_VERSION], bytes.as_slice()].concat();
+            if let Ok((_, ticks)) = decode(&bytes) {
+                prop_assert!(ticks.iter().all(|tick| tick.time.hour < 24 && tick.time.minute < 60));
+            }
+        }
+    }
+}
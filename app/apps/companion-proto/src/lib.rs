@@ -0,0 +1,24 @@
+//! Wire format of the compact payloads the server sends to the device.
+//!
+//! Every payload starts with `FORMAT_VERSION`, the tick history and stats follow it with a byte
+//! that is `1` when the pair is active. Decoding never panics, payloads that can't be read are a
+//! `DecodeError`.
+
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+mod error;
+mod history;
+mod reader;
+mod stats;
+mod tick_types;
+
+pub use error::DecodeError;
+pub use history::*;
+pub use stats::*;
+pub use tick_types::*;
+
+/// Bumped whenever a payload's layout changes
+pub const FORMAT_VERSION: u8 = 1;
@@ -0,0 +1,58 @@
+use crate::{DecodeError, FORMAT_VERSION};
+
+/// Reads a payload front to back, every read fails instead of going past its end
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Checks the payload's version before anything else is read
+    pub fn new(bytes: &'a [u8]) -> Result<Self, DecodeError> {
+        let mut reader = Self { bytes };
+        match reader.byte()? {
+            FORMAT_VERSION => Ok(reader),
+            version => Err(DecodeError::UnsupportedVersion(version)),
+        }
+    }
+
+    pub fn byte(&mut self) -> Result<u8, DecodeError> {
+        let (byte, rest) = self.bytes.split_first().ok_or(DecodeError::Truncated)?;
+        self.bytes = rest;
+        Ok(*byte)
+    }
+
+    pub fn bool(&mut self) -> Result<bool, DecodeError> {
+        Ok(self.byte()? != 0)
+    }
+
+    /// A big endian `u16`
+    pub fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_be_bytes([self.byte()?, self.byte()?]))
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < len {
+            return Err(DecodeError::Truncated);
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+}
+
+/// Fails before reading anything when `count` entries can't fit in `max`
+pub fn check_count(count: usize, max: usize) -> Result<(), DecodeError> {
+    match count > max {
+        true => Err(DecodeError::TooMany { count, max }),
+        false => Ok(()),
+    }
+}
+
+/// The longest start of `text` that fits in `max` bytes without splitting a character
+pub fn truncate(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
@@ -0,0 +1,143 @@
+use crate::reader::{check_count, Reader};
+use crate::DecodeError;
+use heapless::Vec;
+
+/// Tick types the device has room for, the server leaves the others out of the device's stats
+pub const DEVICE_TICK_TYPES: usize = 10;
+
+/// Size of the stats of `types` tick types over `buckets` buckets, see `encode_tick_stats`
+pub const fn tick_stats_size(types: usize, buckets: usize) -> usize {
+    4 + types + buckets * types * 2
+}
+
+/// Tick count of every tick type in a bucket, in the same order as the stats' tick types
+pub type StatsBucket<const T: usize> = Vec<u16, T>;
+
+/// Tick counts of up to `T` tick types over up to `B` buckets
+#[derive(Debug, Default, PartialEq)]
+pub struct TickStats<const T: usize, const B: usize> {
+    pub type_ids: Vec<u8, T>,
+    pub buckets: Vec<StatsBucket<T>, B>,
+}
+
+/// Whether the pair is active and its tick stats, tick types past the first `T` are skipped like
+/// in `decode_tick_types`. Fails before reading any count when there are more than `B` buckets
+pub fn decode_tick_stats<const T: usize, const B: usize>(
+    bytes: &[u8],
+) -> Result<(bool, TickStats<T, B>), DecodeError> {
+    let mut reader = Reader::new(bytes)?;
+    let active = reader.bool()?;
+    let bucket_count = reader.byte()? as usize;
+    let type_count = reader.byte()? as usize;
+    check_count(bucket_count, B)?;
+
+    // Skipped types are still read past, the bucket count was checked so every bucket fits
+    let mut stats = TickStats::default();
+    for _ in 0..type_count {
+        stats.type_ids.push(reader.byte()?).ok();
+    }
+    for _ in 0..bucket_count {
+        let mut bucket = StatsBucket::new();
+        for _ in 0..type_count {
+            bucket.push(reader.u16()?).ok();
+        }
+        stats.buckets.push(bucket).ok();
+    }
+    Ok((active, stats))
+}
+
+/// WARNING: the stats are a byte with the bucket count, a byte with the tick type count, a byte
+/// for each tick type id and then every bucket's counts as big endian `u16`s in the tick types
+/// order. There are never more than 255 buckets or tick types.
+#[cfg(feature = "alloc")]
+pub fn encode_tick_stats(
+    active: bool,
+    type_ids: &[u8],
+    buckets: &[alloc::vec::Vec<u16>],
+) -> alloc::vec::Vec<u8> {
+    let type_ids = &type_ids[..type_ids.len().min(u8::MAX as usize)];
+    let buckets = &buckets[..buckets.len().min(u8::MAX as usize)];
+    let mut res = alloc::vec::Vec::with_capacity(tick_stats_size(type_ids.len(), buckets.len()));
+    res.push(crate::FORMAT_VERSION);
+    res.push(active as u8);
+    res.push(buckets.len() as u8);
+    res.push(type_ids.len() as u8);
+    res.extend_from_slice(type_ids);
+    for bucket in buckets {
+        for index in 0..type_ids.len() {
+            let count = bucket.get(index).copied().unwrap_or_default();
+            res.extend_from_slice(&count.to_be_bytes());
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FORMAT_VERSION;
+    use proptest::prelude::*;
+
+    type Stats = TickStats<10, 7>;
+
+    fn decode(bytes: &[u8]) -> Result<(bool, Stats), DecodeError> {
+        decode_tick_stats(bytes)
+    }
+
+    #[test]
+    fn tick_stats() {
+        let bytes = encode_tick_stats(true, &[1, 3], &[vec![4, 0], vec![256, 1]]);
+        assert_eq!(bytes.len(), tick_stats_size(2, 2));
+        assert_eq!(
+            bytes,
+            [FORMAT_VERSION, 1, 2, 2, 1, 3, 0, 4, 0, 0, 1, 0, 0, 1]
+        );
+        let (active, stats) = decode(&bytes).unwrap();
+        assert!(active);
+        assert_eq!(stats.type_ids, [1, 3]);
+        assert_eq!(stats.buckets, [[4, 0], [256, 1]]);
+
+        for end in 0..bytes.len() {
+            assert_eq!(decode(&bytes[..end]).unwrap_err(), DecodeError::Truncated);
+        }
+        assert_eq!(
+            decode(&[FORMAT_VERSION, 1, 8, 0]).unwrap_err(),
+            DecodeError::TooMany { count: 8, max: 7 }
+        );
+
+        // Types that don't fit are skipped without shifting the next bucket's counts
+        let type_ids: std::vec::Vec<u8> = (0..11).collect();
+        let buckets = [(0..11).collect(), (100..111).collect()];
+        let (_, stats) = decode(&encode_tick_stats(true, &type_ids, &buckets)).unwrap();
+        assert_eq!(stats.type_ids, type_ids[..10]);
+        assert_eq!(stats.buckets, [&buckets[0][..10], &buckets[1][..10]]);
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(
+            active: bool,
+            (type_ids, buckets) in (0..=20usize, 0..=7usize).prop_flat_map(|(types, buckets)| (
+                prop::collection::vec(any::<u8>(), types),
+                prop::collection::vec(prop::collection::vec(any::<u16>(), types), buckets),
+            )),
+        ) {
+            let (decoded_active, stats) = decode(&encode_tick_stats(active, &type_ids, &buckets)).unwrap();
+            prop_assert_eq!(decoded_active, active);
+            let types = type_ids.len().min(10);
+            prop_assert_eq!(stats.type_ids.as_slice(), &type_ids[..types]);
+            for (decoded, bucket) in stats.buckets.iter().zip(&buckets) {
+                prop_assert_eq!(decoded.as_slice(), &bucket[..types]);
+            }
+            prop_assert_eq!(stats.buckets.len(), buckets.len());
+        }
+
+        #[test]
+        fn arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+            let bytes = [&[FORMAT_VERSION], bytes.as_slice()].concat();
+            if let Ok((_, stats)) = decode(&bytes) {
+                prop_assert!(stats.buckets.iter().all(|bucket| bucket.len() == stats.type_ids.len()));
+            }
+        }
+    }
+}
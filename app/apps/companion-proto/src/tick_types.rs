@@ -0,0 +1,137 @@
+use crate::reader::{truncate, Reader};
+use crate::DecodeError;
+use heapless::{String, Vec};
+
+/// A tick type's id and its name, cut to `S` bytes
+#[derive(Debug, Default, PartialEq)]
+pub struct TickType<const S: usize> {
+    pub id: u8,
+    pub name: String<S>,
+}
+
+/// The pair's tick types, types past the first `N` are skipped and names longer than `S` bytes
+/// are cut short without splitting a character
+pub fn decode_tick_types<const N: usize, const S: usize>(
+    bytes: &[u8],
+) -> Result<Vec<TickType<S>, N>, DecodeError> {
+    let mut reader = Reader::new(bytes)?;
+    let count = reader.byte()?;
+
+    let mut ticks = Vec::new();
+    for _ in 0..count {
+        let id = reader.byte()?;
+        let len = reader.byte()? as usize;
+        let name =
+            core::str::from_utf8(reader.bytes(len)?).map_err(|_| DecodeError::InvalidName)?;
+
+        let mut tick = TickType {
+            id,
+            ..Default::default()
+        };
+        tick.name.push_str(truncate(name, S)).ok();
+        // Skipped types are still read past
+        ticks.push(tick).ok();
+    }
+    Ok(ticks)
+}
+
+/// WARNING: each type is its id, its name's length and the name, all behind a byte with the
+/// count. There are never more than 255 types and names are cut to 255 bytes.
+#[cfg(feature = "alloc")]
+pub fn encode_tick_types(ticks: &[(u8, &str)]) -> alloc::vec::Vec<u8> {
+    let ticks = &ticks[..ticks.len().min(u8::MAX as usize)];
+    let mut res = alloc::vec::Vec::with_capacity(2 + ticks.len() * 2);
+    res.push(crate::FORMAT_VERSION);
+    res.push(ticks.len() as u8);
+    for (id, name) in ticks {
+        let name = truncate(name, u8::MAX as usize);
+        res.push(*id);
+        res.push(name.len() as u8);
+        res.extend_from_slice(name.as_bytes());
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FORMAT_VERSION;
+    use proptest::prelude::*;
+
+    const SIZE: usize = 25;
+    const ALLOC: usize = 10;
+
+    fn decode(bytes: &[u8]) -> Result<std::vec::Vec<(u8, std::string::String)>, DecodeError> {
+        let ticks = decode_tick_types::<ALLOC, SIZE>(bytes)?;
+        Ok(ticks
+            .iter()
+            .map(|tick| (tick.id, tick.name.to_string()))
+            .collect())
+    }
+
+    #[test]
+    fn tick_types() {
+        let mut bytes = encode_tick_types(&[(3, "water"), (1, "walk"), (7, "")]);
+        bytes.extend_from_slice(&[0; 16]);
+        assert_eq!(
+            decode(&bytes).unwrap(),
+            [(3, "water".into()), (1, "walk".into()), (7, "".into())]
+        );
+        assert!(decode(&[FORMAT_VERSION, 0]).unwrap().is_empty());
+
+        let bytes = encode_tick_types(&[(1, "first"), (2, "second")]);
+        for end in 0..bytes.len() {
+            assert_eq!(decode(&bytes[..end]).unwrap_err(), DecodeError::Truncated);
+        }
+        assert_eq!(
+            decode(&[FORMAT_VERSION, 1, 1, 1, 0xff]).unwrap_err(),
+            DecodeError::InvalidName
+        );
+    }
+
+    #[test]
+    fn long_names() {
+        let long = "a".repeat(SIZE + 10);
+        let decoded = decode(&encode_tick_types(&[(1, &long), (2, "next")])).unwrap();
+        assert_eq!(decoded[0].1, long[..SIZE]);
+        // The rest of the name is skipped, not read as the next type
+        assert_eq!(decoded[1], (2, "next".into()));
+
+        // Names are never cut in the middle of a character
+        let accents = "é".repeat(SIZE);
+        let decoded = decode(&encode_tick_types(&[(1, &accents)])).unwrap();
+        assert_eq!(decoded[0].1, accents[..SIZE - 1]);
+        let encoded = encode_tick_types(&[(1, &"é".repeat(200))]);
+        assert_eq!(encoded[3], 254);
+    }
+
+    #[test]
+    fn overflow() {
+        let ticks: std::vec::Vec<_> = (0..ALLOC as u8 + 5).map(|id| (id, "tick")).collect();
+        let decoded = decode(&encode_tick_types(&ticks)).unwrap();
+        assert_eq!(decoded.len(), ALLOC);
+        assert_eq!(decoded.last().unwrap().0, ALLOC as u8 - 1);
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(ticks in prop::collection::vec((any::<u8>(), "\\PC{0,24}"), 0..=ALLOC)) {
+            let names: std::vec::Vec<_> = ticks.iter().map(|(id, name)| (*id, name.as_str())).collect();
+            let decoded = decode(&encode_tick_types(&names)).unwrap();
+            prop_assert_eq!(decoded.len(), ticks.len());
+            for ((id, name), (decoded_id, decoded_name)) in ticks.iter().zip(decoded) {
+                prop_assert_eq!(*id, decoded_id);
+                prop_assert!(name.starts_with(&decoded_name));
+                prop_assert!(decoded_name.len() <= SIZE);
+            }
+        }
+
+        #[test]
+        fn arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..512)) {
+            let bytes = [&[FORMAT_VERSION], bytes.as_slice()].concat();
+            if let Ok(ticks) = decode(&bytes) {
+                prop_assert!(ticks.len() <= ALLOC);
+            }
+        }
+    }
+}
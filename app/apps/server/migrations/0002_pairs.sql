@@ -0,0 +1,43 @@
+-- Scope everything to a pair, existing data is moved to the default pair whose key is set on startup
+CREATE TABLE pairs (
+    id TEXT PRIMARY KEY,
+    pubkey TEXT NOT NULL
+);
+INSERT INTO pairs (id, pubkey) SELECT 'default', '' WHERE EXISTS (SELECT 1 FROM settings);
+
+ALTER TABLE ticks RENAME TO ticks_legacy;
+ALTER TABLE tick_types RENAME TO tick_types_legacy;
+ALTER TABLE settings RENAME TO settings_legacy;
+
+CREATE TABLE settings (
+    pair TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY(pair, key),
+    FOREIGN KEY(pair) REFERENCES pairs(id)
+);
+INSERT INTO settings (pair, key, value) SELECT 'default', key, value FROM settings_legacy;
+
+-- Tick type ids are only unique within a pair
+CREATE TABLE tick_types (
+    pair TEXT NOT NULL,
+    id INTEGER NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY(pair, id),
+    FOREIGN KEY(pair) REFERENCES pairs(id)
+);
+INSERT INTO tick_types (pair, id, value) SELECT 'default', id, value FROM tick_types_legacy;
+
+CREATE TABLE ticks (
+    id INTEGER PRIMARY KEY,
+    pair TEXT NOT NULL,
+    tick_type INTEGER NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(pair, tick_type) REFERENCES tick_types(pair, id)
+);
+INSERT INTO ticks (id, pair, tick_type, created_at)
+    SELECT id, 'default', tick_type, created_at FROM ticks_legacy;
+
+DROP TABLE ticks_legacy;
+DROP TABLE tick_types_legacy;
+DROP TABLE settings_legacy;
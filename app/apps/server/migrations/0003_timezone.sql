@@ -0,0 +1,3 @@
+-- Every pair gets its own timezone and hour at which its day rolls over, defaulting to the old hardcoded values
+INSERT OR IGNORE INTO settings (pair, key, value) SELECT id, 'timezone', 'America/Puerto_Rico' FROM pairs;
+INSERT OR IGNORE INTO settings (pair, key, value) SELECT id, 'day_start', '6' FROM pairs;
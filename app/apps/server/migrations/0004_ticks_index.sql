@@ -0,0 +1,2 @@
+-- History is always read per pair and by time range
+CREATE INDEX ticks_pair_created_at ON ticks (pair, created_at);
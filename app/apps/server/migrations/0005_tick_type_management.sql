@@ -0,0 +1,5 @@
+-- Tick types can be reordered, archived and given an icon, archived types keep their ticks
+ALTER TABLE tick_types ADD COLUMN position INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE tick_types ADD COLUMN archived BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE tick_types ADD COLUMN icon TEXT;
+UPDATE tick_types SET position = id;
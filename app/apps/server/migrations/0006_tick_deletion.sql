@@ -0,0 +1,2 @@
+-- Ticks are soft deleted so undoing a tick never loses data
+ALTER TABLE ticks ADD COLUMN deleted_at DATETIME;
@@ -0,0 +1,20 @@
+-- Every change to a pair's data bumps its revision so clients can tell when to refresh
+ALTER TABLE pairs ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;
+
+CREATE TRIGGER ticks_insert_revision AFTER INSERT ON ticks BEGIN
+    UPDATE pairs SET revision = revision + 1 WHERE id = NEW.pair;
+END;
+CREATE TRIGGER ticks_update_revision AFTER UPDATE ON ticks BEGIN
+    UPDATE pairs SET revision = revision + 1 WHERE id = NEW.pair;
+END;
+CREATE TRIGGER tick_types_insert_revision AFTER INSERT ON tick_types BEGIN
+    UPDATE pairs SET revision = revision + 1 WHERE id = NEW.pair;
+END;
+CREATE TRIGGER tick_types_update_revision AFTER UPDATE ON tick_types BEGIN
+    UPDATE pairs SET revision = revision + 1 WHERE id = NEW.pair;
+END;
+-- The sequence changes with every signed request without changing anything that's shown
+CREATE TRIGGER settings_update_revision AFTER UPDATE ON settings
+WHEN NEW.key <> 'sequence' AND OLD.value IS NOT NEW.value BEGIN
+    UPDATE pairs SET revision = revision + 1 WHERE id = NEW.pair;
+END;
@@ -0,0 +1,18 @@
+-- Messages are kept instead of overwritten, the current one is the latest that hasn't expired
+CREATE TABLE messages (
+    id INTEGER PRIMARY KEY,
+    pair TEXT NOT NULL,
+    author TEXT NOT NULL,
+    message TEXT NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME,
+    FOREIGN KEY(pair) REFERENCES pairs(id)
+);
+CREATE INDEX messages_pair ON messages (pair, id);
+
+INSERT INTO messages (pair, author, message) SELECT pair, pair, value FROM settings WHERE key = 'message';
+DELETE FROM settings WHERE key = 'message';
+
+CREATE TRIGGER messages_insert_revision AFTER INSERT ON messages BEGIN
+    UPDATE pairs SET revision = revision + 1 WHERE id = NEW.pair;
+END;
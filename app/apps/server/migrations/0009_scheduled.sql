@@ -0,0 +1,13 @@
+-- Signed actions waiting to be applied at a later time, applied ones are kept with their outcome
+CREATE TABLE scheduled (
+    id INTEGER PRIMARY KEY,
+    pair TEXT NOT NULL,
+    author TEXT NOT NULL,
+    action TEXT NOT NULL,
+    due_at DATETIME NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    applied_at DATETIME,
+    error TEXT,
+    FOREIGN KEY(pair) REFERENCES pairs(id)
+);
+CREATE INDEX scheduled_pending ON scheduled (due_at) WHERE applied_at IS NULL;
@@ -0,0 +1,3 @@
+-- Daily hours during which a pair is paused regardless of its active flag, empty when it has none
+INSERT OR IGNORE INTO settings (pair, key, value) SELECT id, 'quiet_start', '' FROM pairs;
+INSERT OR IGNORE INTO settings (pair, key, value) SELECT id, 'quiet_end', '' FROM pairs;
@@ -0,0 +1,20 @@
+-- Pairs can have several identities, each with its own key and sequence. A pair's existing key and
+-- sequence become its identity named after the pair
+CREATE TABLE identities (
+    pair TEXT NOT NULL,
+    name TEXT NOT NULL,
+    pubkey TEXT NOT NULL,
+    sequence INTEGER NOT NULL DEFAULT 0,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY(pair, name),
+    FOREIGN KEY(pair) REFERENCES pairs(id)
+);
+INSERT INTO identities (pair, name, pubkey, sequence)
+    SELECT pairs.id, pairs.id, pairs.pubkey, CAST(settings.value AS INTEGER)
+    FROM pairs JOIN settings ON settings.pair = pairs.id AND settings.key = 'sequence';
+DELETE FROM settings WHERE key = 'sequence';
+ALTER TABLE pairs DROP COLUMN pubkey;
+
+-- Ticks remember who ticked them, older ones could only have been made with the pair's own key
+ALTER TABLE ticks ADD COLUMN author TEXT NOT NULL DEFAULT '';
+UPDATE ticks SET author = pair;
@@ -0,0 +1,7 @@
+-- Keys that were rotated out or revoked, they can never sign again for any pair
+CREATE TABLE revoked_keys (
+    pubkey TEXT PRIMARY KEY,
+    pair TEXT NOT NULL,
+    identity TEXT NOT NULL,
+    revoked_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
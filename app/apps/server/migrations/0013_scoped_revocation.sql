@@ -0,0 +1,13 @@
+-- A revoked key is only locked out of the pair it was revoked in, anyone can name any public key so
+-- revoking one must not lock it out of other pairs. Each identity that held the key keeps its row
+CREATE TABLE identity_revoked_keys (
+    pubkey TEXT NOT NULL,
+    pair TEXT NOT NULL,
+    identity TEXT NOT NULL,
+    revoked_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY(pair, identity, pubkey)
+);
+INSERT INTO identity_revoked_keys (pubkey, pair, identity, revoked_at)
+    SELECT pubkey, pair, identity, revoked_at FROM revoked_keys ORDER BY rowid;
+DROP TABLE revoked_keys;
+ALTER TABLE identity_revoked_keys RENAME TO revoked_keys;
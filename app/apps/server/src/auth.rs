@@ -1,54 +1,272 @@
 use crate::config::Config;
-use crate::settings::{save_sequence, sequence};
-use axum::http::{HeaderValue, StatusCode};
+use crate::error::{AppError, AppResult};
+use crate::identities::identity_key;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, OriginalUri, Request};
+use axum::http::{HeaderMap, Method};
+use chrono::Utc;
 use secp256k1::ecdsa::Signature;
 use secp256k1::hashes::{sha256, Hash};
-use secp256k1::{Message, Secp256k1, SecretKey};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
+use tokio_rusqlite::{params, TransactionBehavior};
 
+/// Header holding the request's signature
+pub const AUTH_HEADER: &str = "auth";
+/// Header holding the unix timestamp after which the signature is no longer valid
+pub const EXPIRES_HEADER: &str = "expires";
+/// Header naming the identity that signed the request, the pair's own identity when missing
+pub const IDENTITY_HEADER: &str = "identity";
+/// Signatures valid for longer than this are rejected so captured requests can't be held onto
+pub const MAX_EXPIRY_SECONDS: i64 = 5 * 60;
+
+/// The canonical envelope that gets signed, it binds the signature to a single request
 #[derive(Serialize, Deserialize)]
-pub struct Authentication<T> {
+pub struct Authentication {
+    pub method: String,
+    /// Request path including the query
+    pub path: String,
+    /// Hex encoded sha256 of the raw request body
+    pub body: String,
     pub sequence: u64,
-    pub message: T,
+    pub expires: i64,
 }
 
-pub fn hash<T: Serialize>(msg: Authentication<T>) -> Message {
-    let digest = sha256::Hash::hash(serde_json::to_vec(&msg).unwrap().as_slice());
+impl Authentication {
+    pub fn new(method: &Method, path: &str, body: &[u8], sequence: u64, expires: i64) -> Self {
+        Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: sha256::Hash::hash(body).to_string(),
+            sequence,
+            expires,
+        }
+    }
+}
+
+pub fn hash(msg: &Authentication) -> Message {
+    let digest = sha256::Hash::hash(serde_json::to_vec(msg).unwrap().as_slice());
     Message::from_digest(digest.to_byte_array())
 }
 
-pub fn sign<T: Serialize>(secret_key: &SecretKey, msg: T, sequence: u64) -> Signature {
+pub fn sign(secret_key: &SecretKey, msg: &Authentication) -> Signature {
     let secp = Secp256k1::signing_only();
-    let msg = hash(Authentication {
-        sequence,
-        message: msg,
-    });
-    secp.sign_ecdsa(&msg, secret_key)
-}
-
-pub async fn evaulate<T: Serialize>(
-    config: &Config,
-    cert: &HeaderValue,
-    expected: T,
-) -> Option<StatusCode> {
-    let sequence = sequence(&config.db).await;
-    let expected_message = hash(Authentication {
-        sequence,
-        message: expected,
-    });
-
-    let signature = Signature::from_str(cert.to_str().unwrap()).unwrap();
+    secp.sign_ecdsa(&hash(msg), secret_key)
+}
 
-    let secp = Secp256k1::verification_only();
+/// A request carrying a JSON payload that must be evaluated before being trusted
+pub struct Signed<T> {
+    method: Method,
+    path: String,
+    headers: HeaderMap,
+    body: Bytes,
+    pub payload: T,
+}
+
+impl<S: Send + Sync, T: DeserializeOwned> FromRequest<S> for Signed<T> {
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let method = req.method().clone();
+        // Nested routers strip their prefix so the original uri is the one the client signed
+        let path = req
+            .extensions()
+            .get::<OriginalUri>()
+            .map(|uri| uri.0.clone())
+            .unwrap_or_else(|| req.uri().clone())
+            .path_and_query()
+            .map(|path| path.to_string())
+            .unwrap_or_default();
+        let headers = req.headers().clone();
+
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(|e| AppError::InvalidBody(e.to_string()))?;
+        let payload =
+            serde_json::from_slice(&body).map_err(|e| AppError::InvalidBody(e.to_string()))?;
+
+        Ok(Self {
+            method,
+            path,
+            headers,
+            body,
+            payload,
+        })
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &'static str) -> AppResult<&'a str> {
+    headers
+        .get(name)
+        .ok_or(AppError::MissingHeader(name))?
+        .to_str()
+        .map_err(|_| AppError::InvalidHeader(name))
+}
+
+/// Checks the request's signature and consumes its sequence, returns the identity that signed it
+pub async fn evaulate<T>(config: &Config, pair: &str, request: &Signed<T>) -> AppResult<String> {
+    let identity = match request.headers.contains_key(IDENTITY_HEADER) {
+        true => header(&request.headers, IDENTITY_HEADER)?.to_string(),
+        false => pair.to_string(),
+    };
+    // Fails early when the identity or pair don't exist
+    identity_key(&config.db, pair, &identity).await?;
+
+    let signature = Signature::from_str(header(&request.headers, AUTH_HEADER)?)
+        .map_err(|_| AppError::InvalidHeader(AUTH_HEADER))?;
+    let expires: i64 = header(&request.headers, EXPIRES_HEADER)?
+        .parse()
+        .map_err(|_| AppError::InvalidHeader(EXPIRES_HEADER))?;
+
+    let now = Utc::now().timestamp();
+    if expires < now || expires > now + MAX_EXPIRY_SECONDS {
+        return Err(AppError::Expired);
+    }
+
+    let pair = pair.to_string();
+    let name = identity.clone();
+    let method = request.method.clone();
+    let path = request.path.clone();
+    let body = request.body.clone();
 
-    if secp
-        .verify_ecdsa(&expected_message, &signature, &config.pubkey)
+    config
+        .db
+        .call(move |conn| {
+            // The sequence is checked and consumed in a single write transaction so two requests
+            // can never be accepted with the same sequence
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let (sequence, pubkey, revoked): (u64, String, bool) = tx.query_row(
+                "SELECT sequence, pubkey, pubkey IN ( \
+                    SELECT pubkey FROM revoked_keys WHERE pair = ?1 \
+                ) \
+                FROM identities WHERE pair = ?1 AND name = ?2",
+                params![pair, name],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )?;
+            if revoked {
+                return Ok(Err(AppError::KeyRevoked));
+            }
+            let Ok(pubkey) = PublicKey::from_str(&pubkey) else {
+                return Ok(Err(AppError::Internal(format!(
+                    "stored key `{pubkey}` is not a public key"
+                ))));
+            };
+
+            let expected = Authentication::new(&method, &path, &body, sequence, expires);
+            if !verify(&pubkey, &expected, &signature) {
+                return Ok(Err(AppError::Unauthorized));
+            }
+
+            tx.execute(
+                "UPDATE identities SET sequence = ?1 WHERE pair = ?2 AND name = ?3",
+                params![sequence + 1, pair, name],
+            )?;
+            tx.commit()?;
+            Ok(Ok(()))
+        })
+        .await??;
+
+    Ok(identity)
+}
+
+pub fn verify(pubkey: &PublicKey, expected: &Authentication, signature: &Signature) -> bool {
+    let secp = Secp256k1::verification_only();
+    secp.verify_ecdsa(&hash(expected), signature, pubkey)
         .is_ok()
-    {
-        save_sequence(&config.db, sequence + 1).await;
-        None
-    } else {
-        Some(StatusCode::UNAUTHORIZED)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{initialize_db, DefaultPair};
+    use crate::identities::identity_sequence;
+    use crate::pair::DEFAULT_PAIR;
+    use std::fs::remove_file;
+    use std::path::PathBuf;
+    use tokio_rusqlite::Connection;
+
+    fn signed(method: Method, path: &str, body: &str, sequence: u64, expires: i64) -> Signed<()> {
+        let secret_key = SecretKey::from_byte_array(&[1; 32]).unwrap();
+        let auth = Authentication::new(&method, path, body.as_bytes(), sequence, expires);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTH_HEADER,
+            sign(&secret_key, &auth).to_string().parse().unwrap(),
+        );
+        headers.insert(EXPIRES_HEADER, expires.to_string().parse().unwrap());
+
+        Signed {
+            method,
+            path: path.to_string(),
+            headers,
+            body: Bytes::from(body.to_string()),
+            payload: (),
+        }
+    }
+
+    #[tokio::test]
+    async fn signed_envelope() {
+        let db_path = PathBuf::from("./signed_envelope_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+        let config = Config::new(conn.clone());
+
+        let path = "/pair/default/active";
+        let body = r#"{"active":true}"#;
+        let expires = Utc::now().timestamp() + 60;
+
+        let request = signed(Method::POST, path, body, 0, expires);
+        assert!(evaulate(&config, DEFAULT_PAIR, &request).await.is_ok());
+        assert_eq!(
+            identity_sequence(&conn, DEFAULT_PAIR, DEFAULT_PAIR)
+                .await
+                .unwrap(),
+            1
+        );
+
+        // Replaying the same request fails since the sequence was consumed
+        assert!(matches!(
+            evaulate(&config, DEFAULT_PAIR, &request).await,
+            Err(AppError::Unauthorized)
+        ));
+
+        // The signature is bound to the method, path and body
+        let mut request = signed(Method::POST, path, body, 1, expires);
+        request.path = "/pair/default/message".to_string();
+        assert!(matches!(
+            evaulate(&config, DEFAULT_PAIR, &request).await,
+            Err(AppError::Unauthorized)
+        ));
+        request.path = path.to_string();
+        request.method = Method::PUT;
+        assert!(matches!(
+            evaulate(&config, DEFAULT_PAIR, &request).await,
+            Err(AppError::Unauthorized)
+        ));
+        request.method = Method::POST;
+        request.body = Bytes::from(r#"{"active":false}"#);
+        assert!(matches!(
+            evaulate(&config, DEFAULT_PAIR, &request).await,
+            Err(AppError::Unauthorized)
+        ));
+
+        // Expired and too long lived signatures are rejected
+        let now = Utc::now().timestamp();
+        for expires in [now - 1, now + MAX_EXPIRY_SECONDS + 60] {
+            let request = signed(Method::POST, path, body, 1, expires);
+            assert!(matches!(
+                evaulate(&config, DEFAULT_PAIR, &request).await,
+                Err(AppError::Expired)
+            ));
+        }
+
+        // None of the rejected requests consumed the sequence
+        let request = signed(Method::POST, path, body, 1, expires);
+        assert!(evaulate(&config, DEFAULT_PAIR, &request).await.is_ok());
+
+        remove_file(db_path.clone()).unwrap();
     }
 }
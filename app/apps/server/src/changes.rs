@@ -0,0 +1,183 @@
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use axum::body::{to_bytes, Body};
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::header::{ETAG, IF_NONE_MATCH};
+use axum::http::{HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use secp256k1::hashes::{sha256, Hash};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_rusqlite::{params, Connection, OptionalExtension};
+
+pub const DEFAULT_CHANGES_TIMEOUT: u64 = 30;
+pub const MAX_CHANGES_TIMEOUT: u64 = 120;
+
+/// Returns the pair's revision, it goes up every time anything about the pair changes
+pub async fn pair_revision(connection: &Connection, pair: &str) -> AppResult<u64> {
+    let pair = pair.to_string();
+    let revision = connection
+        .call(move |conn| {
+            let res = conn
+                .query_row(
+                    "SELECT revision FROM pairs WHERE id = ?1",
+                    params![pair],
+                    |r| r.get(0),
+                )
+                .optional()?;
+            Ok(res)
+        })
+        .await?;
+    revision.ok_or(AppError::PairNotFound)
+}
+
+#[derive(Deserialize, Default)]
+pub struct ChangesQuery {
+    /// The last revision the caller has seen
+    pub since: Option<u64>,
+    /// Seconds to wait for a change
+    pub timeout: Option<u64>,
+}
+
+/// Returns the pair's revision as soon as it's newer than `since`, or once the timeout is up
+pub async fn get_changes(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    query: Result<Query<ChangesQuery>, QueryRejection>,
+) -> AppResult<String> {
+    let Query(query) = query.map_err(|e| AppError::InvalidQuery(e.body_text()))?;
+    let wait = query.timeout.unwrap_or(DEFAULT_CHANGES_TIMEOUT);
+    if wait > MAX_CHANGES_TIMEOUT {
+        return Err(AppError::InvalidQuery(format!(
+            "timeout must be at most {MAX_CHANGES_TIMEOUT} seconds"
+        )));
+    }
+
+    // Subscribing before reading the revision makes sure no change is missed in between
+    let mut events = config.events.subscribe();
+    let revision = pair_revision(&config.db, &pair).await?;
+    if query.since.is_none_or(|since| revision > since) {
+        return Ok(revision.to_string());
+    }
+
+    let changed = async {
+        loop {
+            match events.recv().await {
+                Ok((event_pair, _)) if event_pair != pair => continue,
+                _ => return,
+            }
+        }
+    };
+    let _ = timeout(Duration::from_secs(wait), changed).await;
+
+    Ok(pair_revision(&config.db, &pair).await?.to_string())
+}
+
+/// Tags successful GET responses with a hash of their body and answers with a 304 when the
+/// caller already has it
+pub async fn etag(request: Request, next: Next) -> AppResult<Response> {
+    if request.method() != Method::GET {
+        return Ok(next.run(request).await);
+    }
+    let if_none_match = request.headers().get(IF_NONE_MATCH).cloned();
+
+    let response = next.run(request).await;
+    if response.status() != StatusCode::OK {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    let hash = sha256::Hash::hash(&body).to_string();
+    let tag = HeaderValue::from_str(&format!("\"{}\"", &hash[..16])).unwrap();
+
+    if if_none_match.is_some_and(|value| value == tag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(ETAG, tag)]).into_response());
+    }
+
+    parts.headers.insert(ETAG, tag);
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{initialize_db, DefaultPair};
+    use crate::pair::DEFAULT_PAIR;
+    use crate::test_util::{send, signed_post};
+    use std::fs::remove_file;
+    use std::path::PathBuf;
+    use tokio::time::Instant;
+    use tower::ServiceExt;
+
+    fn get(path: &str) -> Request {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn revisions_and_etags() {
+        let db_path = PathBuf::from("./revisions_and_etags_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+        let config = Config::new(conn.clone());
+
+        let start = pair_revision(&conn, DEFAULT_PAIR).await.unwrap();
+        signed_post(&config, "/pair/default/message", r#"{"message":"hi"}"#).await;
+        signed_post(&config, "/pair/default/tick", r#"{"ty":1}"#).await;
+        // Failed requests don't change anything, even though they may consume a sequence
+        signed_post(&config, "/pair/default/tick", r#"{"ty":100}"#).await;
+        assert_eq!(pair_revision(&conn, DEFAULT_PAIR).await.unwrap(), start + 2);
+
+        // Callers behind are answered right away
+        let revision = (start + 2).to_string();
+        let (status, body) = send(&config, get("/pair/default/changes?since=0")).await;
+        assert_eq!((status, &body[..]), (StatusCode::OK, revision.as_bytes()));
+
+        // Callers up to date wait for the next change
+        let poller = tokio::spawn({
+            let config = config.clone();
+            let path = format!("/pair/default/changes?since={revision}&timeout=10");
+            async move { send(&config, get(&path)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        signed_post(&config, "/pair/default/active", r#"{"active":false}"#).await;
+        let revision = (start + 3).to_string();
+        let (_, body) = poller.await.unwrap();
+        assert_eq!(&body[..], revision.as_bytes());
+
+        // Or until the timeout
+        let now = Instant::now();
+        let path = format!("/pair/default/changes?since={revision}&timeout=1");
+        let (_, body) = send(&config, get(&path)).await;
+        assert_eq!(&body[..], revision.as_bytes());
+        assert!(now.elapsed() >= Duration::from_secs(1));
+        let (status, _) = send(&config, get("/pair/default/changes?timeout=1000")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        signed_post(&config, "/pair/default/active", r#"{"active":true}"#).await;
+
+        // Unchanged responses are not sent again
+        let response = crate::router(config.clone())
+            .oneshot(get("/pair/default/message"))
+            .await
+            .unwrap();
+        let tag = response.headers().get(ETAG).unwrap().clone();
+        let mut request = get("/pair/default/message");
+        request.headers_mut().insert(IF_NONE_MATCH, tag.clone());
+        let (status, body) = send(&config, request).await;
+        assert_eq!((status, body.len()), (StatusCode::NOT_MODIFIED, 0));
+
+        signed_post(&config, "/pair/default/message", r#"{"message":"new"}"#).await;
+        let mut request = get("/pair/default/message");
+        request.headers_mut().insert(IF_NONE_MATCH, tag);
+        let (status, body) = send(&config, request).await;
+        assert_eq!((status, &body[..]), (StatusCode::OK, b"new".as_slice()));
+
+        remove_file(db_path.clone()).unwrap();
+    }
+}
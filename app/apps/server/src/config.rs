@@ -1,58 +1,71 @@
-use crate::settings::{ACTIVE_SETTING, MESSAGE_SETTING, SEQUENCE_SETTING};
-use dotenv_codegen::dotenv;
+use crate::error::AppResult;
+use crate::events::{Events, EVENT_BUFFER};
+use crate::identities::{key_revoked, replace_key};
+use crate::migrations::migrate;
+use crate::pair::{create_pair, pair_exists, DEFAULT_PAIR};
 use secp256k1::PublicKey;
-use tokio_rusqlite::{params, Connection};
+use std::sync::Arc;
+use tokio::sync::{broadcast, Notify};
+use tokio_rusqlite::Connection;
 
 #[derive(Clone)]
 pub struct Config {
     pub db: Connection,
-    pub pubkey: PublicKey,
+    /// Every pair's events, subscribers filter out the ones for their pair
+    pub events: Events,
+    /// Wakes the scheduler up when a new action is scheduled
+    pub scheduler: Arc<Notify>,
 }
 
-pub async fn initialize_db(conn: &Connection) {
-    conn.call(|conn| {
-        // Create settings
-        let query = "CREATE TABLE settings (
-                key TEXT PRIMARY KEY,
-                value TEXT NOT NULL
-            );";
-        conn.execute(query, ())?;
+impl Config {
+    pub fn new(db: Connection) -> Self {
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        Self {
+            db,
+            events,
+            scheduler: Arc::new(Notify::new()),
+        }
+    }
+}
 
-        let insert = "INSERT INTO settings (key, value) VALUES (?1, ?2);";
-        let mut settings_insert = conn.prepare(insert)?;
-        settings_insert
-            .execute(params![ACTIVE_SETTING, "true"])
-            .unwrap();
-        settings_insert
-            .execute(params![MESSAGE_SETTING, "generic_message"])
-            .unwrap();
-        settings_insert
-            .execute(params![SEQUENCE_SETTING, "0"])
-            .unwrap();
+/// The pair owned by whoever runs the server
+pub struct DefaultPair {
+    pub pubkey: PublicKey,
+    /// Tick types the pair is created with
+    pub ticks: Vec<String>,
+}
 
-        // Go through all the defined ticks and create them
-        let query = "CREATE TABLE tick_types (
-                id INTEGER PRIMARY KEY,
-                value TEXT NOT NULL
-            );";
-        conn.execute(query, ())?;
-        let insert = "INSERT INTO tick_types (value) VALUES (?1);";
-        let mut tick_types_insert = conn.prepare(insert)?;
-        for tick in dotenv!("TICKS").split(',') {
-            tick_types_insert.execute(params![tick]).unwrap();
+#[cfg(test)]
+impl DefaultPair {
+    pub fn test() -> Self {
+        let secret_key = secp256k1::SecretKey::from_byte_array(&[1; 32]).unwrap();
+        Self {
+            pubkey: PublicKey::from_secret_key(&secp256k1::Secp256k1::new(), &secret_key),
+            ticks: vec!["first".to_string(), "second".to_string()],
         }
+    }
+}
 
-        // Create the tick history table
-        let query = "CREATE TABLE ticks (
-                id INTEGER PRIMARY KEY,
-                tick_type INTEGER NOT NULL,
-                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                FOREIGN KEY(tick_type) REFERENCES tick_types(id)
-            );";
-        conn.execute(query, ())?;
+/// Migrates the database to the latest schema and makes sure the default pair exists,
+/// returns the schema version the database was in before
+pub async fn initialize_db(conn: &Connection, default_pair: &DefaultPair) -> AppResult<u32> {
+    let version = migrate(conn).await?;
+
+    // The default pair's key follows the configured one, unless it was rotated away from it
+    let pubkey = default_pair.pubkey.to_string();
+    if pair_exists(conn, DEFAULT_PAIR).await? {
+        if !key_revoked(conn, DEFAULT_PAIR, &pubkey).await? {
+            replace_key(conn, DEFAULT_PAIR, DEFAULT_PAIR, pubkey).await?;
+        }
+    } else {
+        create_pair(
+            conn,
+            DEFAULT_PAIR.to_string(),
+            pubkey,
+            default_pair.ticks.clone(),
+        )
+        .await?;
+    }
 
-        Ok(())
-    })
-    .await
-    .unwrap();
+    Ok(version)
 }
@@ -0,0 +1,313 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+pub type AppResult<T> = Result<T, AppError>;
+
+/// Every error a handler can fail with, each one maps to a status code and a stable error code
+#[derive(Debug)]
+pub enum AppError {
+    /// A required header was not sent
+    MissingHeader(&'static str),
+    /// A header was sent but could not be parsed
+    InvalidHeader(&'static str),
+    /// The body could not be read or deserialized
+    InvalidBody(String),
+    /// The query parameters could not be understood
+    InvalidQuery(String),
+    /// The signature does not match the expected request
+    Unauthorized,
+    /// The signature's expiry is in the past or too far in the future
+    Expired,
+    /// The identity's key was revoked
+    KeyRevoked,
+    PairNotFound,
+    /// The pair has no identity with that name
+    IdentityNotFound,
+    /// The pair already has an identity with that name
+    IdentityExists,
+    /// Identities can only change their own key and scheduled actions
+    OtherIdentity,
+    /// Keys revoked in a pair can't be given to any of its identities
+    PubkeyRevoked,
+    TickTypeNotFound,
+    /// No tick matched, or it was already deleted
+    TickNotFound,
+    /// Archived tick types can't be ticked
+    TickTypeArchived,
+    /// Tick type ids are a single byte and have all been used
+    TickTypeLimit,
+    /// Paused pairs can't be ticked
+    PairInactive,
+    /// No pending scheduled action has that id
+    ScheduledNotFound,
+    PairExists,
+    Database(tokio_rusqlite::Error),
+    /// Stored data could not be understood
+    Internal(String),
+}
+
+/// The JSON body returned with every error
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::MissingHeader(_)
+            | AppError::InvalidHeader(_)
+            | AppError::InvalidBody(_)
+            | AppError::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized | AppError::Expired | AppError::KeyRevoked => {
+                StatusCode::UNAUTHORIZED
+            }
+            AppError::OtherIdentity => StatusCode::FORBIDDEN,
+            AppError::PairNotFound
+            | AppError::IdentityNotFound
+            | AppError::TickTypeNotFound
+            | AppError::TickNotFound
+            | AppError::ScheduledNotFound => StatusCode::NOT_FOUND,
+            AppError::PairExists
+            | AppError::IdentityExists
+            | AppError::PubkeyRevoked
+            | AppError::PairInactive
+            | AppError::TickTypeArchived
+            | AppError::TickTypeLimit => StatusCode::CONFLICT,
+            AppError::Database(_) | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine readable code, these must not change once released
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::MissingHeader(_) => "missing_header",
+            AppError::InvalidHeader(_) => "invalid_header",
+            AppError::InvalidBody(_) => "invalid_body",
+            AppError::InvalidQuery(_) => "invalid_query",
+            AppError::Unauthorized => "unauthorized",
+            AppError::Expired => "expired",
+            AppError::KeyRevoked => "key_revoked",
+            AppError::PairNotFound => "pair_not_found",
+            AppError::IdentityNotFound => "identity_not_found",
+            AppError::IdentityExists => "identity_exists",
+            AppError::OtherIdentity => "other_identity",
+            AppError::PubkeyRevoked => "pubkey_revoked",
+            AppError::TickTypeNotFound => "tick_type_not_found",
+            AppError::TickNotFound => "tick_not_found",
+            AppError::TickTypeArchived => "tick_type_archived",
+            AppError::TickTypeLimit => "tick_type_limit",
+            AppError::PairInactive => "pair_inactive",
+            AppError::ScheduledNotFound => "scheduled_not_found",
+            AppError::PairExists => "pair_exists",
+            AppError::Database(_) => "database",
+            AppError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::MissingHeader(header) => write!(f, "missing `{header}` header"),
+            AppError::InvalidHeader(header) => write!(f, "invalid `{header}` header"),
+            AppError::InvalidBody(err) => write!(f, "invalid body: {err}"),
+            AppError::InvalidQuery(err) => write!(f, "invalid query: {err}"),
+            AppError::Unauthorized => write!(f, "signature does not match the request"),
+            AppError::Expired => write!(f, "signature expired or expires too far in the future"),
+            AppError::KeyRevoked => write!(f, "key was revoked"),
+            AppError::PairNotFound => write!(f, "pair not found"),
+            AppError::IdentityNotFound => write!(f, "identity not found"),
+            AppError::IdentityExists => write!(f, "identity already exists"),
+            AppError::OtherIdentity => write!(f, "this belongs to another identity"),
+            AppError::PubkeyRevoked => write!(f, "pubkey was revoked"),
+            AppError::TickTypeNotFound => write!(f, "tick type not found"),
+            AppError::TickNotFound => write!(f, "tick not found"),
+            AppError::TickTypeArchived => write!(f, "tick type is archived"),
+            AppError::TickTypeLimit => write!(f, "no tick type ids left"),
+            AppError::PairInactive => write!(f, "pair is paused"),
+            AppError::ScheduledNotFound => write!(f, "scheduled action not found"),
+            AppError::PairExists => write!(f, "pair already exists"),
+            AppError::Database(err) => write!(f, "database error: {err}"),
+            AppError::Internal(err) => write!(f, "internal error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<tokio_rusqlite::Error> for AppError {
+    fn from(err: tokio_rusqlite::Error) -> Self {
+        AppError::Database(err)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let message = match &self {
+            // SQLite's errors describe the schema, they are only logged
+            AppError::Database(_) => {
+                tracing::error!("{self}");
+                "database error".to_string()
+            }
+            _ => self.to_string(),
+        };
+        let body = ErrorBody {
+            code: self.code().to_string(),
+            message,
+        };
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::{sign, Authentication, AUTH_HEADER, EXPIRES_HEADER};
+    use crate::config::{initialize_db, Config, DefaultPair};
+    use crate::router;
+    use axum::body::{to_bytes, Body};
+    use axum::http::{Method, Request};
+    use chrono::Utc;
+    use secp256k1::SecretKey;
+    use std::fs::remove_file;
+    use std::path::PathBuf;
+    use tokio_rusqlite::Connection;
+    use tower::ServiceExt;
+
+    async fn send(config: &Config, request: Request<Body>) -> (StatusCode, ErrorBody) {
+        let response = router(config.clone()).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn post(path: &str, body: &str, auth: Option<&str>, expires: Option<i64>) -> Request<Body> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(path)
+            .header("content-type", "application/json");
+        if let Some(auth) = auth {
+            request = request.header(AUTH_HEADER, auth);
+        }
+        if let Some(expires) = expires {
+            request = request.header(EXPIRES_HEADER, expires.to_string());
+        }
+        request.body(Body::from(body.to_string())).unwrap()
+    }
+
+    fn signature(path: &str, body: &str, sequence: u64, expires: i64) -> String {
+        let secret_key = SecretKey::from_byte_array(&[1; 32]).unwrap();
+        let auth = Authentication::new(&Method::POST, path, body.as_bytes(), sequence, expires);
+        sign(&secret_key, &auth).to_string()
+    }
+
+    #[tokio::test]
+    async fn failure_status_codes() {
+        let db_path = PathBuf::from("./failure_status_codes_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+        let config = Config::new(conn.clone());
+
+        let path = "/pair/default/tick";
+        let body = r#"{"ty":1}"#;
+        let expires = Utc::now().timestamp() + 60;
+        let valid = signature(path, body, 0, expires);
+
+        let cases = [
+            (
+                post(path, body, None, Some(expires)),
+                StatusCode::BAD_REQUEST,
+                "missing_header",
+            ),
+            (
+                post(path, body, Some(&valid), None),
+                StatusCode::BAD_REQUEST,
+                "missing_header",
+            ),
+            (
+                post(path, body, Some("not a signature"), Some(expires)),
+                StatusCode::BAD_REQUEST,
+                "invalid_header",
+            ),
+            (
+                post(path, "{", Some(&valid), Some(expires)),
+                StatusCode::BAD_REQUEST,
+                "invalid_body",
+            ),
+            (
+                post(
+                    path,
+                    body,
+                    Some(&signature(path, body, 5, expires)),
+                    Some(expires),
+                ),
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+            ),
+            (
+                post(path, body, Some(&valid), Some(expires - 120)),
+                StatusCode::UNAUTHORIZED,
+                "expired",
+            ),
+            (
+                post("/pair/missing/tick", body, Some(&valid), Some(expires)),
+                StatusCode::NOT_FOUND,
+                "pair_not_found",
+            ),
+            (
+                Request::get("/pair/default/tick_history?timezone=Mars/Olympus")
+                    .body(Body::empty())
+                    .unwrap(),
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+            ),
+            (
+                Request::get("/pair/missing/message")
+                    .body(Body::empty())
+                    .unwrap(),
+                StatusCode::NOT_FOUND,
+                "pair_not_found",
+            ),
+        ];
+
+        for (request, status, code) in cases {
+            let (res_status, res_body) = send(&config, request).await;
+            assert_eq!((res_status, res_body.code.as_str()), (status, code));
+        }
+
+        // Ticking an unknown tick type
+        let body = r#"{"ty":100}"#;
+        let request = post(
+            path,
+            body,
+            Some(&signature(path, body, 0, expires)),
+            Some(expires),
+        );
+        let (status, body) = send(&config, request).await;
+        assert_eq!(
+            (status, body.code.as_str()),
+            (StatusCode::NOT_FOUND, "tick_type_not_found")
+        );
+
+        // A broken database is reported instead of panicking
+        conn.call(|conn| Ok(conn.execute_batch("DROP TABLE ticks;")?))
+            .await
+            .unwrap();
+        let request = Request::get("/pair/default/tick_history")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(&config, request).await;
+        assert_eq!(
+            (status, body.code.as_str()),
+            (StatusCode::INTERNAL_SERVER_ERROR, "database")
+        );
+        assert_eq!(body.message, "database error");
+
+        remove_file(db_path.clone()).unwrap();
+    }
+}
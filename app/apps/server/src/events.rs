@@ -0,0 +1,153 @@
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::pair::pair_exists;
+use crate::tick::Tick;
+use axum::extract::{Path, State};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Events a slow subscriber can fall behind on before it starts missing them
+pub const EVENT_BUFFER: usize = 64;
+
+/// A change to a pair's state, sent to everyone subscribed to the pair
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PairEvent {
+    Message {
+        message: String,
+    },
+    Active {
+        active: bool,
+    },
+    Tick {
+        tick: Tick,
+    },
+    TickDeleted {
+        id: u64,
+    },
+    /// Tick types were added or changed
+    TickTypes,
+    /// Events were missed, everything should be fetched again
+    Lagged {
+        missed: u64,
+    },
+}
+
+impl PairEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PairEvent::Message { .. } => "message",
+            PairEvent::Active { .. } => "active",
+            PairEvent::Tick { .. } => "tick",
+            PairEvent::TickDeleted { .. } => "tick_deleted",
+            PairEvent::TickTypes => "tick_types",
+            PairEvent::Lagged { .. } => "lagged",
+        }
+    }
+}
+
+pub type Events = broadcast::Sender<(String, PairEvent)>;
+
+/// Sends the event to the pair's subscribers, it's fine if there are none
+pub fn publish(config: &Config, pair: &str, event: PairEvent) {
+    let _ = config.events.send((pair.to_string(), event));
+}
+
+/// Streams the pair's events as server sent events, each one named after its type with the
+/// event as JSON in its data
+pub async fn get_events(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+) -> AppResult<Sse<impl Stream<Item = Result<Event, Infallible>>>> {
+    if !pair_exists(&config.db, &pair).await? {
+        return Err(AppError::PairNotFound);
+    }
+
+    let receiver = config.events.subscribe();
+    let stream = stream::unfold(receiver, move |mut receiver| {
+        let pair = pair.clone();
+        async move {
+            let event = loop {
+                match receiver.recv().await {
+                    Ok((event_pair, event)) if event_pair == pair => break event,
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => break PairEvent::Lagged { missed },
+                    Err(RecvError::Closed) => return None,
+                }
+            };
+            let sse = Event::default()
+                .event(event.name())
+                .json_data(&event)
+                .unwrap();
+            Some((Ok(sse), receiver))
+        }
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{initialize_db, DefaultPair};
+    use crate::router;
+    use crate::test_util::signed_post;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use futures_util::StreamExt;
+    use std::fs::remove_file;
+    use std::path::PathBuf;
+    use tokio_rusqlite::Connection;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn pair_events() {
+        let db_path = PathBuf::from("./pair_events_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+        let config = Config::new(conn.clone());
+
+        let request = Request::get("/pair/default/events")
+            .body(Body::empty())
+            .unwrap();
+        let response = router(config.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut stream = response.into_body().into_data_stream();
+
+        // Other pairs' events are not sent
+        publish(&config, "other", PairEvent::TickTypes);
+        let (status, _) =
+            signed_post(&config, "/pair/default/message", r#"{"message":"hi"}"#).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = signed_post(&config, "/pair/default/tick", r#"{"ty":2}"#).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let chunk = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8(chunk.to_vec()).unwrap(),
+            "event: message\ndata: {\"type\":\"message\",\"message\":\"hi\"}\n\n"
+        );
+
+        let chunk = String::from_utf8(stream.next().await.unwrap().unwrap().to_vec()).unwrap();
+        let data = chunk
+            .lines()
+            .find_map(|line| line.strip_prefix("data: "))
+            .unwrap();
+        match serde_json::from_str(data).unwrap() {
+            PairEvent::Tick { tick } => assert_eq!((tick.id, tick.tick), (1, 2)),
+            event => panic!("unexpected event {event:?}"),
+        }
+
+        let request = Request::get("/pair/missing/events")
+            .body(Body::empty())
+            .unwrap();
+        let response = router(config.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        remove_file(db_path.clone()).unwrap();
+    }
+}
@@ -0,0 +1,670 @@
+use crate::auth::{evaulate, Signed};
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::pair::pair_exists;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tokio_rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+pub const MAX_IDENTITY_NAME_LEN: usize = 24;
+
+/// Someone allowed to sign requests for a pair, every pair starts with an identity named after it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub name: String,
+    pub pubkey: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AddIdentity {
+    pub name: String,
+    pub pubkey: String,
+}
+
+/// Any of the pair's identities can add another one, existing identities are never replaced
+pub async fn add_identity(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    request: Signed<AddIdentity>,
+) -> AppResult<impl IntoResponse> {
+    evaulate(&config, &pair, &request).await?;
+
+    let AddIdentity { name, pubkey } = request.payload;
+    if name.trim().is_empty() || name.chars().count() > MAX_IDENTITY_NAME_LEN {
+        return Err(AppError::InvalidBody(format!(
+            "identity name must be 1 to {MAX_IDENTITY_NAME_LEN} characters"
+        )));
+    }
+    validate_key(&config.db, &pair, &pubkey).await?;
+
+    let identity = Identity { name, pubkey };
+    let (name, key) = (identity.name.clone(), identity.pubkey.clone());
+    let inserted = config
+        .db
+        .call(move |conn| {
+            let res = conn.execute(
+                "INSERT INTO identities (pair, name, pubkey) VALUES (?1, ?2, ?3) \
+                ON CONFLICT (pair, name) DO NOTHING",
+                params![pair, name, key],
+            )?;
+            Ok(res)
+        })
+        .await?;
+    if inserted == 0 {
+        return Err(AppError::IdentityExists);
+    }
+
+    Ok((StatusCode::CREATED, Json(identity)))
+}
+
+pub async fn get_identities(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+) -> AppResult<Json<Vec<Identity>>> {
+    let pair_id = pair.clone();
+    let identities = config
+        .db
+        .call(move |conn| {
+            let res = conn
+                .prepare("SELECT name, pubkey FROM identities WHERE pair = ?1 ORDER BY rowid")?
+                .query_map(params![pair_id], |r| {
+                    Ok(Identity {
+                        name: r.get(0)?,
+                        pubkey: r.get(1)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(res)
+        })
+        .await?;
+
+    if identities.is_empty() && !pair_exists(&config.db, &pair).await? {
+        return Err(AppError::PairNotFound);
+    }
+    Ok(Json(identities))
+}
+
+#[derive(Deserialize, Default)]
+pub struct SequenceQuery {
+    /// Defaults to the identity named after the pair
+    pub identity: Option<String>,
+}
+
+/// Returns the sequence the identity's next signed request must use
+pub async fn get_sequence(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    query: Result<Query<SequenceQuery>, QueryRejection>,
+) -> AppResult<String> {
+    let Query(query) = query.map_err(|e| AppError::InvalidQuery(e.body_text()))?;
+    let identity = query.identity.unwrap_or_else(|| pair.clone());
+    Ok(identity_sequence(&config.db, &pair, &identity)
+        .await?
+        .to_string())
+}
+
+pub async fn identity_sequence(connection: &Connection, pair: &str, name: &str) -> AppResult<u64> {
+    let (pair_id, name) = (pair.to_string(), name.to_string());
+    let sequence = connection
+        .call(move |conn| {
+            let res = conn
+                .query_row(
+                    "SELECT sequence FROM identities WHERE pair = ?1 AND name = ?2",
+                    params![pair_id, name],
+                    |r| r.get(0),
+                )
+                .optional()?;
+            Ok(res)
+        })
+        .await?;
+
+    match sequence {
+        Some(sequence) => Ok(sequence),
+        None if pair_exists(connection, pair).await? => Err(AppError::IdentityNotFound),
+        None => Err(AppError::PairNotFound),
+    }
+}
+
+/// Returns the public key of the identity, failing when it or its pair don't exist
+pub async fn identity_key(connection: &Connection, pair: &str, name: &str) -> AppResult<PublicKey> {
+    let (pair_id, name) = (pair.to_string(), name.to_string());
+    let key: Option<String> = connection
+        .call(move |conn| {
+            let res = conn
+                .query_row(
+                    "SELECT pubkey FROM identities WHERE pair = ?1 AND name = ?2",
+                    params![pair_id, name],
+                    |r| r.get(0),
+                )
+                .optional()?;
+            Ok(res)
+        })
+        .await?;
+
+    match key {
+        Some(key) => PublicKey::from_str(&key)
+            .map_err(|_| AppError::Internal(format!("stored key `{key}` is not a public key"))),
+        None if pair_exists(connection, pair).await? => Err(AppError::IdentityNotFound),
+        None => Err(AppError::PairNotFound),
+    }
+}
+
+/// Drops the identity's scheduled actions that weren't applied yet, they were signed with a key
+/// that is no longer trusted
+fn cancel_pending(tx: &Transaction, pair: &str, name: &str) -> rusqlite::Result<usize> {
+    tx.execute(
+        "DELETE FROM scheduled WHERE pair = ?1 AND author = ?2 AND applied_at IS NULL",
+        params![pair, name],
+    )
+}
+
+/// Replaces the identity's key, the previous one is revoked so it can never sign for the identity
+/// again
+pub async fn replace_key(
+    connection: &Connection,
+    pair: &str,
+    name: &str,
+    pubkey: String,
+) -> AppResult<()> {
+    let (pair, name) = (pair.to_string(), name.to_string());
+    connection
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            let previous: Option<String> = tx
+                .query_row(
+                    "SELECT pubkey FROM identities WHERE pair = ?1 AND name = ?2",
+                    params![pair, name],
+                    |r| r.get(0),
+                )
+                .optional()?;
+            let Some(previous) = previous else {
+                return Ok(Err(AppError::IdentityNotFound));
+            };
+            if previous == pubkey {
+                return Ok(Ok(()));
+            }
+
+            // Pairs migrated from before keys were stored start without one
+            if !previous.is_empty() {
+                tx.execute(
+                    "INSERT OR IGNORE INTO revoked_keys (pubkey, pair, identity) VALUES (?1, ?2, ?3)",
+                    params![previous, pair, name],
+                )?;
+            }
+            tx.execute(
+                "UPDATE identities SET pubkey = ?1 WHERE pair = ?2 AND name = ?3",
+                params![pubkey, pair, name],
+            )?;
+            cancel_pending(&tx, &pair, &name)?;
+            tx.commit()?;
+            Ok(Ok(()))
+        })
+        .await?
+}
+
+/// Whether the key was revoked by any of the pair's identities, other pairs may still use it
+pub async fn key_revoked(connection: &Connection, pair: &str, pubkey: &str) -> AppResult<bool> {
+    let (pair, pubkey) = (pair.to_string(), pubkey.to_string());
+    connection
+        .call(move |conn| {
+            let res = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM revoked_keys WHERE pair = ?1 AND pubkey = ?2)",
+                params![pair, pubkey],
+                |r| r.get(0),
+            )?;
+            Ok(res)
+        })
+        .await
+        .map_err(AppError::from)
+}
+
+/// Makes sure a key given in a request can be used to sign for the pair
+pub async fn validate_key(connection: &Connection, pair: &str, pubkey: &str) -> AppResult<()> {
+    if let Err(err) = PublicKey::from_str(pubkey) {
+        return Err(AppError::InvalidBody(format!("invalid pubkey: {err}")));
+    }
+    if key_revoked(connection, pair, pubkey).await? {
+        return Err(AppError::PubkeyRevoked);
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RotateKey {
+    pub pubkey: String,
+}
+
+/// Replaces the signing identity's key with a new one, the request must be signed with the old key
+pub async fn rotate_key(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    request: Signed<RotateKey>,
+) -> AppResult<impl IntoResponse> {
+    let name = evaulate(&config, &pair, &request).await?;
+
+    let pubkey = request.payload.pubkey;
+    validate_key(&config.db, &pair, &pubkey).await?;
+    replace_key(&config.db, &pair, &name, pubkey.clone()).await?;
+
+    Ok((StatusCode::CREATED, Json(Identity { name, pubkey })))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RevokedKey {
+    pub pubkey: String,
+    /// The identity the key belonged to
+    pub identity: String,
+    pub revoked_at: String,
+}
+
+/// Revokes the signing identity's key without replacing it, the identity can't sign anymore
+#[derive(Serialize, Deserialize)]
+pub struct RevokeKey {
+    /// Must be the signing identity
+    pub identity: String,
+}
+
+/// An identity revokes its own key, ex: when it may have leaked
+pub async fn revoke_key(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    request: Signed<RevokeKey>,
+) -> AppResult<Json<RevokedKey>> {
+    let name = evaulate(&config, &pair, &request).await?;
+    if request.payload.identity != name {
+        return Err(AppError::OtherIdentity);
+    }
+
+    let pubkey = identity_key(&config.db, &pair, &name).await?.to_string();
+    let revoked = config
+        .db
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT OR IGNORE INTO revoked_keys (pubkey, pair, identity) VALUES (?1, ?2, ?3)",
+                params![pubkey, pair, name],
+            )?;
+            cancel_pending(&tx, &pair, &name)?;
+            let res = tx.query_row(
+                "SELECT pubkey, identity, revoked_at FROM revoked_keys \
+                WHERE pair = ?1 AND identity = ?2 AND pubkey = ?3",
+                params![pair, name, pubkey],
+                |r| {
+                    Ok(RevokedKey {
+                        pubkey: r.get(0)?,
+                        identity: r.get(1)?,
+                        revoked_at: r.get(2)?,
+                    })
+                },
+            )?;
+            tx.commit()?;
+            Ok(res)
+        })
+        .await?;
+
+    Ok(Json(revoked))
+}
+
+/// Every key that was revoked or rotated out of the pair
+pub async fn get_revoked_keys(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+) -> AppResult<Json<Vec<RevokedKey>>> {
+    if !pair_exists(&config.db, &pair).await? {
+        return Err(AppError::PairNotFound);
+    }
+
+    let revoked = config
+        .db
+        .call(move |conn| {
+            let res = conn
+                .prepare(
+                    "SELECT pubkey, identity, revoked_at FROM revoked_keys \
+                    WHERE pair = ?1 ORDER BY revoked_at, rowid",
+                )?
+                .query_map(params![pair], |r| {
+                    Ok(RevokedKey {
+                        pubkey: r.get(0)?,
+                        identity: r.get(1)?,
+                        revoked_at: r.get(2)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(res)
+        })
+        .await?;
+
+    Ok(Json(revoked))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{initialize_db, DefaultPair};
+    use crate::messages::query_messages;
+    use crate::pair::DEFAULT_PAIR;
+    use crate::scheduler::query_scheduled;
+    use crate::test_util::{send, signed_post, signed_post_as};
+    use axum::body::Body;
+    use axum::http::Request;
+    use chrono::{TimeDelta, Utc};
+    use secp256k1::{Secp256k1, SecretKey};
+    use std::fs::remove_file;
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn pair_identities() {
+        let db_path = PathBuf::from("./pair_identities_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+        let config = Config::new(conn.clone());
+
+        let partner = SecretKey::from_byte_array(&[2; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &partner);
+        let body = format!(r#"{{"name":"partner","pubkey":"{pubkey}"}}"#);
+        let (status, _) = signed_post(&config, "/pair/default/identities", &body).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = signed_post(&config, "/pair/default/identities", &body).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(
+            identity_key(&conn, DEFAULT_PAIR, "partner").await.unwrap(),
+            pubkey
+        );
+
+        // Each identity signs with its own key and sequence
+        let (status, _) = signed_post_as(
+            &config,
+            "/pair/default/message",
+            r#"{"message":"from partner"}"#,
+            "partner",
+            &partner,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = signed_post_as(
+            &config,
+            "/pair/default/tick",
+            r#"{"ty":1}"#,
+            "partner",
+            &partner,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        signed_post(&config, "/pair/default/message", r#"{"message":"from me"}"#).await;
+        assert_eq!(
+            identity_sequence(&conn, DEFAULT_PAIR, "partner")
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            identity_sequence(&conn, DEFAULT_PAIR, DEFAULT_PAIR)
+                .await
+                .unwrap(),
+            3
+        );
+
+        // Another identity's key doesn't work
+        let (status, _) = signed_post_as(
+            &config,
+            "/pair/default/tick",
+            r#"{"ty":1}"#,
+            DEFAULT_PAIR,
+            &partner,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = signed_post_as(
+            &config,
+            "/pair/default/tick",
+            r#"{"ty":1}"#,
+            "stranger",
+            &partner,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Everything remembers who made it
+        let (messages, _) = query_messages(&conn, DEFAULT_PAIR, None, 2).await.unwrap();
+        let authors: Vec<&str> = messages.iter().map(|m| m.author.as_str()).collect();
+        assert_eq!(authors, vec![DEFAULT_PAIR, "partner"]);
+        let authors: Vec<String> = conn
+            .call(|conn| {
+                let res = conn
+                    .prepare("SELECT author FROM ticks")?
+                    .query_map([], |r| r.get(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(res)
+            })
+            .await
+            .unwrap();
+        assert_eq!(authors, vec!["partner"]);
+
+        // Only the default pair's own identity can register pairs
+        let other = PublicKey::from_secret_key(&Secp256k1::new(), &partner);
+        let register = format!(r#"{{"id":"other","pubkey":"{other}","ticks":[]}}"#);
+        let (status, _) = signed_post_as(&config, "/pairs", &register, "partner", &partner).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = signed_post(&config, "/pairs", &register).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(identity_key(&conn, "other", "other").await.unwrap(), other);
+        assert!(matches!(
+            identity_sequence(&conn, "missing", "missing").await,
+            Err(AppError::PairNotFound)
+        ));
+
+        remove_file(db_path.clone()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn key_rotation() {
+        let db_path = PathBuf::from("./key_rotation_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+        let config = Config::new(conn.clone());
+
+        let old = SecretKey::from_byte_array(&[1; 32]).unwrap();
+        let old_pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &old);
+        let new = SecretKey::from_byte_array(&[3; 32]).unwrap();
+        let new_pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &new);
+        let due_at = (Utc::now() + TimeDelta::minutes(5)).to_rfc3339();
+        let scheduled = format!(r#"{{"due_at":"{due_at}","action":{{"type":"tick","ty":1}}}}"#);
+        signed_post(&config, "/pair/default/scheduled", &scheduled).await;
+
+        // The rotation is signed with the old key
+        let body = format!(r#"{{"pubkey":"{new_pubkey}"}}"#);
+        let (status, _) = signed_post(&config, "/pair/default/identities/rotate", &body).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            identity_key(&conn, DEFAULT_PAIR, DEFAULT_PAIR)
+                .await
+                .unwrap(),
+            new_pubkey
+        );
+        // What the old key scheduled is canceled along with it
+        assert!(query_scheduled(&conn, DEFAULT_PAIR, true)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // The old key no longer works while the new one keeps the sequence going
+        let (status, body) =
+            signed_post(&config, "/pair/default/active", r#"{"active":true}"#).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(String::from_utf8_lossy(&body).contains("unauthorized"));
+        let (status, _) = signed_post_as(
+            &config,
+            "/pair/default/active",
+            r#"{"active":true}"#,
+            DEFAULT_PAIR,
+            &new,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        // Revoked keys can't be brought back to their identity
+        let body = format!(r#"{{"pubkey":"{old_pubkey}"}}"#);
+        let (status, body) = signed_post_as(
+            &config,
+            "/pair/default/identities/rotate",
+            &body,
+            DEFAULT_PAIR,
+            &new,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(String::from_utf8_lossy(&body).contains("pubkey_revoked"));
+
+        // Restarting with the old configured key keeps the rotated one
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+        assert_eq!(
+            identity_key(&conn, DEFAULT_PAIR, DEFAULT_PAIR)
+                .await
+                .unwrap(),
+            new_pubkey
+        );
+
+        // Identities can't revoke or replace each other
+        let partner = SecretKey::from_byte_array(&[2; 32]).unwrap();
+        let partner_pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &partner);
+        let body = format!(r#"{{"name":"partner","pubkey":"{partner_pubkey}"}}"#);
+        signed_post_as(
+            &config,
+            "/pair/default/identities",
+            &body,
+            DEFAULT_PAIR,
+            &new,
+        )
+        .await;
+        let (status, body) = signed_post_as(
+            &config,
+            "/pair/default/identities/revoke",
+            r#"{"identity":"default"}"#,
+            "partner",
+            &partner,
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(String::from_utf8_lossy(&body).contains("other_identity"));
+        let body = format!(r#"{{"name":"default","pubkey":"{partner_pubkey}"}}"#);
+        let (status, _) = signed_post_as(
+            &config,
+            "/pair/default/identities",
+            &body,
+            "partner",
+            &partner,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(
+            identity_key(&conn, DEFAULT_PAIR, DEFAULT_PAIR)
+                .await
+                .unwrap(),
+            new_pubkey
+        );
+
+        // Another identity shares the partner's key before it leaks
+        let body = format!(r#"{{"name":"tablet","pubkey":"{partner_pubkey}"}}"#);
+        let (status, _) = signed_post_as(
+            &config,
+            "/pair/default/identities",
+            &body,
+            DEFAULT_PAIR,
+            &new,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        // A leaked key is revoked by its own identity, which can't sign anymore
+        signed_post_as(
+            &config,
+            "/pair/default/scheduled",
+            &scheduled,
+            "partner",
+            &partner,
+        )
+        .await;
+        signed_post_as(
+            &config,
+            "/pair/default/scheduled",
+            &scheduled,
+            DEFAULT_PAIR,
+            &new,
+        )
+        .await;
+        let (status, _) = signed_post_as(
+            &config,
+            "/pair/default/identities/revoke",
+            r#"{"identity":"partner"}"#,
+            "partner",
+            &partner,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = signed_post_as(
+            &config,
+            "/pair/default/tick",
+            r#"{"ty":1}"#,
+            "partner",
+            &partner,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(String::from_utf8_lossy(&body).contains("key_revoked"));
+        let pending = query_scheduled(&conn, DEFAULT_PAIR, false).await.unwrap();
+        let authors: Vec<&str> = pending.iter().map(|s| s.author.as_str()).collect();
+        assert_eq!(authors, vec![DEFAULT_PAIR]);
+
+        // Revoked keys are refused pair-wide, they can't come back under another identity
+        let body = format!(r#"{{"name":"phone","pubkey":"{old_pubkey}"}}"#);
+        let (status, body) = signed_post_as(
+            &config,
+            "/pair/default/identities",
+            &body,
+            DEFAULT_PAIR,
+            &new,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(String::from_utf8_lossy(&body).contains("pubkey_revoked"));
+        let (status, body) = signed_post_as(
+            &config,
+            "/pair/default/tick",
+            r#"{"ty":1}"#,
+            "tablet",
+            &partner,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(String::from_utf8_lossy(&body).contains("key_revoked"));
+
+        let (status, body) = send(
+            &config,
+            Request::get("/pair/default/identities/revoked")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let revoked: Vec<RevokedKey> = serde_json::from_slice(&body).unwrap();
+        let revoked: Vec<(String, String)> = revoked
+            .into_iter()
+            .map(|key| (key.pubkey, key.identity))
+            .collect();
+        assert_eq!(
+            revoked,
+            vec![
+                (old_pubkey.to_string(), DEFAULT_PAIR.to_string()),
+                (partner_pubkey.to_string(), "partner".to_string()),
+            ]
+        );
+
+        remove_file(db_path.clone()).unwrap();
+    }
+}
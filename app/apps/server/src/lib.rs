@@ -1,8 +1,94 @@
 mod auth;
+mod changes;
 mod config;
+mod error;
+mod events;
+mod identities;
+mod messages;
+mod migrations;
+pub mod options;
+mod pair;
+mod scheduler;
 mod settings;
+#[cfg(test)]
+mod test_util;
 mod tick;
+mod tick_types;
 
-pub use auth::{sign, Authentication};
-pub use settings::{Active, Message};
-pub use tick::{Tick, TickType, TriggerTick};
+use crate::changes::{etag, get_changes};
+use crate::events::get_events;
+use crate::identities::{
+    add_identity, get_identities, get_revoked_keys, get_sequence, revoke_key, rotate_key,
+};
+use crate::messages::{get_message, get_messages, set_message};
+use crate::pair::register_pair;
+use crate::scheduler::{cancel_scheduled, get_scheduled, schedule};
+use crate::settings::*;
+use crate::tick::{
+    delete_tick, get_embedded_tick_history, get_embedded_tick_stats, get_tick_history,
+    get_tick_stats, trigger_tick,
+};
+use crate::tick_types::{
+    add_tick_type, get_embedded_ticks, get_ticks, reorder_tick_types, update_tick_type,
+};
+use axum::middleware::from_fn;
+use axum::response::IntoResponse;
+use axum::routing::{get, post};
+use axum::Router;
+
+pub use auth::{
+    sign, verify, Authentication, AUTH_HEADER, EXPIRES_HEADER, IDENTITY_HEADER, MAX_EXPIRY_SECONDS,
+};
+pub use config::{initialize_db, Config, DefaultPair};
+pub use error::{AppError, ErrorBody};
+pub use events::PairEvent;
+pub use identities::{AddIdentity, Identity, RevokeKey, RevokedKey, RotateKey};
+pub use messages::{Message, StoredMessage};
+pub use migrations::HEAD as SCHEMA_VERSION;
+pub use pair::{RegisterPair, DEFAULT_PAIR};
+pub use scheduler::{run_scheduler, CancelScheduled, Schedule, Scheduled, ScheduledAction};
+pub use settings::{Active, QuietHours, Timezone};
+pub use tick::{Bucket, DeleteTick, StatsBucket, Tick, TickStats, TriggerTick, NEXT_CURSOR_HEADER};
+pub use tick_types::{AddTickType, ReorderTickTypes, TickType, UpdateTickType};
+
+pub fn router(config: Config) -> Router {
+    // Everything that belongs to a pair is nested under its id
+    let pair = Router::new()
+        .route("/message", get(get_message).post(set_message))
+        .route("/messages", get(get_messages))
+        .route("/active", get(get_active).post(set_active))
+        .route("/sequence", get(get_sequence))
+        .route("/identities", get(get_identities).post(add_identity))
+        .route("/identities/rotate", post(rotate_key))
+        .route("/identities/revoke", post(revoke_key))
+        .route("/identities/revoked", get(get_revoked_keys))
+        .route("/timezone", get(get_timezone).post(set_timezone))
+        .route("/quiet_hours", get(get_quiet_hours).post(set_quiet_hours))
+        .route("/tick", post(trigger_tick))
+        .route("/tick/delete", post(delete_tick))
+        .route("/ticks", get(get_ticks))
+        .route("/compressed_ticks", get(get_embedded_ticks))
+        .route("/tick_types", post(add_tick_type))
+        .route("/tick_types/order", post(reorder_tick_types))
+        .route("/tick_types/{id}", post(update_tick_type))
+        .route("/tick_history", get(get_tick_history))
+        .route("/compressed_tick_history", get(get_embedded_tick_history))
+        .route("/tick_stats", get(get_tick_stats))
+        .route("/compressed_tick_stats", get(get_embedded_tick_stats))
+        .route("/scheduled", get(get_scheduled).post(schedule))
+        .route("/scheduled/cancel", post(cancel_scheduled))
+        .layer(from_fn(etag))
+        // Streamed responses can't be tagged
+        .route("/events", get(get_events))
+        .route("/changes", get(get_changes));
+
+    Router::new()
+        .route("/", get(health_check))
+        .route("/pairs", post(register_pair))
+        .nest("/pair/{pair}", pair)
+        .with_state(config)
+}
+
+async fn health_check() -> impl IntoResponse {
+    "healthy".to_string()
+}
@@ -1,65 +1,56 @@
-mod auth;
-mod config;
-mod settings;
-mod tick;
-
-use crate::config::{initialize_db, Config};
-use crate::settings::*;
-use crate::tick::{get_embedded_tick_history, get_tick_history, get_ticks, trigger_tick};
-use axum::response::IntoResponse;
-use axum::routing::{get, post};
-use axum::Router;
+use clap::Parser;
 use dotenv::dotenv;
-use dotenv_codegen::dotenv;
-use secp256k1::PublicKey;
-use serde::{Deserialize, Serialize};
-use std::fs::{exists, remove_file};
-use std::path::PathBuf;
-use std::str::FromStr;
+use server::options::ServerArgs;
+use server::{initialize_db, router, run_scheduler, Config, SCHEMA_VERSION};
+use std::process::exit;
 use tokio_rusqlite::Connection;
 
+#[derive(Parser)]
+#[command(about = "Long Distance Companion server")]
+struct Cli {
+    #[command(flatten)]
+    args: ServerArgs,
+}
+
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    let db_path = PathBuf::from(dotenv!("DB_PATH"));
-    let public_key = PublicKey::from_str(dotenv!("PUBLIC_KEY")).unwrap();
-
-    #[cfg(debug_assertions)]
-    remove_file(db_path.clone()).ok();
-
-    let init = !exists(db_path.clone()).unwrap();
-
-    let conn = Connection::open(db_path).await.unwrap();
+    // Initialized first so startup errors are logged too
+    tracing_subscriber::fmt::init();
 
-    if init {
-        initialize_db(&conn).await;
+    let options = match Cli::parse().args.resolve() {
+        Ok(options) => options,
+        Err(err) => {
+            tracing::error!("{err}");
+            exit(2);
+        }
+    };
+
+    let conn = Connection::open(&options.db_path).await.unwrap();
+
+    // Creates or upgrades the schema as needed
+    let version = match initialize_db(&conn, &options.default_pair).await {
+        Ok(version) => version,
+        Err(err) => {
+            tracing::error!("could not initialize the database: {err}");
+            exit(1);
+        }
+    };
+    if version != SCHEMA_VERSION {
+        println!("Migrated database from version {version} to {SCHEMA_VERSION}");
     }
 
-    // initialize tracing
-    tracing_subscriber::fmt::init();
+    let config = Config::new(conn);
+
+    // Applies scheduled actions, including the ones that came due while the server was down
+    tokio::spawn(run_scheduler(config.clone()));
 
     // build our application with a route
-    let app = Router::new()
-        .route("/", get(health_check))
-        .route("/message", get(get_message).post(set_message))
-        .route("/active", get(get_active).post(set_active))
-        .route("/sequence", get(get_sequence))
-        .route("/tick", post(trigger_tick))
-        .route("/ticks", get(get_ticks))
-        .route("/tick_history", get(get_tick_history))
-        .route("/compressed_tick_history", get(get_embedded_tick_history))
-        .with_state(Config {
-            db: conn,
-            pubkey: public_key,
-        });
+    let app = router(config);
 
     // run our app with hyper
-    let listener = tokio::net::TcpListener::bind(dotenv!("SERVER_URL"))
+    let listener = tokio::net::TcpListener::bind(options.server_url)
         .await
         .unwrap();
     axum::serve(listener, app).await.unwrap();
 }
-
-async fn health_check() -> impl IntoResponse {
-    "healthy".to_string()
-}
@@ -0,0 +1,429 @@
+use crate::auth::{evaulate, Signed};
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::events::{publish, PairEvent};
+use crate::pair::pair_exists;
+use crate::settings::pair_active;
+use crate::tick::NEXT_CURSOR_HEADER;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio_rusqlite::{params, Connection, OptionalExtension};
+
+pub const DEFAULT_MESSAGES_LIMIT: u32 = 50;
+pub const MAX_MESSAGES_LIMIT: u32 = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    pub message: String,
+    /// When the message stops being the current one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoredMessage {
+    pub id: u64,
+    pub author: String,
+    pub message: String,
+    pub time: String,
+    pub expires_at: Option<String>,
+}
+
+pub async fn set_message(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    request: Signed<Message>,
+) -> AppResult<impl IntoResponse> {
+    let author = evaulate(&config, &pair, &request).await?;
+    let stored = post_message(&config, &pair, &author, request.payload).await?;
+
+    Ok((StatusCode::CREATED, stored.message))
+}
+
+/// Stores a new message for the pair and lets its subscribers know
+pub async fn post_message(
+    config: &Config,
+    pair: &str,
+    author: &str,
+    message: Message,
+) -> AppResult<StoredMessage> {
+    let Message {
+        message,
+        expires_at,
+    } = message;
+    if expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Err(AppError::InvalidBody(
+            "message would already be expired".to_string(),
+        ));
+    }
+
+    let stored = add_message(&config.db, pair, author, message, expires_at).await?;
+    if expires_at.is_some() {
+        // The scheduler waits for the message to expire
+        config.scheduler.notify_one();
+    }
+    publish(
+        config,
+        pair,
+        PairEvent::Message {
+            message: stored.message.clone(),
+        },
+    );
+
+    Ok(stored)
+}
+
+/// Returns the current message, which is empty when every message expired or while the pair is
+/// paused
+pub async fn get_message(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+) -> AppResult<String> {
+    let message = current_message(&config.db, &pair).await?;
+    if !pair_active(&config.db, &pair).await? {
+        return Ok(String::new());
+    }
+    Ok(message)
+}
+
+#[derive(Deserialize, Default)]
+pub struct MessagesQuery {
+    /// Id of the last message of the previous page
+    pub cursor: Option<u64>,
+    pub limit: Option<u32>,
+}
+
+/// Every message ever set, newest first
+pub async fn get_messages(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    query: Result<Query<MessagesQuery>, QueryRejection>,
+) -> AppResult<impl IntoResponse> {
+    let Query(query) = query.map_err(|e| AppError::InvalidQuery(e.body_text()))?;
+    if !pair_exists(&config.db, &pair).await? {
+        return Err(AppError::PairNotFound);
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_MESSAGES_LIMIT);
+    if limit == 0 || limit > MAX_MESSAGES_LIMIT {
+        return Err(AppError::InvalidQuery(format!(
+            "limit must be in 1..={MAX_MESSAGES_LIMIT}"
+        )));
+    }
+
+    let (messages, next) = query_messages(&config.db, &pair, query.cursor, limit).await?;
+
+    let mut headers = HeaderMap::new();
+    if let Some(next) = next {
+        headers.insert(NEXT_CURSOR_HEADER, HeaderValue::from(next));
+    }
+    Ok((headers, Json(messages)))
+}
+
+pub async fn add_message(
+    connection: &Connection,
+    pair: &str,
+    author: &str,
+    message: String,
+    expires_at: Option<DateTime<Utc>>,
+) -> AppResult<StoredMessage> {
+    let pair = pair.to_string();
+    let author = author.to_string();
+    let expires_at = expires_at.map(|expires_at| expires_at.naive_utc());
+    connection
+        .call(move |conn| {
+            let res = conn.query_row(
+                "INSERT INTO messages (pair, author, message, expires_at) \
+                VALUES (?1, ?2, ?3, ?4) \
+                RETURNING id, author, message, created_at, expires_at;",
+                params![pair, author, message, expires_at],
+                |r| {
+                    Ok(StoredMessage {
+                        id: r.get(0)?,
+                        author: r.get(1)?,
+                        message: r.get(2)?,
+                        time: r.get(3)?,
+                        expires_at: r.get(4)?,
+                    })
+                },
+            )?;
+            Ok(res)
+        })
+        .await
+        .map_err(AppError::from)
+}
+
+/// The latest message that hasn't expired
+pub async fn current_message(connection: &Connection, pair: &str) -> AppResult<String> {
+    current_message_at(connection, pair, Utc::now()).await
+}
+
+/// The latest message that hadn't expired at `now`
+pub async fn current_message_at(
+    connection: &Connection,
+    pair: &str,
+    now: DateTime<Utc>,
+) -> AppResult<String> {
+    let db_pair = pair.to_string();
+    let now = now.naive_utc();
+    let message: Option<String> = connection
+        .call(move |conn| {
+            let res = conn
+                .query_row(
+                    "\
+                SELECT message FROM messages \
+                WHERE pair = ?1 AND (expires_at IS NULL OR expires_at > ?2) \
+                ORDER BY id DESC \
+                LIMIT 1",
+                    params![db_pair, now],
+                    |r| r.get(0),
+                )
+                .optional()?;
+            Ok(res)
+        })
+        .await?;
+
+    match message {
+        Some(message) => Ok(message),
+        None if pair_exists(connection, pair).await? => Ok(String::new()),
+        None => Err(AppError::PairNotFound),
+    }
+}
+
+/// Bumps the revision of the pairs whose current message expired in `(since, now]` and publishes
+/// the message that replaces it, returns the next time a message expires
+pub async fn publish_expired_messages(
+    config: &Config,
+    since: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> AppResult<Option<DateTime<Utc>>> {
+    let (since, now_utc) = (since.naive_utc(), now.naive_utc());
+    let (pairs, next): (Vec<String>, Option<NaiveDateTime>) = config
+        .db
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            // Only the message that was current at `since` changes what the pair shows
+            let pairs = tx
+                .prepare(
+                    "\
+                SELECT pair FROM messages AS expired \
+                WHERE expires_at > ?1 AND expires_at <= ?2 AND id = ( \
+                    SELECT MAX(id) FROM messages \
+                    WHERE pair = expired.pair AND (expires_at IS NULL OR expires_at > ?1))",
+                )?
+                .query_map(params![since, now_utc], |r| r.get(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            for pair in &pairs {
+                tx.execute(
+                    "UPDATE pairs SET revision = revision + 1 WHERE id = ?1",
+                    params![pair],
+                )?;
+            }
+            let next = tx.query_row(
+                "SELECT MIN(expires_at) FROM messages WHERE expires_at > ?1",
+                params![now_utc],
+                |r| r.get(0),
+            )?;
+            tx.commit()?;
+            Ok((pairs, next))
+        })
+        .await?;
+
+    for pair in pairs {
+        let message = current_message_at(&config.db, &pair, now).await?;
+        publish(config, &pair, PairEvent::Message { message });
+    }
+    Ok(next.map(|next| next.and_utc()))
+}
+
+/// Returns a page of messages, newest first, along with the cursor of the next page
+pub async fn query_messages(
+    connection: &Connection,
+    pair: &str,
+    cursor: Option<u64>,
+    limit: u32,
+) -> AppResult<(Vec<StoredMessage>, Option<u64>)> {
+    let pair = pair.to_string();
+    let mut messages = connection
+        .call(move |conn| {
+            let res = conn
+                .prepare(
+                    "\
+                SELECT id, author, message, created_at, expires_at \
+                FROM messages \
+                WHERE pair = ?1 AND (?2 IS NULL OR id < ?2) \
+                ORDER BY id DESC \
+                LIMIT ?3",
+                )?
+                .query_map(params![pair, cursor, limit + 1], |r| {
+                    Ok(StoredMessage {
+                        id: r.get(0)?,
+                        author: r.get(1)?,
+                        message: r.get(2)?,
+                        time: r.get(3)?,
+                        expires_at: r.get(4)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(res)
+        })
+        .await?;
+
+    // One extra message is queried to know if there's another page
+    let next = if messages.len() > limit as usize {
+        messages.truncate(limit as usize);
+        messages.last().map(|message| message.id)
+    } else {
+        None
+    };
+
+    Ok((messages, next))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::changes::pair_revision;
+    use crate::config::{initialize_db, DefaultPair};
+    use crate::pair::DEFAULT_PAIR;
+    use chrono::TimeDelta;
+    use std::fs::remove_file;
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn message_history() {
+        let db_path = PathBuf::from("./message_history_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+
+        add_message(&conn, DEFAULT_PAIR, "me", "SOMETHING".to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            current_message(&conn, DEFAULT_PAIR).await.unwrap(),
+            "SOMETHING"
+        );
+
+        add_message(
+            &conn,
+            DEFAULT_PAIR,
+            "me",
+            "SOMETHING_ELSE".to_string(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            current_message(&conn, DEFAULT_PAIR).await.unwrap(),
+            "SOMETHING_ELSE"
+        );
+
+        // Expired messages fall back to the one before them
+        let expired = Utc::now() - TimeDelta::minutes(1);
+        add_message(&conn, DEFAULT_PAIR, "me", "GONE".to_string(), Some(expired))
+            .await
+            .unwrap();
+        assert_eq!(
+            current_message(&conn, DEFAULT_PAIR).await.unwrap(),
+            "SOMETHING_ELSE"
+        );
+        let expires = Utc::now() + TimeDelta::minutes(1);
+        add_message(
+            &conn,
+            DEFAULT_PAIR,
+            "me",
+            "BRIEF".to_string(),
+            Some(expires),
+        )
+        .await
+        .unwrap();
+        assert_eq!(current_message(&conn, DEFAULT_PAIR).await.unwrap(), "BRIEF");
+
+        // The history keeps everything, including the pair's initial message
+        let (page, next) = query_messages(&conn, DEFAULT_PAIR, None, 3).await.unwrap();
+        let messages: Vec<&str> = page.iter().map(|m| m.message.as_str()).collect();
+        assert_eq!(messages, vec!["BRIEF", "GONE", "SOMETHING_ELSE"]);
+        assert_eq!(page[0].author, "me");
+        assert!(page[0].expires_at.is_some());
+        let (page, next) = query_messages(&conn, DEFAULT_PAIR, next, 3).await.unwrap();
+        let messages: Vec<&str> = page.iter().map(|m| m.message.as_str()).collect();
+        assert_eq!(messages, vec!["SOMETHING", "generic_message"]);
+        assert_eq!(next, None);
+
+        assert!(matches!(
+            current_message(&conn, "missing").await,
+            Err(AppError::PairNotFound)
+        ));
+
+        remove_file(db_path.clone()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expiry_events() {
+        let db_path = PathBuf::from("./expiry_events_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+        let config = Config::new(conn.clone());
+
+        let now = Utc::now();
+        let expires = now + TimeDelta::minutes(10);
+        let message = |message: &str, expires_at| Message {
+            message: message.to_string(),
+            expires_at: Some(expires_at),
+        };
+        post_message(&config, DEFAULT_PAIR, "me", message("BRIEF", expires))
+            .await
+            .unwrap();
+        let revision = pair_revision(&conn, DEFAULT_PAIR).await.unwrap();
+        let mut events = config.events.subscribe();
+
+        // Nothing happens until the message expires
+        let later = now + TimeDelta::minutes(5);
+        let next = publish_expired_messages(&config, now, later).await.unwrap();
+        assert_eq!(next, Some(expires));
+        assert_eq!(pair_revision(&conn, DEFAULT_PAIR).await.unwrap(), revision);
+        assert!(events.try_recv().is_err());
+
+        // Then the message before it is back
+        let expired = now + TimeDelta::minutes(11);
+        let next = publish_expired_messages(&config, later, expired)
+            .await
+            .unwrap();
+        assert_eq!(next, None);
+        let (_, event) = events.try_recv().unwrap();
+        assert_eq!(
+            event,
+            PairEvent::Message {
+                message: "generic_message".to_string()
+            }
+        );
+        assert_eq!(
+            pair_revision(&conn, DEFAULT_PAIR).await.unwrap(),
+            revision + 1
+        );
+
+        // Messages that were already replaced don't change what the pair shows
+        let shown_expires = now + TimeDelta::minutes(20);
+        post_message(&config, DEFAULT_PAIR, "me", message("HIDDEN", expires))
+            .await
+            .unwrap();
+        post_message(&config, DEFAULT_PAIR, "me", message("SHOWN", shown_expires))
+            .await
+            .unwrap();
+        let revision = pair_revision(&conn, DEFAULT_PAIR).await.unwrap();
+        let mut events = config.events.subscribe();
+        let next = publish_expired_messages(&config, later, expired)
+            .await
+            .unwrap();
+        assert_eq!(next, Some(shown_expires));
+        assert_eq!(pair_revision(&conn, DEFAULT_PAIR).await.unwrap(), revision);
+        assert!(events.try_recv().is_err());
+
+        remove_file(db_path.clone()).unwrap();
+    }
+}
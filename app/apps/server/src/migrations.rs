@@ -0,0 +1,185 @@
+use crate::error::{AppError, AppResult};
+use tokio_rusqlite::{params, Connection};
+
+/// Ordered forward migrations, a migration's version is its position in the list starting at 1.
+/// Migrations must never be edited or reordered once released, only appended.
+pub const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/0001_initial.sql"),
+    include_str!("../migrations/0002_pairs.sql"),
+    include_str!("../migrations/0003_timezone.sql"),
+    include_str!("../migrations/0004_ticks_index.sql"),
+    include_str!("../migrations/0005_tick_type_management.sql"),
+    include_str!("../migrations/0006_tick_deletion.sql"),
+    include_str!("../migrations/0007_revisions.sql"),
+    include_str!("../migrations/0008_messages.sql"),
+    include_str!("../migrations/0009_scheduled.sql"),
+    include_str!("../migrations/0010_quiet_hours.sql"),
+    include_str!("../migrations/0011_identities.sql"),
+    include_str!("../migrations/0012_key_revocation.sql"),
+    include_str!("../migrations/0013_scoped_revocation.sql"),
+];
+
+/// The version a fully migrated database is in
+pub const HEAD: u32 = MIGRATIONS.len() as u32;
+
+/// Brings the database up to [`HEAD`] and returns the version it was in before migrating,
+/// a database that has never been migrated is in version 0
+pub async fn migrate(connection: &Connection) -> AppResult<u32> {
+    connection
+        .call(|conn| {
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS schema_version (
+                    version INTEGER PRIMARY KEY,
+                    applied_at DATETIME DEFAULT CURRENT_TIMESTAMP
+                );",
+                (),
+            )?;
+
+            let current: u32 = conn.query_row(
+                "SELECT COALESCE(MAX(version), 0) FROM schema_version;",
+                [],
+                |r| r.get(0),
+            )?;
+
+            for (version, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+                // Each migration is applied along with its version or not at all
+                let tx = conn.transaction()?;
+                tx.execute_batch(migration)?;
+                tx.execute(
+                    "INSERT INTO schema_version (version) VALUES (?1);",
+                    params![version + 1],
+                )?;
+                tx.commit()?;
+            }
+
+            Ok(current)
+        })
+        .await
+        .map_err(AppError::from)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{initialize_db, DefaultPair};
+    use crate::identities::{identity_key, identity_sequence};
+    use crate::messages::current_message;
+    use crate::pair::DEFAULT_PAIR;
+    use crate::settings::{pair_timezone, Timezone};
+    use std::fs::remove_file;
+    use std::path::PathBuf;
+
+    async fn schema_version(connection: &Connection) -> u32 {
+        connection
+            .call(|conn| {
+                let res = conn.query_row(
+                    "SELECT COALESCE(MAX(version), 0) FROM schema_version;",
+                    [],
+                    |r| r.get(0),
+                )?;
+                Ok(res)
+            })
+            .await
+            .unwrap()
+    }
+
+    /// A database as created by the server before migrations existed
+    const V0_FIXTURE: &str = "
+        CREATE TABLE settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+        INSERT INTO settings (key, value) VALUES
+            ('active', 'false'),
+            ('message', 'legacy message'),
+            ('sequence', '42');
+
+        CREATE TABLE tick_types (
+            id INTEGER PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+        INSERT INTO tick_types (value) VALUES ('first'), ('second');
+
+        CREATE TABLE ticks (
+            id INTEGER PRIMARY KEY,
+            tick_type INTEGER NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY(tick_type) REFERENCES tick_types(id)
+        );
+        INSERT INTO ticks (tick_type) VALUES (1), (2), (2);
+    ";
+
+    #[tokio::test]
+    async fn migrate_empty_db() {
+        let db_path = PathBuf::from("./migrate_empty_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+
+        assert_eq!(migrate(&conn).await.unwrap(), 0);
+        assert_eq!(schema_version(&conn).await, HEAD);
+
+        // Migrating again is a no-op
+        assert_eq!(migrate(&conn).await.unwrap(), HEAD);
+        assert_eq!(schema_version(&conn).await, HEAD);
+
+        remove_file(db_path.clone()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn migrate_v0_fixture() {
+        let db_path = PathBuf::from("./migrate_v0_fixture_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        conn.call(|conn| Ok(conn.execute_batch(V0_FIXTURE)?))
+            .await
+            .unwrap();
+
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+        assert_eq!(schema_version(&conn).await, HEAD);
+
+        // Legacy data now belongs to the default pair, which gets its key from the options
+        assert_eq!(
+            identity_key(&conn, DEFAULT_PAIR, DEFAULT_PAIR)
+                .await
+                .unwrap(),
+            DefaultPair::test().pubkey
+        );
+        assert_eq!(
+            current_message(&conn, DEFAULT_PAIR).await.unwrap(),
+            "legacy message"
+        );
+        assert_eq!(
+            identity_sequence(&conn, DEFAULT_PAIR, DEFAULT_PAIR)
+                .await
+                .unwrap(),
+            42
+        );
+
+        assert_eq!(
+            pair_timezone(&conn, DEFAULT_PAIR).await.unwrap(),
+            Timezone {
+                timezone: "America/Puerto_Rico".to_string(),
+                day_start: 6
+            }
+        );
+
+        let (tick_types, ticks): (Vec<String>, Vec<u8>) = conn
+            .call(|conn| {
+                let tick_types = conn
+                    .prepare("SELECT value FROM tick_types WHERE pair = ?1 ORDER BY id")?
+                    .query_map(params![DEFAULT_PAIR], |r| r.get(0))?
+                    .map(|i| i.unwrap())
+                    .collect();
+                let ticks = conn
+                    .prepare("SELECT tick_type FROM ticks WHERE pair = ?1 ORDER BY id")?
+                    .query_map(params![DEFAULT_PAIR], |r| r.get(0))?
+                    .map(|i| i.unwrap())
+                    .collect();
+                Ok((tick_types, ticks))
+            })
+            .await
+            .unwrap();
+        assert_eq!(tick_types, vec!["first", "second"]);
+        assert_eq!(ticks, vec![1, 2, 2]);
+
+        remove_file(db_path.clone()).unwrap();
+    }
+}
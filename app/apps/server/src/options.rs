@@ -0,0 +1,288 @@
+//! Runtime options shared by the server and client binaries.
+//!
+//! Every option can be given as a CLI flag, an environment variable (a `.env` file is also read)
+//! or a key in an optional TOML file, in that order of precedence.
+
+use crate::config::DefaultPair;
+use clap::Args;
+use secp256k1::{PublicKey, SecretKey};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use url::Url;
+
+#[derive(Debug)]
+pub enum OptionsError {
+    /// The config file could not be read or parsed
+    File(PathBuf, String),
+    /// A required option was not set anywhere
+    Missing(&'static str),
+    /// An option was set but its value is not valid
+    Invalid(&'static str, String),
+}
+
+impl Display for OptionsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OptionsError::File(path, err) => {
+                write!(f, "could not load config file {}: {err}", path.display())
+            }
+            OptionsError::Missing(option) => write!(f, "missing required option `{option}`"),
+            OptionsError::Invalid(option, err) => write!(f, "invalid value for `{option}`: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for OptionsError {}
+
+/// Reads the TOML config file if one was given, a missing file is only an error when it was
+/// explicitly requested
+fn load_file<T: DeserializeOwned + Default>(path: Option<&Path>) -> Result<T, OptionsError> {
+    let Some(path) = path else {
+        return Ok(T::default());
+    };
+
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| OptionsError::File(path.to_path_buf(), e.to_string()))?;
+    toml::from_str(&content).map_err(|e| OptionsError::File(path.to_path_buf(), e.to_string()))
+}
+
+fn parse<T: FromStr>(option: &'static str, value: Option<String>) -> Result<T, OptionsError>
+where
+    T::Err: Display,
+{
+    let value = value.ok_or(OptionsError::Missing(option))?;
+    value
+        .parse()
+        .map_err(|e: T::Err| OptionsError::Invalid(option, e.to_string()))
+}
+
+#[derive(Args, Debug, Default)]
+pub struct ServerArgs {
+    /// TOML file with any of the options below
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+    /// SQLite database path
+    #[arg(long, env = "DB_PATH")]
+    pub db_path: Option<PathBuf>,
+    /// Address the server listens on
+    #[arg(long, env = "SERVER_URL")]
+    pub server_url: Option<String>,
+    /// Public key of the default pair
+    #[arg(long, env = "PUBLIC_KEY")]
+    pub public_key: Option<String>,
+    /// Comma separated tick types the default pair is created with
+    #[arg(long, env = "TICKS", value_delimiter = ',')]
+    pub ticks: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ServerFile {
+    db_path: Option<PathBuf>,
+    server_url: Option<String>,
+    public_key: Option<String>,
+    ticks: Option<Vec<String>>,
+}
+
+pub struct ServerOptions {
+    pub db_path: PathBuf,
+    pub server_url: SocketAddr,
+    pub default_pair: DefaultPair,
+}
+
+impl ServerArgs {
+    pub fn resolve(self) -> Result<ServerOptions, OptionsError> {
+        let file: ServerFile = load_file(self.config.as_deref())?;
+
+        let ticks = self.ticks.or(file.ticks).unwrap_or_default();
+        if ticks.iter().any(|tick| tick.trim().is_empty()) {
+            return Err(OptionsError::Invalid(
+                "ticks",
+                "tick names cannot be empty".to_string(),
+            ));
+        }
+
+        Ok(ServerOptions {
+            db_path: self
+                .db_path
+                .or(file.db_path)
+                .unwrap_or_else(|| PathBuf::from("./db.sqlite")),
+            server_url: parse(
+                "server_url",
+                self.server_url
+                    .or(file.server_url)
+                    .or(Some("0.0.0.0:3000".to_string())),
+            )?,
+            default_pair: DefaultPair {
+                pubkey: parse::<PublicKey>("public_key", self.public_key.or(file.public_key))?,
+                ticks,
+            },
+        })
+    }
+}
+
+#[derive(Args, Debug, Default)]
+pub struct ClientArgs {
+    /// TOML file with any of the options below
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+    /// Base url of the server
+    #[arg(long, env = "CLIENT_URL")]
+    pub client_url: Option<String>,
+    /// Key used to sign requests
+    #[arg(long, env = "SECRET_KEY", hide_env_values = true)]
+    pub secret_key: Option<String>,
+    /// Pair the client acts on
+    #[arg(long, env = "PAIR")]
+    pub pair: Option<String>,
+    /// Identity the secret key belongs to, the pair's own identity by default
+    #[arg(long, env = "IDENTITY")]
+    pub identity: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ClientFile {
+    client_url: Option<String>,
+    secret_key: Option<String>,
+    pair: Option<String>,
+    identity: Option<String>,
+}
+
+pub struct ClientOptions {
+    /// The pair's base url, every pair endpoint is relative to it
+    pub url: Url,
+    pub secret_key: SecretKey,
+    pub identity: Option<String>,
+}
+
+impl ClientArgs {
+    pub fn resolve(self) -> Result<ClientOptions, OptionsError> {
+        let file: ClientFile = load_file(self.config.as_deref())?;
+
+        let url: Url = parse("client_url", self.client_url.or(file.client_url))?;
+        let pair = self
+            .pair
+            .or(file.pair)
+            .unwrap_or_else(|| crate::pair::DEFAULT_PAIR.to_string());
+        let mut url = url
+            .join("/pair/")
+            .map_err(|e| OptionsError::Invalid("client_url", e.to_string()))?;
+        // A single segment so ids with `/`, `?` or `#` don't point at another endpoint
+        url.path_segments_mut()
+            .map_err(|_| OptionsError::Invalid("client_url", "not a base url".to_string()))?
+            .pop_if_empty()
+            .push(&pair)
+            .push("");
+
+        Ok(ClientOptions {
+            url,
+            secret_key: parse("secret_key", self.secret_key.or(file.secret_key))?,
+            identity: self.identity.or(file.identity),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    const PUBLIC_KEY: &str = "031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f";
+
+    #[test]
+    fn server_precedence() {
+        let path = PathBuf::from("./server_precedence.toml");
+        write(
+            &path,
+            format!(
+                "db_path = \"file.sqlite\"\nserver_url = \"127.0.0.1:4000\"\npublic_key = \"{PUBLIC_KEY}\"\n"
+            ),
+        )
+        .unwrap();
+
+        let options = ServerArgs {
+            config: Some(path.clone()),
+            db_path: Some(PathBuf::from("flag.sqlite")),
+            ticks: Some(vec!["first".to_string()]),
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap();
+
+        // Flags win over the file and the file over the defaults
+        assert_eq!(options.db_path, PathBuf::from("flag.sqlite"));
+        assert_eq!(options.server_url, "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(options.default_pair.pubkey.to_string(), PUBLIC_KEY);
+        assert_eq!(options.default_pair.ticks, vec!["first"]);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn server_validation() {
+        assert!(matches!(
+            ServerArgs::default().resolve(),
+            Err(OptionsError::Missing("public_key"))
+        ));
+        assert!(matches!(
+            ServerArgs {
+                public_key: Some("not a key".to_string()),
+                ..Default::default()
+            }
+            .resolve(),
+            Err(OptionsError::Invalid("public_key", _))
+        ));
+        assert!(matches!(
+            ServerArgs {
+                public_key: Some(PUBLIC_KEY.to_string()),
+                server_url: Some("nowhere".to_string()),
+                ..Default::default()
+            }
+            .resolve(),
+            Err(OptionsError::Invalid("server_url", _))
+        ));
+        assert!(matches!(
+            ServerArgs {
+                config: Some(PathBuf::from("./missing_config.toml")),
+                ..Default::default()
+            }
+            .resolve(),
+            Err(OptionsError::File(..))
+        ));
+    }
+
+    #[test]
+    fn client_pair_url() {
+        let options = ClientArgs {
+            client_url: Some("http://localhost:3000".to_string()),
+            secret_key: Some("01".repeat(32)),
+            pair: Some("other".to_string()),
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap();
+
+        assert_eq!(
+            options.url.join("message").unwrap().as_str(),
+            "http://localhost:3000/pair/other/message"
+        );
+
+        let options = ClientArgs {
+            client_url: Some("http://localhost:3000".to_string()),
+            secret_key: Some("01".repeat(32)),
+            pair: Some("a/b?c#d".to_string()),
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap();
+        assert_eq!(
+            options.url.join("message").unwrap().as_str(),
+            "http://localhost:3000/pair/a%2Fb%3Fc%23d/message"
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use crate::auth::{evaulate, Signed};
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::identities::validate_key;
+use crate::settings::{
+    ACTIVE_SETTING, DAY_START_SETTING, QUIET_END_SETTING, QUIET_START_SETTING, TIMEZONE_SETTING,
+};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::{Deserialize, Serialize};
+use tokio_rusqlite::{params, Connection};
+
+/// The pair created from the server's own environment, its key is also the one allowed to
+/// register new pairs
+pub const DEFAULT_PAIR: &str = "default";
+
+#[derive(Serialize, Deserialize)]
+pub struct RegisterPair {
+    pub id: String,
+    pub pubkey: String,
+    pub ticks: Vec<String>,
+}
+
+pub async fn register_pair(
+    State(config): State<Config>,
+    request: Signed<RegisterPair>,
+) -> AppResult<impl IntoResponse> {
+    if evaulate(&config, DEFAULT_PAIR, &request).await? != DEFAULT_PAIR {
+        return Err(AppError::Unauthorized);
+    }
+
+    let payload = request.payload;
+    validate_key(&config.db, &payload.id, &payload.pubkey).await?;
+
+    if pair_exists(&config.db, &payload.id).await? {
+        return Err(AppError::PairExists);
+    }
+
+    let id = payload.id;
+    create_pair(&config.db, id.clone(), payload.pubkey, payload.ticks).await?;
+
+    Ok((StatusCode::CREATED, id))
+}
+
+/// Registers a pair along with its own identity, default settings and tick types
+pub async fn create_pair(
+    connection: &Connection,
+    id: String,
+    pubkey: String,
+    ticks: Vec<String>,
+) -> AppResult<()> {
+    connection
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+
+            tx.execute("INSERT INTO pairs (id) VALUES (?1);", params![id])?;
+            tx.execute(
+                "INSERT INTO identities (pair, name, pubkey) VALUES (?1, ?1, ?2);",
+                params![id, pubkey],
+            )?;
+
+            {
+                let insert = "INSERT INTO settings (pair, key, value) VALUES (?1, ?2, ?3);";
+                let mut settings_insert = tx.prepare(insert)?;
+                settings_insert.execute(params![id, ACTIVE_SETTING, "true"])?;
+                settings_insert.execute(params![id, TIMEZONE_SETTING, "America/Puerto_Rico"])?;
+                settings_insert.execute(params![id, DAY_START_SETTING, "6"])?;
+                settings_insert.execute(params![id, QUIET_START_SETTING, ""])?;
+                settings_insert.execute(params![id, QUIET_END_SETTING, ""])?;
+
+                tx.execute(
+                    "INSERT INTO messages (pair, author, message) VALUES (?1, ?1, ?2);",
+                    params![id, "generic_message"],
+                )?;
+
+                // Tick ids are counted per pair so each one starts at 1
+                let insert = "INSERT INTO tick_types (pair, id, value, position) \
+                    VALUES (?1, ?2, ?3, ?2);";
+                let mut tick_types_insert = tx.prepare(insert)?;
+                for (id_offset, tick) in ticks.iter().enumerate() {
+                    tick_types_insert.execute(params![id, id_offset + 1, tick])?;
+                }
+            }
+
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+        .map_err(AppError::from)
+}
+
+pub async fn pair_exists(connection: &Connection, pair: &str) -> AppResult<bool> {
+    let pair = pair.to_string();
+    connection
+        .call(move |conn| {
+            let res = conn
+                .prepare("SELECT EXISTS(SELECT 1 FROM pairs WHERE id = ?1)")?
+                .query_row(params![pair], |r| r.get(0))?;
+            Ok(res)
+        })
+        .await
+        .map_err(AppError::from)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{initialize_db, DefaultPair};
+    use crate::identities::identity_key;
+    use crate::messages::{add_message, current_message};
+    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use std::fs::remove_file;
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn pairs_are_isolated() {
+        let db_path = PathBuf::from("./pairs_are_isolated_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+
+        let secret_key = SecretKey::from_byte_array(&[1; 32]).unwrap();
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+        create_pair(
+            &conn,
+            "other".to_string(),
+            public_key.to_string(),
+            vec!["first".to_string(), "second".to_string()],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            identity_key(&conn, "other", "other").await.unwrap(),
+            public_key
+        );
+        assert!(matches!(
+            identity_key(&conn, "missing", "missing").await,
+            Err(AppError::PairNotFound)
+        ));
+
+        add_message(&conn, "other", "other", "other".to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            current_message(&conn, DEFAULT_PAIR).await.unwrap(),
+            "generic_message"
+        );
+        assert_eq!(current_message(&conn, "other").await.unwrap(), "other");
+
+        let ticks: Vec<(u8, String)> = conn
+            .call(|conn| {
+                let res = conn
+                    .prepare("SELECT id, value FROM tick_types WHERE pair = 'other'")?
+                    .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+                    .map(|i| i.unwrap())
+                    .collect();
+                Ok(res)
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            ticks,
+            vec![(1, "first".to_string()), (2, "second".to_string())]
+        );
+
+        remove_file(db_path.clone()).unwrap();
+    }
+}
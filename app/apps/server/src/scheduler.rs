@@ -0,0 +1,531 @@
+use crate::auth::{evaulate, Signed};
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::messages::{post_message, publish_expired_messages, Message};
+use crate::pair::pair_exists;
+use crate::settings::{pair_timezone, publish_quiet_hours};
+use crate::tick::{add_tick, TriggerTick};
+use crate::tick_types::query_tick_types;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::{DateTime, NaiveDateTime, SubsecRound, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use tokio_rusqlite::{params, Connection, OptionalExtension};
+
+/// Longest the scheduler sleeps for without checking for due actions
+pub const SCHEDULER_INTERVAL: u64 = 60;
+
+/// A signed request that can be applied later
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ScheduledAction {
+    Message(Message),
+    Tick(TriggerTick),
+}
+
+/// Actions are stored as JSON
+impl FromSql for ScheduledAction {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        serde_json::from_str(value.as_str()?).map_err(|err| FromSqlError::Other(Box::new(err)))
+    }
+}
+
+/// Schedules an action, its due time is either an instant or a time in the pair's timezone
+#[derive(Serialize, Deserialize)]
+pub struct Schedule {
+    pub due_at: Option<DateTime<Utc>>,
+    /// ex: 2024-11-04T07:00:00
+    pub local_due_at: Option<NaiveDateTime>,
+    pub action: ScheduledAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Scheduled {
+    pub id: u64,
+    pub author: String,
+    pub action: ScheduledAction,
+    pub due_at: String,
+    pub applied_at: Option<String>,
+    /// Why the action could not be applied
+    pub error: Option<String>,
+}
+
+pub async fn schedule(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    request: Signed<Schedule>,
+) -> AppResult<impl IntoResponse> {
+    let author = evaulate(&config, &pair, &request).await?;
+
+    let Schedule {
+        due_at,
+        local_due_at,
+        action,
+    } = request.payload;
+    let due_at = match (due_at, local_due_at) {
+        (Some(due_at), None) => due_at,
+        (None, Some(local_due_at)) => {
+            let tz = pair_timezone(&config.db, &pair)
+                .await?
+                .tz()
+                .map_err(AppError::Internal)?;
+            tz.from_local_datetime(&local_due_at)
+                .earliest()
+                .ok_or_else(|| {
+                    AppError::InvalidBody(format!("{local_due_at} does not exist in {tz}"))
+                })?
+                .with_timezone(&Utc)
+        }
+        _ => {
+            return Err(AppError::InvalidBody(
+                "exactly one of `due_at` or `local_due_at` must be given".to_string(),
+            ))
+        }
+    };
+    if due_at <= Utc::now() {
+        return Err(AppError::InvalidBody("due time is in the past".to_string()));
+    }
+
+    // Catch what can already be known to fail instead of waiting for the due time
+    match &action {
+        ScheduledAction::Message(message) => {
+            if message
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= due_at)
+            {
+                return Err(AppError::InvalidBody(
+                    "message would already be expired".to_string(),
+                ));
+            }
+        }
+        ScheduledAction::Tick(tick) => {
+            let tick_types = query_tick_types(&config.db, &pair, true).await?;
+            match tick_types.iter().find(|tick_type| tick_type.id == tick.ty) {
+                None => return Err(AppError::TickTypeNotFound),
+                Some(tick_type) if tick_type.archived => return Err(AppError::TickTypeArchived),
+                Some(_) => {}
+            }
+        }
+    }
+
+    let scheduled = add_scheduled(&config.db, &pair, &author, action, due_at).await?;
+    config.scheduler.notify_one();
+
+    Ok((StatusCode::CREATED, Json(scheduled)))
+}
+
+#[derive(Deserialize, Default)]
+pub struct ScheduledQuery {
+    /// Also return the actions that were already applied
+    #[serde(default)]
+    pub applied: bool,
+}
+
+/// The pair's scheduled actions in the order they're due
+pub async fn get_scheduled(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    query: Result<Query<ScheduledQuery>, QueryRejection>,
+) -> AppResult<Json<Vec<Scheduled>>> {
+    let Query(query) = query.map_err(|e| AppError::InvalidQuery(e.body_text()))?;
+    if !pair_exists(&config.db, &pair).await? {
+        return Err(AppError::PairNotFound);
+    }
+
+    Ok(Json(
+        query_scheduled(&config.db, &pair, query.applied).await?,
+    ))
+}
+
+/// Cancels an action that hasn't been applied yet
+#[derive(Serialize, Deserialize)]
+pub struct CancelScheduled {
+    pub id: u64,
+}
+
+pub async fn cancel_scheduled(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    request: Signed<CancelScheduled>,
+) -> AppResult<Json<Scheduled>> {
+    let author = evaulate(&config, &pair, &request).await?;
+
+    let id = request.payload.id;
+    let canceled = config
+        .db
+        .call(move |conn| {
+            // Identities can only cancel the actions they scheduled
+            let res = conn
+                .query_row(
+                    "DELETE FROM scheduled \
+                    WHERE pair = ?1 AND id = ?2 AND author = ?3 AND applied_at IS NULL \
+                    RETURNING id, author, action, due_at, applied_at, error",
+                    params![pair, id, author],
+                    |r| {
+                        Ok(Scheduled {
+                            id: r.get(0)?,
+                            author: r.get(1)?,
+                            action: r.get(2)?,
+                            due_at: r.get(3)?,
+                            applied_at: r.get(4)?,
+                            error: r.get(5)?,
+                        })
+                    },
+                )
+                .optional()?;
+            if let Some(canceled) = res {
+                return Ok(Ok(canceled));
+            }
+            let other = conn
+                .query_row(
+                    "SELECT 1 FROM scheduled WHERE pair = ?1 AND id = ?2 AND applied_at IS NULL",
+                    params![pair, id],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            match other {
+                Some(()) => Ok(Err(AppError::OtherIdentity)),
+                None => Ok(Err(AppError::ScheduledNotFound)),
+            }
+        })
+        .await??;
+
+    Ok(Json(canceled))
+}
+
+pub async fn add_scheduled(
+    connection: &Connection,
+    pair: &str,
+    author: &str,
+    action: ScheduledAction,
+    due_at: DateTime<Utc>,
+) -> AppResult<Scheduled> {
+    let pair = pair.to_string();
+    let author = author.to_string();
+    let action =
+        serde_json::to_string(&action).map_err(|err| AppError::Internal(err.to_string()))?;
+    let due_at = due_at.trunc_subsecs(0).naive_utc();
+    connection
+        .call(move |conn| {
+            let res = conn.query_row(
+                "INSERT INTO scheduled (pair, author, action, due_at) VALUES (?1, ?2, ?3, ?4) \
+                RETURNING id, author, action, due_at, applied_at, error",
+                params![pair, author, action, due_at],
+                |r| {
+                    Ok(Scheduled {
+                        id: r.get(0)?,
+                        author: r.get(1)?,
+                        action: r.get(2)?,
+                        due_at: r.get(3)?,
+                        applied_at: r.get(4)?,
+                        error: r.get(5)?,
+                    })
+                },
+            )?;
+            Ok(res)
+        })
+        .await
+        .map_err(AppError::from)
+}
+
+pub async fn query_scheduled(
+    connection: &Connection,
+    pair: &str,
+    applied: bool,
+) -> AppResult<Vec<Scheduled>> {
+    let pair = pair.to_string();
+    connection
+        .call(move |conn| {
+            let res = conn
+                .prepare(
+                    "\
+                SELECT id, author, action, due_at, applied_at, error \
+                FROM scheduled \
+                WHERE pair = ?1 AND (?2 OR applied_at IS NULL) \
+                ORDER BY due_at, id",
+                )?
+                .query_map(params![pair, applied], |r| {
+                    Ok(Scheduled {
+                        id: r.get(0)?,
+                        author: r.get(1)?,
+                        action: r.get(2)?,
+                        due_at: r.get(3)?,
+                        applied_at: r.get(4)?,
+                        error: r.get(5)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(res)
+        })
+        .await
+        .map_err(AppError::from)
+}
+
+/// Applies every action due by `now` through the same code as their endpoints, returns how many
+/// were applied. Failures are stored along with the action
+pub async fn apply_due(config: &Config, now: DateTime<Utc>) -> AppResult<usize> {
+    let now = now.trunc_subsecs(0).naive_utc();
+    let mut applied = 0;
+    loop {
+        // Actions are marked as applied before running them so a crash never applies one twice
+        let next = config
+            .db
+            .call(move |conn| {
+                let res = conn
+                    .query_row(
+                        "UPDATE scheduled SET applied_at = ?1 \
+                        WHERE id = ( \
+                            SELECT id FROM scheduled \
+                            WHERE applied_at IS NULL AND due_at <= ?1 \
+                            ORDER BY due_at, id \
+                            LIMIT 1 \
+                        ) \
+                        RETURNING id, author, action, due_at, applied_at, error, pair",
+                        params![now],
+                        |r| {
+                            let scheduled = Scheduled {
+                                id: r.get(0)?,
+                                author: r.get(1)?,
+                                action: r.get(2)?,
+                                due_at: r.get(3)?,
+                                applied_at: r.get(4)?,
+                                error: r.get(5)?,
+                            };
+                            Ok((r.get::<_, String>(6)?, scheduled))
+                        },
+                    )
+                    .optional()?;
+                Ok(res)
+            })
+            .await?;
+        let Some((pair, scheduled)) = next else {
+            return Ok(applied);
+        };
+
+        let result = match scheduled.action {
+            ScheduledAction::Message(message) => {
+                post_message(config, &pair, &scheduled.author, message)
+                    .await
+                    .map(|_| ())
+            }
+            ScheduledAction::Tick(tick) => add_tick(config, &pair, &scheduled.author, tick.ty)
+                .await
+                .map(|_| ()),
+        };
+        if let Err(err) = result {
+            let error = err.to_string();
+            let id = scheduled.id;
+            config
+                .db
+                .call(move |conn| {
+                    conn.execute(
+                        "UPDATE scheduled SET error = ?1 WHERE id = ?2",
+                        params![error, id],
+                    )?;
+                    Ok(())
+                })
+                .await?;
+        }
+        applied += 1;
+    }
+}
+
+/// The earliest due time of the actions still waiting
+pub async fn next_due(connection: &Connection) -> AppResult<Option<DateTime<Utc>>> {
+    let due_at: Option<NaiveDateTime> = connection
+        .call(|conn| {
+            let res = conn.query_row(
+                "SELECT MIN(due_at) FROM scheduled WHERE applied_at IS NULL",
+                [],
+                |r| r.get(0),
+            )?;
+            Ok(res)
+        })
+        .await?;
+    Ok(due_at.map(|due_at| due_at.and_utc()))
+}
+
+/// Applies scheduled actions as they come due, including the ones missed while the server was
+/// down, and publishes pairs pausing or resuming with their quiet hours and messages expiring
+pub async fn run_scheduler(config: Config) {
+    let interval = Duration::from_secs(SCHEDULER_INTERVAL);
+    let mut checked = Utc::now();
+    loop {
+        let now = Utc::now();
+        let next_quiet = match publish_quiet_hours(&config, checked, now).await {
+            Ok(next_quiet) => next_quiet,
+            Err(err) => {
+                tracing::error!("could not publish quiet hours: {err}");
+                None
+            }
+        };
+        let next_expiry = match publish_expired_messages(&config, checked, now).await {
+            Ok(next_expiry) => next_expiry,
+            Err(err) => {
+                tracing::error!("could not publish expired messages: {err}");
+                None
+            }
+        };
+        checked = now;
+
+        let wait = match apply_due(&config, Utc::now()).await {
+            Ok(_) => match next_due(&config.db).await {
+                Ok(Some(due_at)) => (due_at - Utc::now())
+                    .to_std()
+                    .unwrap_or_default()
+                    .min(interval),
+                _ => interval,
+            },
+            Err(err) => {
+                tracing::error!("could not apply scheduled actions: {err}");
+                interval
+            }
+        };
+        let wait = match next_quiet.into_iter().chain(next_expiry).min() {
+            Some(boundary) => wait.min((boundary - Utc::now()).to_std().unwrap_or_default()),
+            None => wait,
+        };
+        let _ = timeout(wait, config.scheduler.notified()).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{initialize_db, DefaultPair};
+    use crate::messages::current_message;
+    use crate::pair::DEFAULT_PAIR;
+    use crate::test_util::{signed_post, signed_post_as};
+    use chrono::{Days, TimeDelta};
+    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use std::fs::remove_file;
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn scheduled_actions() {
+        let db_path = PathBuf::from("./scheduled_actions_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+        let config = Config::new(conn.clone());
+
+        let now = Utc::now();
+        let due_at = (now + TimeDelta::minutes(5)).to_rfc3339();
+        let message = format!(
+            r#"{{"due_at":"{due_at}","action":{{"type":"message","message":"good morning"}}}}"#
+        );
+        let (status, _) = signed_post(&config, "/pair/default/scheduled", &message).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let tick = format!(r#"{{"due_at":"{due_at}","action":{{"type":"tick","ty":1}}}}"#);
+        let (status, _) = signed_post(&config, "/pair/default/scheduled", &tick).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let archived = format!(r#"{{"due_at":"{due_at}","action":{{"type":"tick","ty":2}}}}"#);
+        let (status, _) = signed_post(&config, "/pair/default/scheduled", &archived).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        // Invalid actions are refused right away
+        let missing = format!(r#"{{"due_at":"{due_at}","action":{{"type":"tick","ty":100}}}}"#);
+        let (status, _) = signed_post(&config, "/pair/default/scheduled", &missing).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let past = (now - TimeDelta::minutes(5)).to_rfc3339();
+        let past = format!(r#"{{"due_at":"{past}","action":{{"type":"tick","ty":1}}}}"#);
+        let (status, _) = signed_post(&config, "/pair/default/scheduled", &past).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let both = format!(
+            r#"{{"due_at":"{due_at}","local_due_at":"2024-11-04T07:00:00","action":{{"type":"tick","ty":1}}}}"#
+        );
+        let (status, _) = signed_post(&config, "/pair/default/scheduled", &both).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Local times are in the pair's timezone, Puerto Rico is always 4 hours behind UTC
+        let tomorrow = now.date_naive().checked_add_days(Days::new(2)).unwrap();
+        let local = format!(
+            r#"{{"local_due_at":"{tomorrow}T07:00:00","action":{{"type":"tick","ty":1}}}}"#
+        );
+        let (status, body) = signed_post(&config, "/pair/default/scheduled", &local).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let local: Scheduled = serde_json::from_slice(&body).unwrap();
+        assert_eq!(local.due_at, format!("{tomorrow} 11:00:00"));
+
+        // Only the identity that scheduled an action can cancel it
+        let partner = SecretKey::from_byte_array(&[2; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &partner);
+        let body = format!(r#"{{"name":"partner","pubkey":"{pubkey}"}}"#);
+        signed_post(&config, "/pair/default/identities", &body).await;
+        let cancel = format!(r#"{{"id":{}}}"#, local.id);
+        let (status, body) = signed_post_as(
+            &config,
+            "/pair/default/scheduled/cancel",
+            &cancel,
+            "partner",
+            &partner,
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(String::from_utf8_lossy(&body).contains("other_identity"));
+
+        // Canceled actions are gone
+        let (status, _) = signed_post(&config, "/pair/default/scheduled/cancel", &cancel).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = signed_post(&config, "/pair/default/scheduled/cancel", &cancel).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Nothing happens before the due time
+        assert_eq!(apply_due(&config, now).await.unwrap(), 0);
+        assert_eq!(
+            next_due(&conn).await.unwrap(),
+            Some(
+                DateTime::parse_from_rfc3339(&due_at)
+                    .unwrap()
+                    .trunc_subsecs(0)
+                    .to_utc()
+            )
+        );
+
+        // Things can change between scheduling and applying
+        let (status, _) = signed_post(
+            &config,
+            "/pair/default/tick_types/2",
+            r#"{"archived":true}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let mut events = config.events.subscribe();
+        assert_eq!(
+            apply_due(&config, now + TimeDelta::minutes(10))
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            current_message(&conn, DEFAULT_PAIR).await.unwrap(),
+            "good morning"
+        );
+        let (_, event) = events.recv().await.unwrap();
+        assert!(matches!(event, crate::events::PairEvent::Message { .. }));
+        let (_, event) = events.recv().await.unwrap();
+        assert!(matches!(event, crate::events::PairEvent::Tick { .. }));
+
+        assert!(query_scheduled(&conn, DEFAULT_PAIR, false)
+            .await
+            .unwrap()
+            .is_empty());
+        let applied = query_scheduled(&conn, DEFAULT_PAIR, true).await.unwrap();
+        assert_eq!(applied.len(), 3);
+        assert!(applied
+            .iter()
+            .all(|scheduled| scheduled.applied_at.is_some()));
+        assert_eq!(applied[0].error, None);
+        assert_eq!(applied[1].error, None);
+        assert_eq!(applied[2].error.as_deref(), Some("tick type is archived"));
+        assert_eq!(next_due(&conn).await.unwrap(), None);
+
+        remove_file(db_path.clone()).unwrap();
+    }
+}
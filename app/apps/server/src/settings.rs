@@ -1,116 +1,344 @@
-use crate::auth::evaulate;
+use crate::auth::{evaulate, Signed};
 use crate::config::Config;
-use axum::extract::State;
-use axum::http::{HeaderMap, StatusCode};
+use crate::error::{AppError, AppResult};
+use crate::events::{publish, PairEvent};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::Json;
+use chrono::{DateTime, Days, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
-use tokio_rusqlite::{params, Connection};
+use tokio_rusqlite::{params, Connection, OptionalExtension};
 
-pub const ACTIVE_SETTING: &'static str = "active";
-pub const MESSAGE_SETTING: &'static str = "message";
-pub const SEQUENCE_SETTING: &'static str = "sequence";
+pub const ACTIVE_SETTING: &str = "active";
+pub const TIMEZONE_SETTING: &str = "timezone";
+pub const DAY_START_SETTING: &str = "day_start";
+pub const QUIET_START_SETTING: &str = "quiet_start";
+pub const QUIET_END_SETTING: &str = "quiet_end";
 
 #[derive(Serialize, Deserialize)]
-pub struct Message {
-    pub message: String,
+pub struct Active {
+    pub active: bool,
+}
+
+pub async fn set_active(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    request: Signed<Active>,
+) -> AppResult<impl IntoResponse> {
+    evaulate(&config, &pair, &request).await?;
+
+    let active = request.payload.active;
+    set_setting(&config.db, &pair, ACTIVE_SETTING, active.to_string()).await?;
+
+    // Quiet hours can keep the pair paused even after activating it
+    let active = pair_active(&config.db, &pair).await?;
+    publish(&config, &pair, PairEvent::Active { active });
+
+    Ok((StatusCode::CREATED, active.to_string()))
 }
 
-pub async fn set_message(
+/// Returns whether the pair is active right now, taking its quiet hours into account
+pub async fn get_active(
     State(config): State<Config>,
-    header_map: HeaderMap,
-    Json(payload): Json<Message>,
-) -> impl IntoResponse {
-    let val = header_map.get("auth").unwrap();
+    Path(pair): Path<String>,
+) -> AppResult<String> {
+    Ok(pair_active(&config.db, &pair).await?.to_string())
+}
+
+/// A pair is paused when its active flag is off or during its quiet hours
+pub async fn pair_active(connection: &Connection, pair: &str) -> AppResult<bool> {
+    pair_active_at(connection, pair, Utc::now()).await
+}
 
-    if let Some(res) = evaulate(&config, val, &payload).await {
-        return (res, "".to_string());
+pub async fn pair_active_at(
+    connection: &Connection,
+    pair: &str,
+    now: DateTime<Utc>,
+) -> AppResult<bool> {
+    if query_setting(connection, pair, ACTIVE_SETTING).await? != "true" {
+        return Ok(false);
     }
 
-    let message = payload.message;
-    set_setting(&config.db, MESSAGE_SETTING, message.clone()).await;
+    let quiet_hours = pair_quiet_hours(connection, pair).await?;
+    let tz = pair_timezone(connection, pair)
+        .await?
+        .tz()
+        .map_err(AppError::Internal)?;
+    Ok(!quiet_hours.contains(now.with_timezone(&tz).time()))
+}
 
-    (StatusCode::CREATED, message)
+/// Daily time range in the pair's timezone during which it's paused, it may go past midnight
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct QuietHours {
+    pub start: Option<NaiveTime>,
+    pub end: Option<NaiveTime>,
 }
 
-pub async fn get_message(State(config): State<Config>) -> impl IntoResponse {
-    query_setting(&config.db, MESSAGE_SETTING).await
+impl QuietHours {
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.start, self.end) {
+            (Some(start), Some(end)) if start == end => {
+                Err("quiet hours must not start and end at the same time".to_string())
+            }
+            (Some(_), Some(_)) | (None, None) => Ok(()),
+            _ => Err("quiet hours need both a `start` and an `end`".to_string()),
+        }
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        match (self.start, self.end) {
+            (Some(start), Some(end)) if start < end => start <= time && time < end,
+            (Some(start), Some(end)) => start <= time || time < end,
+            _ => false,
+        }
+    }
+
+    /// The first time after `now` at which the quiet hours start or end
+    pub fn next_boundary(&self, now: DateTime<Tz>) -> Option<DateTime<Utc>> {
+        let tz = now.timezone();
+        let today = now.date_naive();
+        [self.start?, self.end?]
+            .into_iter()
+            .flat_map(|time| {
+                [today, today + Days::new(1), today + Days::new(2)]
+                    .into_iter()
+                    // Times skipped by a DST change happen the next day instead
+                    .filter_map(move |day| tz.from_local_datetime(&day.and_time(time)).earliest())
+                    .find(|boundary| *boundary > now)
+            })
+            .min()
+            .map(|boundary| boundary.to_utc())
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Active {
-    pub active: bool,
+/// Bumps the revision of the pairs whose quiet hours started or ended in `(since, now]` and
+/// publishes their new state, returns the next time a pair's quiet hours start or end
+pub async fn publish_quiet_hours(
+    config: &Config,
+    since: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> AppResult<Option<DateTime<Utc>>> {
+    let pairs: Vec<String> = config
+        .db
+        .call(move |conn| {
+            let res = conn
+                .prepare("SELECT pair FROM settings WHERE key = ?1 AND value <> ''")?
+                .query_map(params![QUIET_START_SETTING], |r| r.get(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(res)
+        })
+        .await?;
+
+    let mut next = None;
+    for pair in pairs {
+        let quiet_hours = pair_quiet_hours(&config.db, &pair).await?;
+        let tz = pair_timezone(&config.db, &pair)
+            .await?
+            .tz()
+            .map_err(AppError::Internal)?;
+        if quiet_hours
+            .next_boundary(since.with_timezone(&tz))
+            .is_some_and(|boundary| boundary <= now)
+        {
+            let bumped = pair.clone();
+            config
+                .db
+                .call(move |conn| {
+                    conn.execute(
+                        "UPDATE pairs SET revision = revision + 1 WHERE id = ?1",
+                        params![bumped],
+                    )?;
+                    Ok(())
+                })
+                .await?;
+            let active = pair_active_at(&config.db, &pair, now).await?;
+            publish(config, &pair, PairEvent::Active { active });
+        }
+        if let Some(boundary) = quiet_hours.next_boundary(now.with_timezone(&tz)) {
+            next = Some(next.map_or(boundary, |next: DateTime<Utc>| next.min(boundary)));
+        }
+    }
+    Ok(next)
 }
 
-pub async fn set_active(
+pub async fn set_quiet_hours(
     State(config): State<Config>,
-    header_map: HeaderMap,
-    Json(payload): Json<Active>,
-) -> impl IntoResponse {
-    let val = header_map.get("auth").unwrap();
+    Path(pair): Path<String>,
+    request: Signed<QuietHours>,
+) -> AppResult<impl IntoResponse> {
+    evaulate(&config, &pair, &request).await?;
 
-    if let Some(res) = evaulate(&config, val, &payload).await {
-        return (res, "".to_string());
-    }
+    let quiet_hours = request.payload;
+    quiet_hours.validate().map_err(AppError::InvalidBody)?;
+
+    let format = |time: Option<NaiveTime>| time.map(|time| time.to_string()).unwrap_or_default();
+    set_settings(
+        &config.db,
+        &pair,
+        vec![
+            (QUIET_START_SETTING, format(quiet_hours.start)),
+            (QUIET_END_SETTING, format(quiet_hours.end)),
+        ],
+    )
+    .await?;
+    // The scheduler publishes the next time the pair is paused or resumed
+    config.scheduler.notify_one();
+
+    let active = pair_active(&config.db, &pair).await?;
+    publish(&config, &pair, PairEvent::Active { active });
+
+    Ok((StatusCode::CREATED, Json(quiet_hours)))
+}
+
+pub async fn get_quiet_hours(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+) -> AppResult<Json<QuietHours>> {
+    Ok(Json(pair_quiet_hours(&config.db, &pair).await?))
+}
 
-    let active = payload.active.to_string();
-    set_setting(&config.db, ACTIVE_SETTING, active.clone()).await;
+pub async fn pair_quiet_hours(connection: &Connection, pair: &str) -> AppResult<QuietHours> {
+    let parse = |time: String| {
+        if time.is_empty() {
+            return Ok(None);
+        }
+        time.parse()
+            .map(Some)
+            .map_err(|_| AppError::Internal(format!("stored quiet hour `{time}` is not a time")))
+    };
+    Ok(QuietHours {
+        start: parse(query_setting(connection, pair, QUIET_START_SETTING).await?)?,
+        end: parse(query_setting(connection, pair, QUIET_END_SETTING).await?)?,
+    })
+}
 
-    (StatusCode::CREATED, active)
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Timezone {
+    /// IANA timezone name, ex: America/Puerto_Rico
+    pub timezone: String,
+    /// Hour of the day at which the tick history rolls over to a new day
+    pub day_start: u32,
 }
 
-pub async fn get_active(State(config): State<Config>) -> impl IntoResponse {
-    query_setting(&config.db, ACTIVE_SETTING).await
+impl Timezone {
+    pub fn tz(&self) -> Result<Tz, String> {
+        if self.day_start > 23 {
+            return Err(format!(
+                "day start hour {} is not in 0..=23",
+                self.day_start
+            ));
+        }
+        self.timezone
+            .parse()
+            .map_err(|_| format!("unknown timezone `{}`", self.timezone))
+    }
 }
 
-pub async fn get_sequence(State(config): State<Config>) -> impl IntoResponse {
-    query_setting(&config.db, SEQUENCE_SETTING).await
+pub async fn set_timezone(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    request: Signed<Timezone>,
+) -> AppResult<impl IntoResponse> {
+    evaulate(&config, &pair, &request).await?;
+
+    let timezone = request.payload;
+    timezone.tz().map_err(AppError::InvalidBody)?;
+
+    set_settings(
+        &config.db,
+        &pair,
+        vec![
+            (TIMEZONE_SETTING, timezone.timezone.clone()),
+            (DAY_START_SETTING, timezone.day_start.to_string()),
+        ],
+    )
+    .await?;
+    config.scheduler.notify_one();
+
+    Ok((StatusCode::CREATED, Json(timezone)))
 }
 
-pub async fn sequence(conn: &Connection) -> u64 {
-    query_setting(conn, SEQUENCE_SETTING)
-        .await
-        .parse::<u64>()
-        .unwrap()
+pub async fn get_timezone(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+) -> AppResult<Json<Timezone>> {
+    Ok(Json(pair_timezone(&config.db, &pair).await?))
 }
 
-pub async fn save_sequence(conn: &Connection, sequence: u64) {
-    set_setting(conn, SEQUENCE_SETTING, sequence.to_string()).await;
+pub async fn pair_timezone(connection: &Connection, pair: &str) -> AppResult<Timezone> {
+    let timezone = query_setting(connection, pair, TIMEZONE_SETTING).await?;
+    let day_start = query_setting(connection, pair, DAY_START_SETTING).await?;
+    Ok(Timezone {
+        timezone,
+        day_start: day_start.parse().map_err(|_| {
+            AppError::Internal(format!("stored day start `{day_start}` is not a number"))
+        })?,
+    })
 }
 
-async fn query_setting(connection: &Connection, key: &str) -> String {
+/// Missing settings are reported as a missing pair since every pair has all of them
+pub async fn query_setting(connection: &Connection, pair: &str, key: &str) -> AppResult<String> {
+    let pair = pair.to_string();
     let key = key.to_string();
     connection
         .call(move |conn| {
             let res = conn
-                .prepare("SELECT value FROM settings WHERE key = ?1")
-                .unwrap()
-                .query_row(params![key], |r| r.get(0))?;
+                .prepare("SELECT value FROM settings WHERE pair = ?1 AND key = ?2")?
+                .query_row(params![pair, key], |r| r.get(0))
+                .optional()?;
             Ok(res)
         })
-        .await
-        .unwrap()
+        .await?
+        .ok_or(AppError::PairNotFound)
 }
 
-async fn set_setting(connection: &Connection, key: &str, value: String) {
-    let key = key.to_string();
+pub async fn set_setting(
+    connection: &Connection,
+    pair: &str,
+    key: &'static str,
+    value: String,
+) -> AppResult<()> {
+    set_settings(connection, pair, vec![(key, value)]).await
+}
+
+/// Sets all the settings or none of them
+pub async fn set_settings(
+    connection: &Connection,
+    pair: &str,
+    settings: Vec<(&'static str, String)>,
+) -> AppResult<()> {
+    let pair = pair.to_string();
     connection
         .call(move |conn| {
-            conn.execute(
-                "UPDATE settings SET value = ?1 WHERE key = ?2",
-                params![value, key],
-            )
-            .unwrap();
-            Ok(())
+            let tx = conn.transaction()?;
+            for (key, value) in settings {
+                let updated = tx.execute(
+                    "UPDATE settings SET value = ?1 WHERE pair = ?2 AND key = ?3",
+                    params![value, pair, key],
+                )?;
+                if updated == 0 {
+                    return Ok(Err(AppError::PairNotFound));
+                }
+            }
+            tx.commit()?;
+            Ok(Ok(()))
         })
-        .await
-        .unwrap();
+        .await?
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::config::initialize_db;
+    use crate::changes::pair_revision;
+    use crate::config::{initialize_db, DefaultPair};
+    use crate::pair::DEFAULT_PAIR;
+    use crate::test_util::{send, signed_post};
+    use axum::body::Body;
+    use axum::http::Request;
+    use chrono::TimeDelta;
+    use companion_proto::{decode_tick_history, decode_tick_stats};
     use std::fs::remove_file;
     use std::path::PathBuf;
 
@@ -118,46 +346,186 @@ mod test {
     async fn active_setting() {
         let db_path = PathBuf::from("./active_setting_db");
         let conn = Connection::open(db_path.clone()).await.unwrap();
-        initialize_db(&conn).await;
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
 
-        set_setting(&conn, ACTIVE_SETTING, false.to_string()).await;
-        assert_eq!(query_setting(&conn, ACTIVE_SETTING).await, "false");
+        set_setting(&conn, DEFAULT_PAIR, ACTIVE_SETTING, false.to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            query_setting(&conn, DEFAULT_PAIR, ACTIVE_SETTING)
+                .await
+                .unwrap(),
+            "false"
+        );
 
-        set_setting(&conn, ACTIVE_SETTING, true.to_string()).await;
-        assert_eq!(query_setting(&conn, ACTIVE_SETTING).await, "true");
+        set_setting(&conn, DEFAULT_PAIR, ACTIVE_SETTING, true.to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            query_setting(&conn, DEFAULT_PAIR, ACTIVE_SETTING)
+                .await
+                .unwrap(),
+            "true"
+        );
 
         remove_file(db_path.clone()).unwrap();
     }
 
+    #[test]
+    fn quiet_hours_range() {
+        let time = |hour| NaiveTime::from_hms_opt(hour, 0, 0).unwrap();
+        let day = QuietHours {
+            start: Some(time(9)),
+            end: Some(time(17)),
+        };
+        assert!(day.contains(time(9)));
+        assert!(!day.contains(time(17)));
+        assert!(!day.contains(time(20)));
+
+        // Nights go past midnight
+        let night = QuietHours {
+            start: Some(time(22)),
+            end: Some(time(7)),
+        };
+        assert!(night.contains(time(23)));
+        assert!(night.contains(time(0)));
+        assert!(!night.contains(time(7)));
+        assert!(!night.contains(time(12)));
+
+        assert!(!QuietHours::default().contains(time(12)));
+        assert!(QuietHours::default().validate().is_ok());
+        let half = QuietHours {
+            start: Some(time(22)),
+            end: None,
+        };
+        assert!(half.validate().is_err());
+    }
+
+    #[test]
+    fn quiet_hours_boundaries() {
+        let time = |hour| NaiveTime::from_hms_opt(hour, 0, 0).unwrap();
+        let night = QuietHours {
+            start: Some(time(22)),
+            end: Some(time(7)),
+        };
+        // Puerto Rico is always 4 hours behind UTC
+        let tz: Tz = "America/Puerto_Rico".parse().unwrap();
+        let local = |day, hour| tz.with_ymd_and_hms(2024, 11, day, hour, 0, 0).unwrap();
+        let utc = |day, hour| Utc.with_ymd_and_hms(2024, 11, day, hour, 0, 0).unwrap();
+
+        assert_eq!(night.next_boundary(local(4, 20)), Some(utc(5, 2)));
+        assert_eq!(night.next_boundary(local(4, 22)), Some(utc(5, 11)));
+        assert_eq!(night.next_boundary(local(5, 3)), Some(utc(5, 11)));
+        assert_eq!(QuietHours::default().next_boundary(local(4, 20)), None);
+    }
+
     #[tokio::test]
-    async fn sequence_setting() {
-        let db_path = PathBuf::from("./sequence_setting_db");
+    async fn quiet_hours_events() {
+        let db_path = PathBuf::from("./quiet_hours_events_db");
         let conn = Connection::open(db_path.clone()).await.unwrap();
-        initialize_db(&conn).await;
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+        let config = Config::new(conn.clone());
+
+        let now = Utc::now();
+        let tz = pair_timezone(&conn, DEFAULT_PAIR)
+            .await
+            .unwrap()
+            .tz()
+            .unwrap();
+        let local = now.with_timezone(&tz).time();
+        let quiet_hours = QuietHours {
+            start: Some(local + TimeDelta::hours(1)),
+            end: Some(local + TimeDelta::hours(2)),
+        };
+        let body = serde_json::to_string(&quiet_hours).unwrap();
+        signed_post(&config, "/pair/default/quiet_hours", &body).await;
+        let revision = pair_revision(&conn, DEFAULT_PAIR).await.unwrap();
+        let mut events = config.events.subscribe();
+
+        // Nothing happens until the quiet hours start
+        let later = now + TimeDelta::minutes(30);
+        let next = publish_quiet_hours(&config, now, later).await.unwrap();
+        assert_eq!(next, Some(now + TimeDelta::hours(1)));
+        assert_eq!(pair_revision(&conn, DEFAULT_PAIR).await.unwrap(), revision);
 
-        set_setting(&conn, SEQUENCE_SETTING, 5.to_string()).await;
-        assert_eq!(query_setting(&conn, SEQUENCE_SETTING).await, "5");
+        // Then the pair is paused, and resumed once they end
+        let start = now + TimeDelta::minutes(61);
+        let next = publish_quiet_hours(&config, later, start).await.unwrap();
+        assert_eq!(next, Some(now + TimeDelta::hours(2)));
+        let (_, event) = events.try_recv().unwrap();
+        assert_eq!(event, PairEvent::Active { active: false });
+        assert_eq!(
+            pair_revision(&conn, DEFAULT_PAIR).await.unwrap(),
+            revision + 1
+        );
 
-        set_setting(&conn, SEQUENCE_SETTING, 10.to_string()).await;
-        assert_eq!(query_setting(&conn, SEQUENCE_SETTING).await, "10");
+        let end = now + TimeDelta::minutes(121);
+        publish_quiet_hours(&config, start, end).await.unwrap();
+        let (_, event) = events.try_recv().unwrap();
+        assert_eq!(event, PairEvent::Active { active: true });
+        assert_eq!(
+            pair_revision(&conn, DEFAULT_PAIR).await.unwrap(),
+            revision + 2
+        );
 
         remove_file(db_path.clone()).unwrap();
     }
 
     #[tokio::test]
-    async fn message_setting() {
-        let db_path = PathBuf::from("./message_setting_db");
+    async fn paused_pair() {
+        let db_path = PathBuf::from("./paused_pair_db");
         let conn = Connection::open(db_path.clone()).await.unwrap();
-        initialize_db(&conn).await;
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+        let config = Config::new(conn.clone());
+        let get = |path: &str| Request::get(path).body(Body::empty()).unwrap();
 
-        set_setting(&conn, MESSAGE_SETTING, "SOMETHING".to_string()).await;
-        assert_eq!(query_setting(&conn, MESSAGE_SETTING).await, "SOMETHING");
+        signed_post(&config, "/pair/default/message", r#"{"message":"hi"}"#).await;
+        let (status, _) = signed_post(&config, "/pair/default/tick", r#"{"ty":1}"#).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, body) = send(&config, get("/pair/default/compressed_tick_history")).await;
+        let (active, ticks) = decode_tick_history::<8>(&body).unwrap();
+        assert!(active);
+        assert_eq!(ticks.len(), 1);
 
-        set_setting(&conn, MESSAGE_SETTING, "SOMETHING_ELSE".to_string()).await;
-        assert_eq!(
-            query_setting(&conn, MESSAGE_SETTING).await,
-            "SOMETHING_ELSE"
-        );
+        // Paused pairs can't be ticked and hold their message back
+        signed_post(&config, "/pair/default/active", r#"{"active":false}"#).await;
+        let (status, _) = signed_post(&config, "/pair/default/tick", r#"{"ty":1}"#).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (_, body) = send(&config, get("/pair/default/message")).await;
+        assert!(body.is_empty());
+        let (_, body) = send(&config, get("/pair/default/compressed_tick_history")).await;
+        let (active, ticks) = decode_tick_history::<8>(&body).unwrap();
+        assert!(!active);
+        assert_eq!(ticks.len(), 1);
+        let (_, body) = send(&config, get("/pair/default/compressed_tick_stats")).await;
+        assert!(!decode_tick_stats::<8, 8>(&body).unwrap().0);
+
+        // Quiet hours pause the pair even when it's active
+        signed_post(&config, "/pair/default/active", r#"{"active":true}"#).await;
+        assert!(pair_active(&conn, DEFAULT_PAIR).await.unwrap());
+        let tz = pair_timezone(&conn, DEFAULT_PAIR)
+            .await
+            .unwrap()
+            .tz()
+            .unwrap();
+        let now = Utc::now().with_timezone(&tz).time();
+        let quiet_hours = QuietHours {
+            start: Some(now - TimeDelta::hours(1)),
+            end: Some(now + TimeDelta::hours(1)),
+        };
+        let body = serde_json::to_string(&quiet_hours).unwrap();
+        let (status, _) = signed_post(&config, "/pair/default/quiet_hours", &body).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, body) = send(&config, get("/pair/default/active")).await;
+        assert_eq!(&body[..], b"false");
+        let (status, _) = signed_post(&config, "/pair/default/tick", r#"{"ty":1}"#).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, _) = signed_post(&config, "/pair/default/quiet_hours", "{}").await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(pair_active(&conn, DEFAULT_PAIR).await.unwrap());
+        let (_, body) = send(&config, get("/pair/default/message")).await;
+        assert_eq!(&body[..], b"hi");
 
         remove_file(db_path.clone()).unwrap();
     }
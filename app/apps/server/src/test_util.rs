@@ -0,0 +1,52 @@
+//! Helpers shared by tests that go through the router.
+
+use crate::auth::{sign, Authentication, AUTH_HEADER, EXPIRES_HEADER, IDENTITY_HEADER};
+use crate::config::Config;
+use crate::identities::identity_sequence;
+use crate::pair::DEFAULT_PAIR;
+use crate::router;
+use axum::body::{to_bytes, Body, Bytes};
+use axum::http::{Method, Request, StatusCode};
+use chrono::Utc;
+use secp256k1::SecretKey;
+use tower::ServiceExt;
+
+/// Posts a body signed with the default pair's test key and its current sequence
+pub async fn signed_post(config: &Config, path: &str, body: &str) -> (StatusCode, Bytes) {
+    let secret_key = SecretKey::from_byte_array(&[1; 32]).unwrap();
+    signed_post_as(config, path, body, DEFAULT_PAIR, &secret_key).await
+}
+
+/// Posts a body signed by one of the default pair's identities
+pub async fn signed_post_as(
+    config: &Config,
+    path: &str,
+    body: &str,
+    identity: &str,
+    secret_key: &SecretKey,
+) -> (StatusCode, Bytes) {
+    // Unknown identities are signed with any sequence so the server gets to reject them
+    let sequence = identity_sequence(&config.db, DEFAULT_PAIR, identity)
+        .await
+        .unwrap_or_default();
+    let expires = Utc::now().timestamp() + 60;
+    let auth = Authentication::new(&Method::POST, path, body.as_bytes(), sequence, expires);
+
+    let request = Request::post(path)
+        .header("content-type", "application/json")
+        .header(IDENTITY_HEADER, identity)
+        .header(AUTH_HEADER, sign(secret_key, &auth).to_string())
+        .header(EXPIRES_HEADER, expires.to_string())
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    send(config, request).await
+}
+
+pub async fn send(config: &Config, request: Request<Body>) -> (StatusCode, Bytes) {
+    let response = router(config.clone()).oneshot(request).await.unwrap();
+    let status = response.status();
+    (
+        status,
+        to_bytes(response.into_body(), usize::MAX).await.unwrap(),
+    )
+}
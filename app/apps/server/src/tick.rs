@@ -1,199 +1,757 @@
-use crate::auth::evaulate;
+use crate::auth::{evaulate, Signed};
 use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::events::{publish, PairEvent};
+use crate::pair::pair_exists;
+use crate::settings::{pair_active, pair_timezone, Timezone};
 use axum::body::Bytes;
-use axum::extract::State;
-use axum::http::{HeaderMap, StatusCode};
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::response::IntoResponse;
 use axum::Json;
-use chrono::{DateTime, NaiveTime, Timelike, Utc};
-use chrono_tz::America::Puerto_Rico;
+use chrono::{DateTime, Datelike, TimeDelta, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use companion_proto::{
+    encode_tick_history, encode_tick_stats, HistoryTick, Time, DEVICE_TICK_TYPES,
+};
 use serde::{Deserialize, Serialize};
-use tokio_rusqlite::{params, Connection};
+use tokio_rusqlite::{params, Connection, OptionalExtension};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TriggerTick {
     pub ty: u8,
 }
 
 pub async fn trigger_tick(
     State(config): State<Config>,
-    header_map: HeaderMap,
-    Json(payload): Json<TriggerTick>,
-) -> impl IntoResponse {
-    let val = header_map.get("auth").unwrap();
+    Path(pair): Path<String>,
+    request: Signed<TriggerTick>,
+) -> AppResult<impl IntoResponse> {
+    let author = evaulate(&config, &pair, &request).await?;
 
-    if let Some(res) = evaulate(&config, val, &payload).await {
-        return (res, "".to_string());
-    }
+    let tick = request.payload.ty;
+    add_tick(&config, &pair, &author, tick).await?;
+
+    Ok((StatusCode::CREATED, tick.to_string()))
+}
 
-    let tick = payload.ty as u8;
+/// Ticks for the pair and lets its subscribers know
+pub async fn add_tick(config: &Config, pair: &str, author: &str, tick: u8) -> AppResult<Tick> {
+    if !pair_active(&config.db, pair).await? {
+        return Err(AppError::PairInactive);
+    }
 
-    config
+    let db_pair = pair.to_string();
+    let author = author.to_string();
+    let created = config
         .db
         .call(move |conn| {
-            conn.execute("INSERT INTO ticks (tick_type) VALUES (?1);", params![tick])
-                .unwrap();
-            Ok(())
+            // Only tick types that belong to the pair and aren't archived can be ticked
+            let archived: Option<bool> = conn
+                .query_row(
+                    "SELECT archived FROM tick_types WHERE pair = ?1 AND id = ?2",
+                    params![db_pair, tick],
+                    |r| r.get(0),
+                )
+                .optional()?;
+            match archived {
+                None => return Ok(Err(AppError::TickTypeNotFound)),
+                Some(true) => return Ok(Err(AppError::TickTypeArchived)),
+                Some(false) => {}
+            }
+
+            let created = conn.query_row(
+                "INSERT INTO ticks (pair, tick_type, author) VALUES (?1, ?2, ?3) \
+                RETURNING id, tick_type, created_at, author;",
+                params![db_pair, tick, author],
+                |r| {
+                    Ok(Tick {
+                        id: r.get(0)?,
+                        tick: r.get(1)?,
+                        time: r.get(2)?,
+                        author: r.get(3)?,
+                    })
+                },
+            )?;
+            Ok(Ok(created))
         })
-        .await
-        .unwrap();
+        .await??;
+    publish(
+        config,
+        pair,
+        PairEvent::Tick {
+            tick: created.clone(),
+        },
+    );
 
-    (StatusCode::CREATED, tick.to_string())
+    Ok(created)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct TickType {
-    pub id: u8,
-    pub tick: String,
-}
-pub async fn get_ticks(State(config): State<Config>) -> Json<Vec<TickType>> {
-    Json(
-        config
-            .db
-            .call(move |conn| {
-                let res: Vec<TickType> = conn
-                    .prepare("SELECT id, value FROM tick_types")
-                    .unwrap()
-                    .query_map([], |r| {
-                        Ok(TickType {
+/// Deletes a single tick, either the given one or the latest one ticked within the last minutes
+#[derive(Serialize, Deserialize, Default)]
+pub struct DeleteTick {
+    pub id: Option<u64>,
+    pub within_minutes: Option<u32>,
+}
+
+pub async fn delete_tick(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    request: Signed<DeleteTick>,
+) -> AppResult<Json<Tick>> {
+    evaulate(&config, &pair, &request).await?;
+
+    let DeleteTick { id, within_minutes } = request.payload;
+    let db_pair = pair.clone();
+    let since = match (id, within_minutes) {
+        (Some(_), None) => None,
+        (None, Some(minutes)) => {
+            Some((Utc::now() - TimeDelta::minutes(minutes as i64)).naive_utc())
+        }
+        _ => {
+            return Err(AppError::InvalidBody(
+                "exactly one of `id` or `within_minutes` must be given".to_string(),
+            ))
+        }
+    };
+
+    let tick = config
+        .db
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            let tick = tx
+                .query_row(
+                    "\
+                SELECT id, tick_type, created_at, author \
+                FROM ticks \
+                WHERE pair = ?1 AND deleted_at IS NULL \
+                AND (?2 IS NULL OR id = ?2) \
+                AND (?3 IS NULL OR created_at >= ?3) \
+                ORDER BY created_at DESC, id DESC \
+                LIMIT 1",
+                    params![db_pair, id, since],
+                    |r| {
+                        Ok(Tick {
                             id: r.get(0)?,
                             tick: r.get(1)?,
+                            time: r.get(2)?,
+                            author: r.get(3)?,
                         })
-                    })?
-                    .map(|i| i.unwrap())
-                    .collect();
-                Ok(res)
-            })
-            .await
-            .unwrap(),
-    )
+                    },
+                )
+                .optional()?;
+            let Some(tick) = tick else {
+                return Ok(Err(AppError::TickNotFound));
+            };
+
+            tx.execute(
+                "UPDATE ticks SET deleted_at = CURRENT_TIMESTAMP WHERE id = ?1",
+                params![tick.id],
+            )?;
+            tx.commit()?;
+            Ok(Ok(tick))
+        })
+        .await??;
+    publish(&config, &pair, PairEvent::TickDeleted { id: tick.id });
+
+    Ok(Json(tick))
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Tick {
-    pub id: u8,
+    pub id: u64,
     pub tick: u8,
     pub time: String,
+    /// The identity that ticked
+    pub author: String,
+}
+
+/// Lets a viewer see the history in their own zone instead of the pair's configured one
+#[derive(Deserialize, Default)]
+pub struct ViewerZone {
+    pub timezone: Option<String>,
+    pub day_start: Option<u32>,
+}
+
+/// The day the tick history is shown for
+pub struct HistoryDay {
+    /// Zone ticks are converted into for the compressed history
+    pub timezone: Tz,
+    /// When the day rolled over
+    pub start: DateTime<Utc>,
+}
+
+impl ViewerZone {
+    /// Resolves the viewer's zone and day start hour, falling back to the pair's settings for
+    /// anything not given
+    pub async fn resolve(self, connection: &Connection, pair: &str) -> AppResult<(Tz, u32)> {
+        let stored = pair_timezone(connection, pair).await?;
+        let timezone = Timezone {
+            timezone: self.timezone.unwrap_or(stored.timezone),
+            day_start: self.day_start.unwrap_or(stored.day_start),
+        };
+        let tz = timezone.tz().map_err(AppError::InvalidQuery)?;
+        Ok((tz, timezone.day_start))
+    }
 }
 
-pub async fn get_tick_history(State(config): State<Config>) -> Json<Vec<Tick>> {
-    Json(query_ticks(&config.db).await)
+impl HistoryDay {
+    pub async fn resolve(
+        connection: &Connection,
+        pair: &str,
+        viewer: ViewerZone,
+    ) -> AppResult<Self> {
+        let (timezone, hour) = viewer.resolve(connection, pair).await?;
+        Ok(Self {
+            timezone,
+            start: day_start(timezone, hour, Utc::now()),
+        })
+    }
 }
 
-pub async fn query_ticks(connection: &Connection) -> Vec<Tick> {
-    let time = Utc::now()
-        .with_timezone(&Puerto_Rico)
-        .with_time(NaiveTime::from_hms_opt(6, 0, 0).unwrap())
+/// Returns when the current day started, before the day start hour the day is still yesterday
+pub fn day_start(timezone: Tz, day_start: u32, now: DateTime<Utc>) -> DateTime<Utc> {
+    let local = now.with_timezone(&timezone);
+    let mut date = local.date_naive();
+    if local.hour() < day_start {
+        date = date.pred_opt().unwrap();
+    }
+
+    let start = date.and_hms_opt(day_start, 0, 0).unwrap();
+    // The start hour might be skipped by a DST change, in which case the day starts right after
+    timezone
+        .from_local_datetime(&start)
+        .earliest()
+        .or_else(|| {
+            timezone
+                .from_local_datetime(&(start + TimeDelta::hours(1)))
+                .earliest()
+        })
         .unwrap()
-        .naive_utc();
+        .with_timezone(&Utc)
+}
 
-    connection
+/// Header holding the cursor to request the next page of tick history with
+pub const NEXT_CURSOR_HEADER: &str = "next-cursor";
+pub const DEFAULT_HISTORY_LIMIT: u32 = 500;
+pub const MAX_HISTORY_LIMIT: u32 = 1000;
+
+#[derive(Deserialize, Default)]
+pub struct HistoryQuery {
+    pub timezone: Option<String>,
+    pub day_start: Option<u32>,
+    /// Start of the range, defaults to when the current day started
+    pub from: Option<DateTime<Utc>>,
+    /// Exclusive end of the range
+    pub to: Option<DateTime<Utc>>,
+    /// Only return ticks of this type
+    pub tick: Option<u8>,
+    /// Id of the last tick of the previous page
+    pub cursor: Option<u64>,
+    pub limit: Option<u32>,
+}
+
+/// A page of ticks ordered by creation time
+pub struct TickRange {
+    pub from: DateTime<Utc>,
+    pub to: Option<DateTime<Utc>>,
+    pub tick: Option<u8>,
+    pub cursor: Option<u64>,
+    pub limit: u32,
+}
+
+pub async fn get_tick_history(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    query: Result<Query<HistoryQuery>, QueryRejection>,
+) -> AppResult<impl IntoResponse> {
+    let Query(query) = query.map_err(|e| AppError::InvalidQuery(e.body_text()))?;
+    if !pair_exists(&config.db, &pair).await? {
+        return Err(AppError::PairNotFound);
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+    if limit == 0 || limit > MAX_HISTORY_LIMIT {
+        return Err(AppError::InvalidQuery(format!(
+            "limit must be in 1..={MAX_HISTORY_LIMIT}"
+        )));
+    }
+
+    let from = match query.from {
+        Some(from) => from,
+        None => {
+            let viewer = ViewerZone {
+                timezone: query.timezone,
+                day_start: query.day_start,
+            };
+            HistoryDay::resolve(&config.db, &pair, viewer).await?.start
+        }
+    };
+    if query.to.is_some_and(|to| to < from) {
+        return Err(AppError::InvalidQuery("`to` is before `from`".to_string()));
+    }
+
+    let range = TickRange {
+        from,
+        to: query.to,
+        tick: query.tick,
+        cursor: query.cursor,
+        limit,
+    };
+    let (ticks, next) = query_ticks(&config.db, &pair, &range).await?;
+
+    let mut headers = HeaderMap::new();
+    if let Some(next) = next {
+        headers.insert(NEXT_CURSOR_HEADER, HeaderValue::from(next));
+    }
+    Ok((headers, Json(ticks)))
+}
+
+/// Returns the requested page of ticks along with the cursor of the next page, if there is one
+pub async fn query_ticks(
+    connection: &Connection,
+    pair: &str,
+    range: &TickRange,
+) -> AppResult<(Vec<Tick>, Option<u64>)> {
+    let pair = pair.to_string();
+    let from = range.from.naive_utc();
+    let to = range.to.map(|to| to.naive_utc());
+    let tick = range.tick;
+    let cursor = range.cursor;
+    let limit = range.limit;
+
+    let mut ticks = connection
         .call(move |conn| {
+            // The cursor resumes right after the last tick of the previous page, ties in time are
+            // broken by id
             let res = conn
                 .prepare(
                     "\
-                SELECT id, tick_type, created_at \
+                SELECT id, tick_type, created_at, author \
                 FROM ticks \
-                WHERE created_at >= ?1;",
-                )
-                .unwrap()
-                .query_map(params![time], |r| {
+                WHERE pair = ?1 AND deleted_at IS NULL AND created_at >= ?2 \
+                AND (?3 IS NULL OR created_at < ?3) \
+                AND (?4 IS NULL OR tick_type = ?4) \
+                AND (?5 IS NULL OR (created_at, id) > \
+                    (SELECT created_at, id FROM ticks WHERE id = ?5)) \
+                ORDER BY created_at, id \
+                LIMIT ?6;",
+                )?
+                .query_map(params![pair, from, to, tick, cursor, limit + 1], |r| {
                     Ok(Tick {
                         id: r.get(0)?,
                         tick: r.get(1)?,
                         time: r.get(2)?,
+                        author: r.get(3)?,
                     })
                 })?
-                .map(|i| i.unwrap())
-                .collect();
+                .collect::<Result<Vec<_>, _>>()?;
             Ok(res)
         })
-        .await
-        .unwrap()
+        .await?;
+
+    // One extra tick is queried to know if there's another page
+    let next = if ticks.len() > limit as usize {
+        ticks.truncate(limit as usize);
+        ticks.last().map(|tick| tick.id)
+    } else {
+        None
+    };
+
+    Ok((ticks, next))
 }
 
-/// WARNING: the returned data assumes that tick is one byte and hour and minute one byte each,
-/// this reduces each tick into 3 bytes total
-pub async fn get_embedded_tick_history(State(config): State<Config>) -> impl IntoResponse {
-    crate::tick::query_embedded_ticks(&config.db).await
+/// Today's ticks in the device's format, see `encode_tick_history`. Hour and minute are in the
+/// viewer's timezone.
+pub async fn get_embedded_tick_history(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    viewer: Result<Query<ViewerZone>, QueryRejection>,
+) -> AppResult<Bytes> {
+    let Query(viewer) = viewer.map_err(|e| AppError::InvalidQuery(e.body_text()))?;
+    if !pair_exists(&config.db, &pair).await? {
+        return Err(AppError::PairNotFound);
+    }
+
+    let day = HistoryDay::resolve(&config.db, &pair, viewer).await?;
+    let ticks = query_embedded_ticks(&config.db, &pair, &day).await?;
+    let active = pair_active(&config.db, &pair).await?;
+    Ok(Bytes::from(encode_tick_history(active, &ticks)))
 }
 
-pub async fn query_embedded_ticks(connection: &Connection) -> Bytes {
-    let time = Utc::now()
-        .with_timezone(&Puerto_Rico)
-        .with_time(NaiveTime::from_hms_opt(6, 0, 0).unwrap())
-        .unwrap()
-        .naive_utc();
+/// The day's ticks with their time in the viewer's timezone
+pub async fn query_embedded_ticks(
+    connection: &Connection,
+    pair: &str,
+    day: &HistoryDay,
+) -> AppResult<Vec<HistoryTick>> {
+    let pair = pair.to_string();
+    let time = day.start.naive_utc();
+    let timezone = day.timezone;
 
-    let collection: Vec<[u8; 3]> = connection
+    connection
         .call(move |conn| {
             let res = conn
                 .prepare(
                     "\
                 SELECT id, tick_type, created_at \
                 FROM ticks \
-                WHERE created_at >= ?1;",
-                )
-                .unwrap()
-                .query_map(params![time], |r| {
+                WHERE pair = ?1 AND deleted_at IS NULL AND created_at >= ?2 \
+                ORDER BY created_at, id;",
+                )?
+                .query_map(params![pair, time], |r| {
                     let tick: u8 = r.get(1)?;
                     let date_time: DateTime<Utc> = r.get(2)?;
-                    let local_time = date_time.with_timezone(&Puerto_Rico);
-                    let hour = local_time.hour() as u8;
-                    let minute = local_time.minute() as u8;
+                    let local_time = date_time.with_timezone(&timezone);
+                    let time = Time {
+                        hour: local_time.hour() as u8,
+                        minute: local_time.minute() as u8,
+                    };
 
-                    Ok([tick, hour, minute])
+                    Ok(HistoryTick {
+                        type_id: tick,
+                        time,
+                    })
                 })?
-                .map(|i| i.unwrap())
-                .collect();
+                .collect::<Result<Vec<_>, _>>()?;
             Ok(res)
         })
         .await
-        .unwrap();
+        .map_err(AppError::from)
+}
+
+/// How tick stats are grouped, days and weeks follow the day start hour and weeks start on Monday
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    Hour,
+    #[default]
+    Day,
+    Week,
+}
+
+/// Stats are limited so the bucket count fits in a byte in the compressed stats
+pub const MAX_STATS_BUCKETS: usize = u8::MAX as usize;
+
+impl Bucket {
+    /// Buckets returned when no range is given, the last one being the current one
+    pub fn default_count(&self) -> u32 {
+        match self {
+            Bucket::Hour => 24,
+            Bucket::Day => 7,
+            Bucket::Week => 4,
+        }
+    }
+
+    /// Returns when the bucket holding the given time started
+    pub fn start(&self, timezone: Tz, day_start_hour: u32, time: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Bucket::Hour => {
+                let local = time.with_timezone(&timezone);
+                time - TimeDelta::minutes(local.minute() as i64)
+                    - TimeDelta::seconds(local.second() as i64)
+                    - TimeDelta::nanoseconds(local.nanosecond() as i64)
+            }
+            Bucket::Day => day_start(timezone, day_start_hour, time),
+            Bucket::Week => {
+                let day = day_start(timezone, day_start_hour, time);
+                let weekday = day.with_timezone(&timezone).weekday();
+                // Noon keeps DST changes from landing the time on a neighbouring day
+                let monday = day - TimeDelta::days(weekday.num_days_from_monday() as i64)
+                    + TimeDelta::hours(12);
+                day_start(timezone, day_start_hour, monday)
+            }
+        }
+    }
+
+    /// Returns when the bucket starting at the given time ends
+    pub fn end(&self, timezone: Tz, day_start_hour: u32, start: DateTime<Utc>) -> DateTime<Utc> {
+        // Days last 23 to 25 hours so these always land inside the next bucket
+        let step = match self {
+            Bucket::Hour => TimeDelta::hours(1),
+            Bucket::Day => TimeDelta::hours(36),
+            Bucket::Week => TimeDelta::days(7) + TimeDelta::hours(12),
+        };
+        self.start(timezone, day_start_hour, start + step)
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct StatsQuery {
+    pub timezone: Option<String>,
+    pub day_start: Option<u32>,
+    pub bucket: Option<Bucket>,
+    /// Defaults to enough time for the bucket's default count
+    pub from: Option<DateTime<Utc>>,
+    /// Exclusive end of the range, defaults to now
+    pub to: Option<DateTime<Utc>>,
+}
+
+pub struct StatsRange {
+    pub bucket: Bucket,
+    pub timezone: Tz,
+    pub day_start: u32,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+impl StatsRange {
+    pub async fn resolve(
+        connection: &Connection,
+        pair: &str,
+        query: StatsQuery,
+    ) -> AppResult<Self> {
+        let viewer = ViewerZone {
+            timezone: query.timezone,
+            day_start: query.day_start,
+        };
+        let (timezone, day_start) = viewer.resolve(connection, pair).await?;
+        let bucket = query.bucket.unwrap_or_default();
+        let to = query.to.unwrap_or_else(Utc::now);
+
+        let from = match query.from {
+            Some(from) => from,
+            None => {
+                let mut from = bucket.start(timezone, day_start, to);
+                for _ in 1..bucket.default_count() {
+                    from = bucket.start(timezone, day_start, from - TimeDelta::seconds(1));
+                }
+                from
+            }
+        };
+        if to < from {
+            return Err(AppError::InvalidQuery("`to` is before `from`".to_string()));
+        }
+
+        Ok(Self {
+            bucket,
+            timezone,
+            day_start,
+            from,
+            to,
+        })
+    }
+
+    /// Start of every bucket in the range
+    pub fn starts(&self) -> AppResult<Vec<DateTime<Utc>>> {
+        let mut starts = vec![self.bucket.start(self.timezone, self.day_start, self.from)];
+        loop {
+            let end = self
+                .bucket
+                .end(self.timezone, self.day_start, *starts.last().unwrap());
+            if end >= self.to {
+                return Ok(starts);
+            }
+            if starts.len() == MAX_STATS_BUCKETS {
+                return Err(AppError::InvalidQuery(format!(
+                    "range has more than {MAX_STATS_BUCKETS} buckets"
+                )));
+            }
+            starts.push(end);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatsBucket {
+    pub start: DateTime<Utc>,
+    /// Tick count of every tick type, in the same order as the stats' ticks
+    pub counts: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TickStats {
+    pub bucket: Bucket,
+    /// Every tick type of the pair in display order, archived ones only when ticked in the range
+    pub ticks: Vec<u8>,
+    pub buckets: Vec<StatsBucket>,
+}
+
+impl TickStats {
+    /// The stats in the device's format, see `encode_tick_stats`. Counts over u16::MAX are capped
+    /// and only the first `DEVICE_TICK_TYPES` tick types are kept so the stats fit its buffer.
+    pub fn to_bytes(&self, active: bool) -> Bytes {
+        let ticks = &self.ticks[..self.ticks.len().min(DEVICE_TICK_TYPES)];
+        let buckets: Vec<Vec<u16>> = self
+            .buckets
+            .iter()
+            .map(|bucket| {
+                bucket.counts[..ticks.len()]
+                    .iter()
+                    .map(|count| (*count).min(u16::MAX as u32) as u16)
+                    .collect()
+            })
+            .collect();
+        Bytes::from(encode_tick_stats(active, ticks, &buckets))
+    }
+}
+
+pub async fn get_tick_stats(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    query: Result<Query<StatsQuery>, QueryRejection>,
+) -> AppResult<Json<TickStats>> {
+    let Query(query) = query.map_err(|e| AppError::InvalidQuery(e.body_text()))?;
+    if !pair_exists(&config.db, &pair).await? {
+        return Err(AppError::PairNotFound);
+    }
+
+    let range = StatsRange::resolve(&config.db, &pair, query).await?;
+    Ok(Json(query_tick_stats(&config.db, &pair, &range).await?))
+}
+
+pub async fn get_embedded_tick_stats(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    query: Result<Query<StatsQuery>, QueryRejection>,
+) -> AppResult<Bytes> {
+    let Query(query) = query.map_err(|e| AppError::InvalidQuery(e.body_text()))?;
+    if !pair_exists(&config.db, &pair).await? {
+        return Err(AppError::PairNotFound);
+    }
+
+    let range = StatsRange::resolve(&config.db, &pair, query).await?;
+    let stats = query_tick_stats(&config.db, &pair, &range).await?;
+    let active = pair_active(&config.db, &pair).await?;
+    Ok(stats.to_bytes(active))
+}
+
+pub async fn query_tick_stats(
+    connection: &Connection,
+    pair: &str,
+    range: &StatsRange,
+) -> AppResult<TickStats> {
+    let starts = range.starts()?;
+
+    let pair = pair.to_string();
+    let from = starts[0].naive_utc();
+    let to = range.to.naive_utc();
+
+    let (ticks, rows): (Vec<u8>, Vec<(u8, DateTime<Utc>)>) = connection
+        .call(move |conn| {
+            let ticks = conn
+                .prepare(
+                    "\
+                SELECT id FROM tick_types \
+                WHERE pair = ?1 AND (archived = 0 OR id IN ( \
+                    SELECT tick_type FROM ticks \
+                    WHERE pair = ?1 AND deleted_at IS NULL \
+                    AND created_at >= ?2 AND created_at < ?3)) \
+                ORDER BY position, id",
+                )?
+                .query_map(params![pair, from, to], |r| r.get(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            let rows = conn
+                .prepare(
+                    "\
+                SELECT tick_type, created_at \
+                FROM ticks \
+                WHERE pair = ?1 AND deleted_at IS NULL AND created_at >= ?2 AND created_at < ?3;",
+                )?
+                .query_map(params![pair, from, to], |r| Ok((r.get(0)?, r.get(1)?)))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok((ticks, rows))
+        })
+        .await?;
 
-    let mut res = Vec::with_capacity((collection.len() * 3) + 2);
-    let collection_size: [u8; 2] = (collection.len() as u16).to_be_bytes();
-    res.append(&mut collection_size.to_vec());
-    for collection in collection {
-        res.append(&mut collection.to_vec());
+    let mut buckets: Vec<StatsBucket> = starts
+        .into_iter()
+        .map(|start| StatsBucket {
+            start,
+            counts: vec![0; ticks.len()],
+        })
+        .collect();
+    for (tick, time) in rows {
+        let bucket = buckets.partition_point(|bucket| bucket.start <= time) - 1;
+        if let Some(index) = ticks.iter().position(|id| *id == tick) {
+            buckets[bucket].counts[index] += 1;
+        }
     }
-    Bytes::from(res)
+
+    Ok(TickStats {
+        bucket: range.bucket,
+        ticks,
+        buckets,
+    })
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::config::initialize_db;
-    use chrono::{Days, NaiveTime, Utc};
-    use chrono_tz::America::Puerto_Rico;
+    use crate::config::{initialize_db, DefaultPair};
+    use crate::pair::DEFAULT_PAIR;
+    use crate::test_util::signed_post;
+    use chrono::NaiveDate;
+    use chrono_tz::America::{New_York, Puerto_Rico};
+    use chrono_tz::Asia::Tokyo;
+    use companion_proto::{decode_tick_stats, tick_stats_size};
     use std::fs::remove_file;
     use std::path::PathBuf;
 
+    fn at(timezone: Tz, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        timezone
+            .from_local_datetime(
+                &NaiveDate::from_ymd_opt(2024, 3, day)
+                    .unwrap()
+                    .and_hms_opt(hour, minute, 0)
+                    .unwrap(),
+            )
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn day_rollover() {
+        // After the day start hour the day is today
+        assert_eq!(
+            day_start(Puerto_Rico, 6, at(Puerto_Rico, 5, 12, 30)),
+            at(Puerto_Rico, 5, 6, 0)
+        );
+        // Before it, the day is still yesterday
+        assert_eq!(
+            day_start(Puerto_Rico, 6, at(Puerto_Rico, 5, 3, 0)),
+            at(Puerto_Rico, 4, 6, 0)
+        );
+        // The same instant is a different day depending on the zone
+        assert_eq!(
+            day_start(Tokyo, 0, at(Puerto_Rico, 5, 12, 0)),
+            at(Tokyo, 6, 0, 0)
+        );
+        // 02:00 doesn't exist on New York's 2024 spring DST change
+        assert_eq!(
+            day_start(New_York, 2, at(New_York, 10, 12, 0)),
+            at(New_York, 10, 3, 0)
+        );
+    }
+
     #[tokio::test]
     async fn test_ticks() {
         let db_path = PathBuf::from("./test_ticks_db");
         let conn = Connection::open(db_path.clone()).await.unwrap();
-        initialize_db(&conn).await;
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
 
-        // Create the important data
+        let day = HistoryDay {
+            timezone: Puerto_Rico,
+            start: at(Puerto_Rico, 5, 6, 0),
+        };
 
-        conn.call(|conn| {
-            let insert = "INSERT INTO ticks (tick_type, created_at) VALUES (?1, ?2);";
+        // Create the important data
+        conn.call(move |conn| {
+            let insert = "INSERT INTO ticks (pair, tick_type, created_at) VALUES (?1, ?2, ?3);";
             let mut ticks_insert = conn.prepare(insert)?;
 
             for hour in 0..24 {
-                let time = Utc::now()
-                    .with_timezone(&Puerto_Rico)
-                    .with_time(NaiveTime::from_hms_opt(hour, 0, 0).unwrap())
-                    .unwrap()
-                    .naive_utc();
+                let time = (day.start + TimeDelta::hours(hour)).naive_utc();
 
                 // Today
-                ticks_insert.execute(params![2, time]).unwrap();
+                ticks_insert
+                    .execute(params![DEFAULT_PAIR, 2, time])
+                    .unwrap();
 
                 // Yesterday
                 ticks_insert
-                    .execute(params![1, time.checked_sub_days(Days::new(1)).unwrap()])
+                    .execute(params![DEFAULT_PAIR, 1, time - TimeDelta::days(1)])
                     .unwrap();
             }
 
@@ -202,11 +760,302 @@ mod test {
         .await
         .unwrap();
 
-        for tick in query_ticks(&conn).await {
+        let mut range = TickRange {
+            from: day.start,
+            to: None,
+            tick: None,
+            cursor: None,
+            limit: MAX_HISTORY_LIMIT,
+        };
+        let (ticks, next) = query_ticks(&conn, DEFAULT_PAIR, &range).await.unwrap();
+        assert_eq!(next, None);
+        assert_eq!(ticks.len(), 24);
+        for tick in ticks {
             assert_eq!(tick.tick, 2);
         }
 
-        println!("{:?}", query_embedded_ticks(&conn).await);
+        // Both days are paged through in order without repeating a tick
+        range.from = day.start - TimeDelta::days(1);
+        range.limit = 20;
+        let mut pages = vec![];
+        loop {
+            let (ticks, next) = query_ticks(&conn, DEFAULT_PAIR, &range).await.unwrap();
+            pages.extend(ticks);
+            match next {
+                Some(next) => range.cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(pages.len(), 48);
+        assert!(pages.windows(2).all(|w| w[0].time < w[1].time));
+        assert_eq!(pages.iter().filter(|tick| tick.tick == 1).count(), 24);
+
+        // Ranges are filtered by tick type and end exclusively
+        range.cursor = None;
+        range.tick = Some(1);
+        range.to = Some(day.start - TimeDelta::hours(12));
+        let (ticks, _) = query_ticks(&conn, DEFAULT_PAIR, &range).await.unwrap();
+        assert_eq!(ticks.len(), 12);
+        assert!(ticks.iter().all(|tick| tick.tick == 1));
+
+        // The compressed history is in the viewer's zone
+        let day = HistoryDay {
+            timezone: Tokyo,
+            start: at(Puerto_Rico, 5, 6, 0),
+        };
+        let embedded = query_embedded_ticks(&conn, DEFAULT_PAIR, &day)
+            .await
+            .unwrap();
+        assert_eq!(embedded.len(), 24);
+        // 06:00 in Puerto Rico is 19:00 in Tokyo
+        assert_eq!(
+            embedded[0],
+            HistoryTick {
+                type_id: 2,
+                time: Time {
+                    hour: 19,
+                    minute: 0
+                }
+            }
+        );
+
+        remove_file(db_path.clone()).unwrap();
+    }
+    #[test]
+    fn stats_buckets() {
+        let time = at(Puerto_Rico, 6, 14, 25);
+        assert_eq!(
+            Bucket::Hour.start(Puerto_Rico, 6, time),
+            at(Puerto_Rico, 6, 14, 0)
+        );
+        assert_eq!(
+            Bucket::Day.start(Puerto_Rico, 6, time),
+            at(Puerto_Rico, 6, 6, 0)
+        );
+        // 2024-03-06 is a Wednesday
+        assert_eq!(
+            Bucket::Week.start(Puerto_Rico, 6, time),
+            at(Puerto_Rico, 4, 6, 0)
+        );
+
+        // Days around a DST change are still aligned to the day start hour
+        let start = Bucket::Day.start(New_York, 6, at(New_York, 9, 12, 0));
+        assert_eq!(Bucket::Day.end(New_York, 6, start), at(New_York, 10, 6, 0));
+        assert_eq!(
+            Bucket::Week.end(New_York, 6, at(New_York, 4, 6, 0)),
+            at(New_York, 11, 6, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn embedded_history_order() {
+        let db_path = PathBuf::from("./embedded_history_order_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+
+        // Ticks are stored out of time order, the device shows the last one as the latest
+        conn.call(move |conn| {
+            let insert = "INSERT INTO ticks (pair, tick_type, created_at) VALUES (?1, ?2, ?3);";
+            let mut ticks_insert = conn.prepare(insert)?;
+            for (tick, hour) in [(2, 15), (1, 9), (2, 12), (1, 15)] {
+                let time = at(Puerto_Rico, 5, hour, 0).naive_utc();
+                ticks_insert.execute(params![DEFAULT_PAIR, tick, time])?;
+            }
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        let day = HistoryDay {
+            timezone: Puerto_Rico,
+            start: at(Puerto_Rico, 5, 6, 0),
+        };
+        let ticks = query_embedded_ticks(&conn, DEFAULT_PAIR, &day)
+            .await
+            .unwrap();
+        let ticks: Vec<(u8, u8)> = ticks
+            .iter()
+            .map(|tick| (tick.type_id, tick.time.hour))
+            .collect();
+        // Ticks made at the same time keep the order they were made in
+        assert_eq!(ticks, vec![(1, 9), (2, 12), (2, 15), (1, 15)]);
+
+        remove_file(db_path.clone()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tick_stats() {
+        let db_path = PathBuf::from("./tick_stats_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+
+        // One tick of the first type every day and two of the second every other day
+        conn.call(move |conn| {
+            let insert = "INSERT INTO ticks (pair, tick_type, created_at) VALUES (?1, ?2, ?3);";
+            let mut ticks_insert = conn.prepare(insert)?;
+            for day in 1..8 {
+                let time = at(Puerto_Rico, day, 12, 0).naive_utc();
+                ticks_insert.execute(params![DEFAULT_PAIR, 1, time])?;
+                if day % 2 == 0 {
+                    ticks_insert.execute(params![DEFAULT_PAIR, 2, time])?;
+                    ticks_insert.execute(params![DEFAULT_PAIR, 2, time])?;
+                }
+            }
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        let range = StatsRange::resolve(
+            &conn,
+            DEFAULT_PAIR,
+            StatsQuery {
+                to: Some(at(Puerto_Rico, 7, 18, 0)),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let stats = query_tick_stats(&conn, DEFAULT_PAIR, &range).await.unwrap();
+
+        assert_eq!(stats.ticks, vec![1, 2]);
+        assert_eq!(stats.buckets.len(), 7);
+        assert_eq!(stats.buckets[0].start, at(Puerto_Rico, 1, 6, 0));
+        let counts: Vec<Vec<u32>> = stats.buckets.iter().map(|b| b.counts.clone()).collect();
+        assert_eq!(
+            counts,
+            vec![
+                vec![1, 0],
+                vec![1, 2],
+                vec![1, 0],
+                vec![1, 2],
+                vec![1, 0],
+                vec![1, 2],
+                vec![1, 0]
+            ]
+        );
+
+        let bytes = stats.to_bytes(true);
+        let (active, decoded) = decode_tick_stats::<2, 7>(&bytes).unwrap();
+        assert!(active);
+        assert_eq!(decoded.type_ids, [1, 2]);
+        assert_eq!(decoded.buckets[0], [1, 0]);
+        assert_eq!(decoded.buckets[1], [1, 2]);
+
+        // Too many buckets are rejected
+        let range = StatsRange::resolve(
+            &conn,
+            DEFAULT_PAIR,
+            StatsQuery {
+                bucket: Some(Bucket::Hour),
+                from: Some(at(Puerto_Rico, 1, 0, 0)),
+                to: Some(at(Puerto_Rico, 20, 0, 0)),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            query_tick_stats(&conn, DEFAULT_PAIR, &range).await,
+            Err(AppError::InvalidQuery(_))
+        ));
+
+        remove_file(db_path.clone()).unwrap();
+    }
+
+    #[test]
+    fn device_stats_size() {
+        // More tick types than the device has room for, every one ticked each day
+        let ticks: Vec<u8> = (1..=17).collect();
+        let stats = TickStats {
+            bucket: Bucket::Day,
+            ticks: ticks.clone(),
+            buckets: (1..8)
+                .map(|day| StatsBucket {
+                    start: at(Puerto_Rico, day, 6, 0),
+                    counts: ticks.iter().map(|id| day * 100 + *id as u32).collect(),
+                })
+                .collect(),
+        };
+
+        let bytes = stats.to_bytes(true);
+        assert_eq!(bytes.len(), tick_stats_size(DEVICE_TICK_TYPES, 7));
+        let (_, decoded) = decode_tick_stats::<DEVICE_TICK_TYPES, 7>(&bytes).unwrap();
+        assert_eq!(decoded.type_ids, ticks[..DEVICE_TICK_TYPES]);
+        assert_eq!(decoded.buckets.len(), 7);
+        assert_eq!(decoded.buckets[6][9], 710);
+    }
+
+    #[tokio::test]
+    async fn delete_ticks() {
+        let db_path = PathBuf::from("./delete_ticks_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+        let config = Config::new(conn.clone());
+
+        // An old tick and two recent ones
+        conn.call(|conn| {
+            let old = (Utc::now() - TimeDelta::hours(1)).naive_utc();
+            conn.execute(
+                "INSERT INTO ticks (pair, tick_type, created_at) VALUES (?1, 1, ?2);",
+                params![DEFAULT_PAIR, old],
+            )?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+        for ty in [1, 2] {
+            let body = format!(r#"{{"ty":{ty}}}"#);
+            let (status, _) = signed_post(&config, "/pair/default/tick", &body).await;
+            assert_eq!(status, StatusCode::CREATED);
+        }
+
+        let path = "/pair/default/tick/delete";
+        for body in ["{}", r#"{"id":1,"within_minutes":5}"#] {
+            let (status, _) = signed_post(&config, path, body).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+
+        // Undoing removes the latest tick, then the one before it
+        let (status, body) = signed_post(&config, path, r#"{"within_minutes":5}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        let deleted: Tick = serde_json::from_slice(&body).unwrap();
+        assert_eq!((deleted.id, deleted.tick), (3, 2));
+        let (_, body) = signed_post(&config, path, r#"{"within_minutes":5}"#).await;
+        let deleted: Tick = serde_json::from_slice(&body).unwrap();
+        assert_eq!(deleted.id, 2);
+
+        // The old tick is out of the undo window but can be deleted by id, only once
+        let (status, _) = signed_post(&config, path, r#"{"within_minutes":5}"#).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = signed_post(&config, path, r#"{"id":1}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = signed_post(&config, path, r#"{"id":1}"#).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Deleted ticks are kept but no longer show up
+        let range = TickRange {
+            from: Utc::now() - TimeDelta::days(1),
+            to: None,
+            tick: None,
+            cursor: None,
+            limit: MAX_HISTORY_LIMIT,
+        };
+        let (ticks, _) = query_ticks(&conn, DEFAULT_PAIR, &range).await.unwrap();
+        assert!(ticks.is_empty());
+        let day = HistoryDay {
+            timezone: Puerto_Rico,
+            start: Utc::now() - TimeDelta::days(1),
+        };
+        let embedded = query_embedded_ticks(&conn, DEFAULT_PAIR, &day)
+            .await
+            .unwrap();
+        assert!(embedded.is_empty());
+        let count: u32 = conn
+            .call(|conn| Ok(conn.query_row("SELECT COUNT(*) FROM ticks", [], |r| r.get(0))?))
+            .await
+            .unwrap();
+        assert_eq!(count, 3);
 
         remove_file(db_path.clone()).unwrap();
     }
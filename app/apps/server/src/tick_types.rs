@@ -0,0 +1,429 @@
+use crate::auth::{evaulate, Signed};
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::events::{publish, PairEvent};
+use crate::pair::pair_exists;
+use axum::body::Bytes;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use companion_proto::encode_tick_types;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tokio_rusqlite::{params, Connection, OptionalExtension};
+
+/// Names longer than this don't fit on the device's screen
+pub const MAX_TICK_NAME_LEN: usize = 24;
+pub const MAX_TICK_ICON_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TickType {
+    pub id: u8,
+    pub tick: String,
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// Archived types can't be ticked anymore but their ticks are kept
+    #[serde(default)]
+    pub archived: bool,
+}
+
+#[derive(Deserialize, Default)]
+pub struct TickTypesQuery {
+    /// Also list archived tick types
+    pub archived: Option<bool>,
+}
+
+pub async fn get_ticks(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    query: Result<Query<TickTypesQuery>, QueryRejection>,
+) -> AppResult<Json<Vec<TickType>>> {
+    let Query(query) = query.map_err(|e| AppError::InvalidQuery(e.body_text()))?;
+    if !pair_exists(&config.db, &pair).await? {
+        return Err(AppError::PairNotFound);
+    }
+
+    let archived = query.archived.unwrap_or(false);
+    Ok(Json(query_tick_types(&config.db, &pair, archived).await?))
+}
+
+/// Every tick type in display order so the device can name the ticks in its history and stats,
+/// see `encode_tick_types`
+pub async fn get_embedded_ticks(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+) -> AppResult<Bytes> {
+    if !pair_exists(&config.db, &pair).await? {
+        return Err(AppError::PairNotFound);
+    }
+
+    // Archived types are still in today's history and the stats
+    let ticks = query_tick_types(&config.db, &pair, true).await?;
+    let names: Vec<(u8, &str)> = ticks
+        .iter()
+        .map(|tick| (tick.id, tick.tick.as_str()))
+        .collect();
+    Ok(Bytes::from(encode_tick_types(&names)))
+}
+
+/// Returns the pair's tick types in their display order
+pub async fn query_tick_types(
+    connection: &Connection,
+    pair: &str,
+    archived: bool,
+) -> AppResult<Vec<TickType>> {
+    let pair = pair.to_string();
+    connection
+        .call(move |conn| {
+            let res = conn
+                .prepare(
+                    "\
+                SELECT id, value, icon, archived \
+                FROM tick_types \
+                WHERE pair = ?1 AND (?2 OR archived = 0) \
+                ORDER BY position, id",
+                )?
+                .query_map(params![pair, archived], |r| {
+                    Ok(TickType {
+                        id: r.get(0)?,
+                        tick: r.get(1)?,
+                        icon: r.get(2)?,
+                        archived: r.get(3)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(res)
+        })
+        .await
+        .map_err(AppError::from)
+}
+
+fn validate_name(tick: &str) -> AppResult<()> {
+    if tick.trim().is_empty() || tick.len() > MAX_TICK_NAME_LEN {
+        return Err(AppError::InvalidBody(format!(
+            "tick names must be 1 to {MAX_TICK_NAME_LEN} bytes long"
+        )));
+    }
+    Ok(())
+}
+
+fn validate_icon(icon: &str) -> AppResult<()> {
+    if icon.len() > MAX_TICK_ICON_LEN {
+        return Err(AppError::InvalidBody(format!(
+            "icons must be at most {MAX_TICK_ICON_LEN} bytes long"
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AddTickType {
+    pub tick: String,
+    pub icon: Option<String>,
+}
+
+/// Adds a tick type after every other one, ids are never reused
+pub async fn add_tick_type(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    request: Signed<AddTickType>,
+) -> AppResult<impl IntoResponse> {
+    evaulate(&config, &pair, &request).await?;
+
+    let AddTickType { tick, icon } = request.payload;
+    validate_name(&tick)?;
+    if let Some(icon) = &icon {
+        validate_icon(icon)?;
+    }
+
+    let events_pair = pair.clone();
+    let (id, tick, icon) = config
+        .db
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            let (id, position): (u32, u32) = tx.query_row(
+                "SELECT COALESCE(MAX(id), 0) + 1, COALESCE(MAX(position), 0) + 1 \
+                FROM tick_types WHERE pair = ?1",
+                params![pair],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )?;
+            let Ok(id) = u8::try_from(id) else {
+                return Ok(Err(AppError::TickTypeLimit));
+            };
+
+            tx.execute(
+                "INSERT INTO tick_types (pair, id, value, position, icon) \
+                VALUES (?1, ?2, ?3, ?4, ?5);",
+                params![pair, id, tick, position, icon],
+            )?;
+            tx.commit()?;
+            Ok(Ok((id, tick, icon)))
+        })
+        .await??;
+    publish(&config, &events_pair, PairEvent::TickTypes);
+
+    Ok((
+        StatusCode::CREATED,
+        Json(TickType {
+            id,
+            tick,
+            icon,
+            archived: false,
+        }),
+    ))
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct UpdateTickType {
+    /// New name
+    pub tick: Option<String>,
+    /// New icon, an empty icon removes it
+    pub icon: Option<String>,
+    pub archived: Option<bool>,
+}
+
+/// Renames, archives or sets the icon of a tick type, its ticks keep pointing at it
+pub async fn update_tick_type(
+    State(config): State<Config>,
+    Path((pair, id)): Path<(String, u8)>,
+    request: Signed<UpdateTickType>,
+) -> AppResult<impl IntoResponse> {
+    evaulate(&config, &pair, &request).await?;
+
+    let UpdateTickType {
+        tick,
+        icon,
+        archived,
+    } = request.payload;
+    if let Some(tick) = &tick {
+        validate_name(tick)?;
+    }
+    if let Some(icon) = &icon {
+        validate_icon(icon)?;
+    }
+
+    let events_pair = pair.clone();
+    let updated = config
+        .db
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            let exists = tx
+                .query_row(
+                    "SELECT 1 FROM tick_types WHERE pair = ?1 AND id = ?2",
+                    params![pair, id],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some();
+            if !exists {
+                return Ok(Err(AppError::TickTypeNotFound));
+            }
+
+            if let Some(tick) = tick {
+                tx.execute(
+                    "UPDATE tick_types SET value = ?1 WHERE pair = ?2 AND id = ?3",
+                    params![tick, pair, id],
+                )?;
+            }
+            if let Some(icon) = icon {
+                let icon = (!icon.is_empty()).then_some(icon);
+                tx.execute(
+                    "UPDATE tick_types SET icon = ?1 WHERE pair = ?2 AND id = ?3",
+                    params![icon, pair, id],
+                )?;
+            }
+            if let Some(archived) = archived {
+                tx.execute(
+                    "UPDATE tick_types SET archived = ?1 WHERE pair = ?2 AND id = ?3",
+                    params![archived, pair, id],
+                )?;
+            }
+
+            let updated = tx.query_row(
+                "SELECT id, value, icon, archived FROM tick_types WHERE pair = ?1 AND id = ?2",
+                params![pair, id],
+                |r| {
+                    Ok(TickType {
+                        id: r.get(0)?,
+                        tick: r.get(1)?,
+                        icon: r.get(2)?,
+                        archived: r.get(3)?,
+                    })
+                },
+            )?;
+            tx.commit()?;
+            Ok(Ok(updated))
+        })
+        .await??;
+    publish(&config, &events_pair, PairEvent::TickTypes);
+
+    Ok((StatusCode::CREATED, Json(updated)))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReorderTickTypes {
+    /// Every tick type that isn't archived, in their new order
+    pub ids: Vec<u8>,
+}
+
+pub async fn reorder_tick_types(
+    State(config): State<Config>,
+    Path(pair): Path<String>,
+    request: Signed<ReorderTickTypes>,
+) -> AppResult<impl IntoResponse> {
+    evaulate(&config, &pair, &request).await?;
+
+    let ids = request.payload.ids;
+    let events_pair = pair.clone();
+    let order = ids.clone();
+    config
+        .db
+        .call(move |conn| {
+            let tx = conn.transaction()?;
+            let current: HashSet<u8> = tx
+                .prepare("SELECT id FROM tick_types WHERE pair = ?1 AND archived = 0")?
+                .query_map(params![pair], |r| r.get(0))?
+                .collect::<Result<_, _>>()?;
+            let requested: HashSet<u8> = ids.iter().copied().collect();
+            if requested.len() != ids.len() || requested != current {
+                return Ok(Err(AppError::InvalidBody(
+                    "ids must list every tick type that isn't archived once".to_string(),
+                )));
+            }
+
+            // Archived types keep their relative order after the rest
+            tx.execute(
+                "UPDATE tick_types SET position = position + ?1 WHERE pair = ?2 AND archived = 1",
+                params![ids.len() + 1, pair],
+            )?;
+            {
+                let mut update =
+                    tx.prepare("UPDATE tick_types SET position = ?1 WHERE pair = ?2 AND id = ?3")?;
+                for (position, id) in ids.iter().enumerate() {
+                    update.execute(params![position + 1, pair, id])?;
+                }
+            }
+            tx.commit()?;
+            Ok(Ok(()))
+        })
+        .await??;
+    publish(&config, &events_pair, PairEvent::TickTypes);
+
+    Ok((StatusCode::CREATED, Json(order)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{initialize_db, DefaultPair};
+    use crate::pair::DEFAULT_PAIR;
+    use crate::test_util::signed_post;
+    use companion_proto::FORMAT_VERSION;
+    use std::fs::remove_file;
+    use std::path::PathBuf;
+
+    async fn post(config: &Config, path: &str, body: &str) -> (StatusCode, String) {
+        let (status, body) = signed_post(config, path, body).await;
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    async fn names(config: &Config, archived: bool) -> Vec<String> {
+        query_tick_types(&config.db, DEFAULT_PAIR, archived)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|tick| tick.tick)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn manage_tick_types() {
+        let db_path = PathBuf::from("./manage_tick_types_db");
+        let conn = Connection::open(db_path.clone()).await.unwrap();
+        initialize_db(&conn, &DefaultPair::test()).await.unwrap();
+        let config = Config::new(conn.clone());
+
+        let (status, body) = post(
+            &config,
+            "/pair/default/tick_types",
+            r#"{"tick":"third","icon":"*"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let third: TickType = serde_json::from_str(&body).unwrap();
+        assert_eq!((third.id, third.icon.as_deref()), (3, Some("*")));
+
+        let (status, _) = post(&config, "/pair/default/tick_types", r#"{"tick":" "}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Ticks survive their type being renamed and archived
+        let (status, _) = post(&config, "/pair/default/tick", r#"{"ty":1}"#).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, body) = post(
+            &config,
+            "/pair/default/tick_types/1",
+            r#"{"tick":"renamed","icon":"","archived":true}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let renamed: TickType = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            renamed,
+            TickType {
+                id: 1,
+                tick: "renamed".to_string(),
+                icon: None,
+                archived: true
+            }
+        );
+        let ticks: u32 = conn
+            .call(|conn| Ok(conn.query_row("SELECT COUNT(*) FROM ticks", [], |r| r.get(0))?))
+            .await
+            .unwrap();
+        assert_eq!(ticks, 1);
+
+        let (status, _) = post(&config, "/pair/default/tick", r#"{"ty":1}"#).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, _) = post(&config, "/pair/default/tick_types/9", r#"{"tick":"x"}"#).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        assert_eq!(names(&config, false).await, vec!["second", "third"]);
+        assert_eq!(
+            names(&config, true).await,
+            vec!["renamed", "second", "third"]
+        );
+
+        // Reordering must list every type that isn't archived exactly once
+        for ids in [r#"{"ids":[3]}"#, r#"{"ids":[3,2,2]}"#, r#"{"ids":[3,2,1]}"#] {
+            let (status, _) = post(&config, "/pair/default/tick_types/order", ids).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+        let (status, _) = post(
+            &config,
+            "/pair/default/tick_types/order",
+            r#"{"ids":[3,2]}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            names(&config, true).await,
+            vec!["third", "second", "renamed"]
+        );
+
+        // The compressed list includes archived types for the device to name old ticks
+        let body = get_embedded_ticks(State(config.clone()), Path(DEFAULT_PAIR.to_string()))
+            .await
+            .unwrap();
+        let mut expected = vec![FORMAT_VERSION, 3, 3, 5];
+        expected.extend_from_slice(b"third");
+        expected.extend_from_slice(&[2, 6]);
+        expected.extend_from_slice(b"second");
+        expected.extend_from_slice(&[1, 7]);
+        expected.extend_from_slice(b"renamed");
+        assert_eq!(&body[..], &expected[..]);
+
+        remove_file(db_path.clone()).unwrap();
+    }
+}
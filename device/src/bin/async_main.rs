@@ -1,25 +1,22 @@
 #![no_std]
 #![no_main]
 
-use device::ServerState;
+use device::provisioning::{
+    parse_request, CredentialStore, Credentials, FormError, ProvisioningRequest, AP_ADDRESS,
+    AP_PREFIX_LEN, AP_SSID, BAD_REQUEST_RESPONSE, CREDENTIALS_OFFSET, REQUEST_SIZE, SAVED_RESPONSE,
+    SAVE_FAILED_RESPONSE,
+};
+use device::{draw_dashboard, draw_paused, draw_provisioning, ServerState};
 use display_interface_spi::SPIInterface;
 use embassy_executor::Spawner;
 use embassy_net::{
     dns::DnsSocket,
     tcp::client::{TcpClient, TcpClientState},
-    Stack, StackResources,
-};
-use embassy_time::{Delay, Duration, Timer};
-use embedded_graphics::mono_font::MonoTextStyle;
-use embedded_graphics::text::TextStyle;
-use embedded_graphics::{
-    mono_font::MonoTextStyleBuilder,
-    pixelcolor::BinaryColor,
-    prelude::*,
-    prelude::*,
-    primitives::{PrimitiveStyle, Rectangle},
-    text::{Baseline, Text},
+    tcp::TcpSocket,
+    Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfigV4,
 };
+use embassy_time::{with_timeout, Delay, Duration, Timer};
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*, primitives::Rectangle};
 use embedded_hal::spi::{ErrorType, Operation, SpiBus};
 use embedded_hal_bus::spi::ExclusiveDevice;
 use esp_alloc as _;
@@ -34,9 +31,10 @@ use esp_hal::{
     timer::timg::TimerGroup,
     Blocking,
 };
+use esp_storage::FlashStorage;
 use esp_wifi::wifi::{
-    ClientConfiguration, Configuration, WifiController, WifiDevice, WifiEvent, WifiStaDevice,
-    WifiState,
+    AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, WifiApDevice,
+    WifiController, WifiDevice, WifiEvent, WifiStaDevice, WifiState,
 };
 use esp_wifi::EspWifiController;
 use log::{debug, error, info};
@@ -59,9 +57,10 @@ macro_rules! mk_static {
     }};
 }
 
-// Wifi network credentials
-const SSID: &str = "DemoNetwork";
-const PASSWORD: &str = "nomeacuerdo";
+/// Connection attempts with the stored credentials before asking for new ones
+const CONNECT_ATTEMPTS: u32 = 3;
+/// Stored credentials are tried again after this long, their network may only have been down
+const PROVISIONING_TIMEOUT: Duration = Duration::from_secs(10 * 60);
 
 struct SpiWrapper<'a> {
     spi: Spi<'a, Blocking>,
@@ -95,6 +94,34 @@ impl<'a> embedded_hal::spi::SpiDevice for SpiWrapper<'a> {
     }
 }
 
+/// Lets the dashboard draw on the e-paper, pixels that are on are drawn black
+struct Epd<'a>(&'a mut Display213BlackWhite);
+
+impl Dimensions for Epd<'_> {
+    fn bounding_box(&self) -> Rectangle {
+        self.0.bounding_box()
+    }
+}
+
+impl DrawTarget for Epd<'_> {
+    type Color = BinaryColor;
+    type Error = <Display213BlackWhite as DrawTarget>::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        self.0
+            .draw_iter(pixels.into_iter().map(|Pixel(point, color)| {
+                let color = match color {
+                    BinaryColor::On => Color::Black,
+                    BinaryColor::Off => Color::White,
+                };
+                Pixel(point, color)
+            }))
+    }
+}
+
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) {
     esp_println::logger::init_logger_from_env();
@@ -122,9 +149,71 @@ async fn main(spawner: Spawner) {
         .unwrap()
     );
 
+    info!("Initializing SPI");
+    let spi = Spi::new_with_config(
+        peripherals.SPI2,
+        SpiConfig {
+            frequency: 100.kHz(),
+            mode: SpiMode::Mode0,
+            ..Default::default()
+        },
+    )
+    .with_sck(peripherals.GPIO5)
+    .with_miso(peripherals.GPIO21)
+    .with_mosi(peripherals.GPIO19);
+
+    let cs_pin = Output::new(peripherals.GPIO15, Level::High);
+    let edc = Output::new(peripherals.GPIO33, Level::Low);
+    // The library asks for these but we're not using them
+    let reset = Output::new(peripherals.GPIO13, Level::High);
+    let busy = Input::new(peripherals.GPIO12, Pull::Down);
+
+    let spi_device = ExclusiveDevice::new(spi, cs_pin, Delay).unwrap();
+    let spi_interface = SPIInterface::new(spi_device, edc);
+
+    info!("Setting Up Display Controller");
+    let mut driver = WeActStudio213BlackWhiteDriver::new(spi_interface, busy, reset, Delay);
+    let mut display = Display213BlackWhite::new();
+    info!("Initializing Display Controller");
+    display.set_rotation(weact_studio_epd::graphics::DisplayRotation::Rotate90);
+    driver.init().unwrap();
+
+    info!("Clearing Display");
+    display.clear(Color::White);
+
     let wifi = peripherals.WIFI;
-    let (wifi_interface, controller) =
-        esp_wifi::wifi::new_with_mode(init, wifi, WifiStaDevice).unwrap();
+    let (ap_interface, sta_interface, mut controller) =
+        esp_wifi::wifi::new_ap_sta(init, wifi).unwrap();
+
+    let mut store = CredentialStore::new(FlashStorage::new(), CREDENTIALS_OFFSET);
+    let stored = match store.load() {
+        Ok(Ok(credentials)) => Some(credentials),
+        Ok(Err(err)) => {
+            info!("No stored credentials: {:?}", err);
+            None
+        }
+        Err(err) => {
+            error!("Failed to read the credentials: {:?}", err);
+            None
+        }
+    };
+    let credentials = match stored {
+        Some(credentials) if connect(&mut controller, &credentials).await => credentials,
+        stored => {
+            display.clear(Color::White);
+            let _ = draw_provisioning(&mut Epd(&mut display), &PROFONT_9_POINT);
+            driver.full_update(&display).unwrap();
+            provision(
+                spawner,
+                &mut controller,
+                ap_interface,
+                &mut store,
+                stored.is_some(),
+            )
+            .await;
+            unreachable!("the device restarts once provisioned");
+        }
+    };
 
     let config = embassy_net::Config::dhcpv4(Default::default());
 
@@ -134,14 +223,14 @@ async fn main(spawner: Spawner) {
     let stack: &'static Stack<WifiDevice<'static, WifiStaDevice>> = &*mk_static!(
         Stack<WifiDevice<'_, WifiStaDevice>>,
         Stack::new(
-            wifi_interface,
+            sta_interface,
             config,
             mk_static!(StackResources<3>, StackResources::<3>::new()),
             seed
         )
     );
 
-    spawner.spawn(connection(controller)).ok();
+    spawner.spawn(connection(controller, credentials)).ok();
     spawner.spawn(net_task(stack)).ok();
 
     // Check for link
@@ -168,79 +257,188 @@ async fn main(spawner: Spawner) {
     let dns = DnsSocket::new(stack);
     let mut client = HttpClient::new(&tcp, &dns);
 
-    info!("Initializing SPI");
-    let spi = Spi::new_with_config(
-        peripherals.SPI2,
-        SpiConfig {
-            frequency: 100.kHz(),
-            mode: SpiMode::Mode0,
-            ..Default::default()
-        },
-    )
-    .with_sck(peripherals.GPIO5)
-    .with_miso(peripherals.GPIO21)
-    .with_mosi(peripherals.GPIO19);
+    info!("Creating State");
+    let mut state = ServerState::new(&mut client, &mut response_buffer).await;
 
-    let cs_pin = Output::new(peripherals.GPIO15, Level::High);
-    let edc = Output::new(peripherals.GPIO33, Level::Low);
-    // The library asks for these but we're not using them
-    let reset = Output::new(peripherals.GPIO13, Level::High);
-    let busy = Input::new(peripherals.GPIO12, Pull::Down);
+    loop {
+        // Waits for the server to report a change so the e-paper only refreshes when needed
+        match state.update(&mut client, &mut response_buffer).await {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(err) => {
+                // The previous state stays on screen until the server answers again
+                error!("Could not update the state: {:?}", err);
+                Timer::after(Duration::from_millis(5000)).await;
+                continue;
+            }
+        }
 
-    let spi_device = ExclusiveDevice::new(spi, cs_pin, Delay).unwrap();
-    let spi_interface = SPIInterface::new(spi_device, edc);
+        debug!("Displaying");
+        display.clear(Color::White);
+        let _ = match state.active {
+            true => draw_dashboard(&mut Epd(&mut display), &state, &PROFONT_9_POINT),
+            false => draw_paused(&mut Epd(&mut display), &PROFONT_9_POINT),
+        };
+        driver.full_update(&display).unwrap();
 
-    info!("Setting Up Display Controller");
-    let mut driver = WeActStudio213BlackWhiteDriver::new(spi_interface, busy, reset, Delay);
-    let mut display = Display213BlackWhite::new();
-    info!("Initializing Display Controller");
-    display.set_rotation(weact_studio_epd::graphics::DisplayRotation::Rotate90);
-    driver.init().unwrap();
+        // The e-paper shouldn't be refreshed too often
+        Timer::after(Duration::from_secs(60)).await;
+    }
+}
 
-    info!("Clearing Display");
-    display.clear(Color::White);
+#[embassy_executor::task]
+async fn update_server_state() {}
 
-    info!("Demo write");
-    // display.set_rotation(DisplayRotation::Rotate0);
-    let style = MonoTextStyle::new(&PROFONT_9_POINT, Color::Black);
-    let _ = Text::with_text_style(
-        "Hello World!",
-        Point::new(0, 15),
-        style,
-        TextStyle::default(),
-    )
-    .draw(&mut display);
-    // Rectangle::new(Point::new(0, 0), Size::new(40, 40))
-    //     .into_styled(PrimitiveStyle::with_fill(Color::Black))
-    //     .draw(&mut display)
-    //     .unwrap();
+fn client_configuration(credentials: &Credentials) -> Configuration {
+    let mut config = ClientConfiguration {
+        ssid: credentials.ssid.as_str().try_into().unwrap(),
+        password: credentials.password.as_str().try_into().unwrap(),
+        ..Default::default()
+    };
+    if credentials.is_open() {
+        config.auth_method = AuthMethod::None;
+    }
+    Configuration::Client(config)
+}
 
-    info!("Creating State");
-    let mut state = ServerState::new(&mut client, &mut response_buffer).await;
+/// Joins the network, false when it couldn't after `CONNECT_ATTEMPTS` tries
+async fn connect(controller: &mut WifiController<'static>, credentials: &Credentials) -> bool {
+    controller
+        .set_configuration(&client_configuration(credentials))
+        .unwrap();
+    debug!("Starting wifi");
+    controller.start_async().await.unwrap();
+    for attempt in 1..=CONNECT_ATTEMPTS {
+        debug!("Connecting to {}...", credentials.ssid);
+        match controller.connect_async().await {
+            Ok(_) => {
+                debug!("Wifi connected!");
+                return true;
+            }
+            Err(e) => {
+                error!("Failed to connect to wifi ({attempt}/{CONNECT_ATTEMPTS}): {e:?}");
+                Timer::after(Duration::from_millis(5000)).await
+            }
+        }
+    }
+    false
+}
+
+/// Opens the device's own network and serves the setup form on it until new credentials are
+/// saved, then restarts to join their network
+async fn provision(
+    spawner: Spawner,
+    controller: &mut WifiController<'static>,
+    ap_interface: WifiDevice<'static, WifiApDevice>,
+    store: &mut CredentialStore<FlashStorage>,
+    retry: bool,
+) {
+    info!("Starting the {} access point", AP_SSID);
+    controller.stop_async().await.ok();
+    let ap_config = Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: AP_SSID.try_into().unwrap(),
+        ..Default::default()
+    });
+    controller.set_configuration(&ap_config).unwrap();
+    controller.start_async().await.unwrap();
+
+    let [a, b, c, d] = AP_ADDRESS;
+    let config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::new(a, b, c, d), AP_PREFIX_LEN),
+        gateway: None,
+        dns_servers: Default::default(),
+    });
+    let stack: &'static Stack<WifiDevice<'static, WifiApDevice>> = &*mk_static!(
+        Stack<WifiDevice<'_, WifiApDevice>>,
+        Stack::new(
+            ap_interface,
+            config,
+            mk_static!(StackResources<3>, StackResources::<3>::new()),
+            1234
+        )
+    );
+    spawner.spawn(ap_task(stack)).ok();
+
+    let serve = serve_form(stack, store);
+    let saved = match retry {
+        true => with_timeout(PROVISIONING_TIMEOUT, serve).await.ok(),
+        false => Some(serve.await),
+    };
+    match saved {
+        Some(credentials) => info!("Saved the credentials for {}", credentials.ssid),
+        None => info!("Trying the stored credentials again"),
+    }
+
+    // Lets the last response go out
+    Timer::after(Duration::from_secs(1)).await;
+    esp_hal::reset::software_reset();
+}
 
+/// Answers the form's requests until valid credentials are submitted and saved
+async fn serve_form(
+    stack: &'static Stack<WifiDevice<'static, WifiApDevice>>,
+    store: &mut CredentialStore<FlashStorage>,
+) -> Credentials {
+    let mut rx_buffer = [0; 1536];
+    let mut tx_buffer = [0; 1536];
+    let mut request = [0; REQUEST_SIZE];
     loop {
-        state.update(&mut client, &mut response_buffer).await;
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        if let Err(err) = socket.accept(80).await {
+            error!("Failed to accept a connection: {:?}", err);
+            continue;
+        }
 
-        debug!("Displaying");
-        // TODO: display message in the top left
+        let mut len = 0;
+        let parsed = loop {
+            match socket.read(&mut request[len..]).await {
+                Ok(0) | Err(_) => break None,
+                Ok(read) => len += read,
+            }
+            match parse_request(&request[..len]) {
+                Err(FormError::Incomplete) if len < REQUEST_SIZE => continue,
+                parsed => break Some(parsed),
+            }
+        };
 
-        // TODO: Display the graph
-        // TODO: maybe do a graph where each tick is a different thinking state
-        // TODO: maybe have bar charts, one on each side or all going up
-        // TODO: maybe simply have a count of each one and say whats the latest one
+        let (response, saved) = match parsed {
+            None => {
+                socket.abort();
+                continue;
+            }
+            Some(Ok(ProvisioningRequest::Submit(credentials))) => match store.save(&credentials) {
+                Ok(()) => (SAVED_RESPONSE, Some(credentials)),
+                Err(err) => {
+                    error!("Failed to save the credentials: {:?}", err);
+                    (SAVE_FAILED_RESPONSE, None)
+                }
+            },
+            Some(Ok(request)) => (request.response(), None),
+            Some(Err(err)) => {
+                debug!("Invalid request: {:?}", err);
+                (BAD_REQUEST_RESPONSE, None)
+            }
+        };
 
-        driver.full_update(&display).unwrap();
+        let mut response = response.as_bytes();
+        while !response.is_empty() {
+            match socket.write(response).await {
+                Ok(0) | Err(_) => break,
+                Ok(written) => response = &response[written..],
+            }
+        }
+        socket.flush().await.ok();
+        socket.close();
 
-        // Might need to increase even more
-        Timer::after(Duration::from_secs(600)).await;
+        if let Some(credentials) = saved {
+            return credentials;
+        }
     }
 }
 
 #[embassy_executor::task]
-async fn update_server_state() {}
-
-#[embassy_executor::task]
-async fn connection(mut controller: WifiController<'static>) {
+async fn connection(mut controller: WifiController<'static>, credentials: Credentials) {
     debug!("start connection task");
     debug!("Device capabilities: {:?}", controller.capabilities());
     loop {
@@ -250,12 +448,9 @@ async fn connection(mut controller: WifiController<'static>) {
             Timer::after(Duration::from_millis(5000)).await
         }
         if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = Configuration::Client(ClientConfiguration {
-                ssid: SSID.try_into().unwrap(),
-                password: PASSWORD.try_into().unwrap(),
-                ..Default::default()
-            });
-            controller.set_configuration(&client_config).unwrap();
+            controller
+                .set_configuration(&client_configuration(&credentials))
+                .unwrap();
             debug!("Starting wifi");
             controller.start_async().await.unwrap();
             debug!("Wifi started!");
@@ -276,3 +471,8 @@ async fn connection(mut controller: WifiController<'static>) {
 async fn net_task(stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>) {
     stack.run().await
 }
+
+#[embassy_executor::task]
+async fn ap_task(stack: &'static Stack<WifiDevice<'static, WifiApDevice>>) {
+    stack.run().await
+}
@@ -1,5 +1,8 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
+pub mod provisioning;
+mod render;
 mod state;
 
+pub use render::{draw_dashboard, draw_paused, draw_provisioning};
 pub use state::ServerState;
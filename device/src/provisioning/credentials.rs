@@ -0,0 +1,243 @@
+use embedded_storage::Storage;
+use heapless::String;
+
+/// Longest SSID Wi-Fi allows
+pub const SSID_SIZE: usize = 32;
+/// Longest WPA2 passphrase, 64 characters are taken as a hex key
+pub const PASSWORD_SIZE: usize = 64;
+/// Shortest WPA2 passphrase, empty passwords are for open networks
+pub const MIN_PASSWORD_LEN: usize = 8;
+/// Start of the `nvs` partition of the default partition table, nothing else uses it without
+/// ESP-IDF
+pub const CREDENTIALS_OFFSET: u32 = 0x9000;
+
+/// Marks a stored record, bumped whenever the record's layout changes
+const MAGIC: [u8; 4] = *b"LDC1";
+/// Magic, SSID length and SSID, password length and password, then the checksum
+pub const RECORD_SIZE: usize = MAGIC.len() + 1 + SSID_SIZE + 1 + PASSWORD_SIZE + 4;
+
+#[derive(Debug, PartialEq)]
+pub enum CredentialsError {
+    /// Nothing was stored yet or the flash was erased
+    Missing,
+    /// The record was only partly written or got overwritten
+    Corrupted,
+    /// SSIDs are 1 to 32 bytes
+    InvalidSsid,
+    /// Passwords are empty or 8 to 64 bytes
+    InvalidPassword,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Credentials {
+    pub ssid: String<SSID_SIZE>,
+    pub password: String<PASSWORD_SIZE>,
+}
+
+impl Credentials {
+    pub fn new(ssid: &str, password: &str) -> Result<Self, CredentialsError> {
+        if ssid.is_empty() {
+            return Err(CredentialsError::InvalidSsid);
+        }
+        if !password.is_empty() && password.len() < MIN_PASSWORD_LEN {
+            return Err(CredentialsError::InvalidPassword);
+        }
+        Ok(Self {
+            ssid: ssid.try_into().map_err(|_| CredentialsError::InvalidSsid)?,
+            password: password
+                .try_into()
+                .map_err(|_| CredentialsError::InvalidPassword)?,
+        })
+    }
+
+    /// Whether the network has no password
+    pub fn is_open(&self) -> bool {
+        self.password.is_empty()
+    }
+
+    pub fn to_record(&self) -> [u8; RECORD_SIZE] {
+        let mut record = [0; RECORD_SIZE];
+        let (magic, rest) = record.split_at_mut(MAGIC.len());
+        magic.copy_from_slice(&MAGIC);
+        let rest = write_field(rest, self.ssid.as_bytes(), SSID_SIZE);
+        write_field(rest, self.password.as_bytes(), PASSWORD_SIZE);
+        let sum = checksum(&record[..RECORD_SIZE - 4]);
+        record[RECORD_SIZE - 4..].copy_from_slice(&sum.to_be_bytes());
+        record
+    }
+
+    pub fn from_record(record: &[u8; RECORD_SIZE]) -> Result<Self, CredentialsError> {
+        if record[..MAGIC.len()] != MAGIC {
+            return Err(CredentialsError::Missing);
+        }
+        let (data, sum) = record.split_at(RECORD_SIZE - 4);
+        if checksum(data).to_be_bytes() != sum {
+            return Err(CredentialsError::Corrupted);
+        }
+
+        let (ssid, rest) = read_field(&data[MAGIC.len()..], SSID_SIZE)?;
+        let (password, _) = read_field(rest, PASSWORD_SIZE)?;
+        Self::new(ssid, password).map_err(|_| CredentialsError::Corrupted)
+    }
+}
+
+/// Writes the length and the value padded to `size`, returns what's after it
+fn write_field<'a>(record: &'a mut [u8], value: &[u8], size: usize) -> &'a mut [u8] {
+    let (field, rest) = record.split_at_mut(1 + size);
+    field[0] = value.len() as u8;
+    field[1..1 + value.len()].copy_from_slice(value);
+    rest
+}
+
+fn read_field(record: &[u8], size: usize) -> Result<(&str, &[u8]), CredentialsError> {
+    let (field, rest) = record.split_at(1 + size);
+    let len = field[0] as usize;
+    if len > size {
+        return Err(CredentialsError::Corrupted);
+    }
+    let value =
+        core::str::from_utf8(&field[1..1 + len]).map_err(|_| CredentialsError::Corrupted)?;
+    Ok((value, rest))
+}
+
+/// FNV-1a, enough to notice a record that was only partly written
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// Credentials stored at a fixed offset of the flash
+pub struct CredentialStore<S> {
+    storage: S,
+    offset: u32,
+}
+
+impl<S: Storage> CredentialStore<S> {
+    pub fn new(storage: S, offset: u32) -> Self {
+        Self { storage, offset }
+    }
+
+    /// The stored credentials, an erased or corrupted record is `CredentialsError`
+    pub fn load(&mut self) -> Result<Result<Credentials, CredentialsError>, S::Error> {
+        let mut record = [0; RECORD_SIZE];
+        self.storage.read(self.offset, &mut record)?;
+        Ok(Credentials::from_record(&record))
+    }
+
+    pub fn save(&mut self, credentials: &Credentials) -> Result<(), S::Error> {
+        self.storage.write(self.offset, &credentials.to_record())
+    }
+
+    /// Erases the record so the device asks for credentials on its next start
+    pub fn clear(&mut self) -> Result<(), S::Error> {
+        self.storage.write(self.offset, &[0xff; RECORD_SIZE])
+    }
+
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::convert::Infallible;
+    use embedded_storage::ReadStorage;
+
+    /// Flash that starts erased
+    struct MemoryStorage(std::vec::Vec<u8>);
+
+    impl ReadStorage for MemoryStorage {
+        type Error = Infallible;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Infallible> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.0[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    impl Storage for MemoryStorage {
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Infallible> {
+            let offset = offset as usize;
+            self.0[offset..offset + bytes.len()].copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn validation() {
+        assert!(Credentials::new("home", "").unwrap().is_open());
+        assert!(Credentials::new("home", "12345678").is_ok());
+        assert!(Credentials::new(&"s".repeat(SSID_SIZE), &"p".repeat(PASSWORD_SIZE)).is_ok());
+        assert_eq!(
+            Credentials::new("", "12345678").unwrap_err(),
+            CredentialsError::InvalidSsid
+        );
+        assert_eq!(
+            Credentials::new(&"s".repeat(SSID_SIZE + 1), "").unwrap_err(),
+            CredentialsError::InvalidSsid
+        );
+        assert_eq!(
+            Credentials::new("home", "1234567").unwrap_err(),
+            CredentialsError::InvalidPassword
+        );
+        assert_eq!(
+            Credentials::new("home", &"p".repeat(PASSWORD_SIZE + 1)).unwrap_err(),
+            CredentialsError::InvalidPassword
+        );
+    }
+
+    #[test]
+    fn store() {
+        let mut store = CredentialStore::new(MemoryStorage(std::vec![0xff; 4096]), 1024);
+        assert_eq!(store.load().unwrap(), Err(CredentialsError::Missing));
+
+        let credentials = Credentials::new("Café wifi", "correct horse").unwrap();
+        store.save(&credentials).unwrap();
+        assert_eq!(store.load().unwrap(), Ok(credentials.clone()));
+        let longest = Credentials::new(&"s".repeat(SSID_SIZE), &"p".repeat(PASSWORD_SIZE)).unwrap();
+        store.save(&longest).unwrap();
+        assert_eq!(store.load().unwrap(), Ok(longest));
+
+        // Only the record's bytes are written
+        let storage = store.into_inner();
+        assert!(storage.0[..1024].iter().all(|byte| *byte == 0xff));
+        assert!(storage.0[1024 + RECORD_SIZE..]
+            .iter()
+            .all(|byte| *byte == 0xff));
+
+        let mut store = CredentialStore::new(storage, 1024);
+        store.clear().unwrap();
+        assert_eq!(store.load().unwrap(), Err(CredentialsError::Missing));
+    }
+
+    #[test]
+    fn corrupted_records() {
+        let record = Credentials::new("home", "12345678").unwrap().to_record();
+        // Any flipped bit is noticed
+        for index in MAGIC.len()..RECORD_SIZE {
+            let mut corrupted = record;
+            corrupted[index] ^= 0x10;
+            assert_eq!(
+                Credentials::from_record(&corrupted).unwrap_err(),
+                CredentialsError::Corrupted
+            );
+        }
+
+        // Lengths past their field are refused even with a valid checksum
+        let mut corrupted = record;
+        corrupted[MAGIC.len()] = SSID_SIZE as u8 + 1;
+        let sum = checksum(&corrupted[..RECORD_SIZE - 4]).to_be_bytes();
+        corrupted[RECORD_SIZE - 4..].copy_from_slice(&sum);
+        assert_eq!(
+            Credentials::from_record(&corrupted).unwrap_err(),
+            CredentialsError::Corrupted
+        );
+    }
+}
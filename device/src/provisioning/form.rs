@@ -0,0 +1,286 @@
+use crate::provisioning::{Credentials, CredentialsError, PASSWORD_SIZE, SSID_SIZE};
+use heapless::Vec;
+
+/// Requests bigger than this are refused, the form is a few hundred bytes at most
+pub const REQUEST_SIZE: usize = 1024;
+
+pub const FORM_RESPONSE: &str = "HTTP/1.1 200 OK\r\n\
+Content-Type: text/html; charset=utf-8\r\n\
+Connection: close\r\n\
+\r\n\
+<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+<title>Companion setup</title></head><body><h1>Wi-Fi</h1>\
+<form method=\"post\" action=\"/\">\
+<p><label>Network <input name=\"ssid\" maxlength=\"32\" required></label></p>\
+<p><label>Password <input name=\"password\" type=\"password\" maxlength=\"64\"></label></p>\
+<p><button>Save</button></p></form></body></html>";
+
+pub const SAVED_RESPONSE: &str = "HTTP/1.1 200 OK\r\n\
+Content-Type: text/html; charset=utf-8\r\n\
+Connection: close\r\n\
+\r\n\
+<!DOCTYPE html><html><body><h1>Saved</h1><p>The device restarts and joins the network.</p>\
+</body></html>";
+
+pub const BAD_REQUEST_RESPONSE: &str = "HTTP/1.1 400 Bad Request\r\n\
+Content-Type: text/html; charset=utf-8\r\n\
+Connection: close\r\n\
+\r\n\
+<!DOCTYPE html><html><body><h1>Invalid network</h1>\
+<p>Networks are 1 to 32 bytes long and passwords empty or 8 to 64 bytes long.</p>\
+<p><a href=\"/\">Try again</a></p></body></html>";
+
+pub const SAVE_FAILED_RESPONSE: &str = "HTTP/1.1 500 Internal Server Error\r\n\
+Content-Type: text/html; charset=utf-8\r\n\
+Connection: close\r\n\
+\r\n\
+<!DOCTYPE html><html><body><h1>Couldn't save the network</h1>\
+<p><a href=\"/\">Try again</a></p></body></html>";
+
+pub const NOT_FOUND_RESPONSE: &str = "HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n";
+
+#[derive(Debug, PartialEq)]
+pub enum FormError {
+    /// More of the request has to be read before it can be parsed
+    Incomplete,
+    /// Not an HTTP request this server understands
+    Malformed,
+    /// The submitted credentials can't be used
+    Credentials(CredentialsError),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProvisioningRequest {
+    /// `GET /`
+    Form,
+    /// `POST /` with the form's fields
+    Submit(Credentials),
+    NotFound,
+}
+
+impl ProvisioningRequest {
+    pub fn response(&self) -> &'static str {
+        match self {
+            ProvisioningRequest::Form => FORM_RESPONSE,
+            ProvisioningRequest::Submit(_) => SAVED_RESPONSE,
+            ProvisioningRequest::NotFound => NOT_FOUND_RESPONSE,
+        }
+    }
+}
+
+/// Parses a request read so far, `FormError::Incomplete` until its whole body was read
+pub fn parse_request(raw: &[u8]) -> Result<ProvisioningRequest, FormError> {
+    let Some(header_end) = raw.windows(4).position(|window| window == b"\r\n\r\n") else {
+        return match raw.len() < REQUEST_SIZE {
+            true => Err(FormError::Incomplete),
+            false => Err(FormError::Malformed),
+        };
+    };
+    let head = core::str::from_utf8(&raw[..header_end]).map_err(|_| FormError::Malformed)?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Err(FormError::Malformed);
+    };
+    // Query strings are ignored
+    let path = target.split('?').next().unwrap_or_default();
+
+    let mut content_length = 0;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(FormError::Malformed);
+        };
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse().map_err(|_| FormError::Malformed)?;
+        }
+    }
+
+    let body = &raw[header_end + 4..];
+    // Lengths that overflow don't fit either
+    let fits = (header_end + 4)
+        .checked_add(content_length)
+        .is_some_and(|size| size <= REQUEST_SIZE);
+    if !fits {
+        return Err(FormError::Malformed);
+    }
+    if body.len() < content_length {
+        return Err(FormError::Incomplete);
+    }
+
+    match (method, path) {
+        ("GET", "/") => Ok(ProvisioningRequest::Form),
+        ("POST", "/") => Ok(ProvisioningRequest::Submit(parse_form(
+            &body[..content_length],
+        )?)),
+        _ => Ok(ProvisioningRequest::NotFound),
+    }
+}
+
+/// Reads the `ssid` and `password` fields of an url encoded form, other fields are ignored
+pub fn parse_form(body: &[u8]) -> Result<Credentials, FormError> {
+    let mut ssid: Option<Vec<u8, SSID_SIZE>> = None;
+    let mut password: Vec<u8, PASSWORD_SIZE> = Vec::new();
+    for field in body.split(|byte| *byte == b'&') {
+        let mut parts = field.splitn(2, |byte| *byte == b'=');
+        let name = parts.next().unwrap_or_default();
+        let value = parts.next().unwrap_or_default();
+        match name {
+            b"ssid" => {
+                ssid = Some(
+                    url_decode(value)
+                        .map_err(|_| FormError::Credentials(CredentialsError::InvalidSsid))?,
+                )
+            }
+            b"password" => {
+                password = url_decode(value)
+                    .map_err(|_| FormError::Credentials(CredentialsError::InvalidPassword))?
+            }
+            _ => {}
+        }
+    }
+
+    let ssid = ssid.ok_or(FormError::Credentials(CredentialsError::InvalidSsid))?;
+    let ssid = core::str::from_utf8(&ssid)
+        .map_err(|_| FormError::Credentials(CredentialsError::InvalidSsid))?;
+    let password = core::str::from_utf8(&password)
+        .map_err(|_| FormError::Credentials(CredentialsError::InvalidPassword))?;
+    Credentials::new(ssid, password).map_err(FormError::Credentials)
+}
+
+/// Decodes `+` and `%XX` escapes, fails when the value doesn't fit or an escape is invalid
+fn url_decode<const N: usize>(value: &[u8]) -> Result<Vec<u8, N>, ()> {
+    let mut decoded = Vec::new();
+    let mut bytes = value.iter();
+    while let Some(byte) = bytes.next() {
+        let byte = match byte {
+            b'+' => b' ',
+            b'%' => {
+                let high = hex(*bytes.next().ok_or(())?)?;
+                let low = hex(*bytes.next().ok_or(())?)?;
+                high << 4 | low
+            }
+            byte => *byte,
+        };
+        decoded.push(byte).map_err(|_| ())?;
+    }
+    Ok(decoded)
+}
+
+fn hex(digit: u8) -> Result<u8, ()> {
+    match digit {
+        b'0'..=b'9' => Ok(digit - b'0'),
+        b'a'..=b'f' => Ok(digit - b'a' + 10),
+        b'A'..=b'F' => Ok(digit - b'A' + 10),
+        _ => Err(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn post(body: &str) -> std::string::String {
+        format!(
+            "POST / HTTP/1.1\r\nHost: 192.168.2.1\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    #[test]
+    fn requests() {
+        let get = "GET / HTTP/1.1\r\nHost: 192.168.2.1\r\nAccept: */*\r\n\r\n";
+        assert_eq!(
+            parse_request(get.as_bytes()).unwrap(),
+            ProvisioningRequest::Form
+        );
+        assert_eq!(
+            parse_request(b"GET /?from=phone HTTP/1.1\r\n\r\n").unwrap(),
+            ProvisioningRequest::Form
+        );
+        assert_eq!(
+            parse_request(b"GET /favicon.ico HTTP/1.1\r\n\r\n").unwrap(),
+            ProvisioningRequest::NotFound
+        );
+        assert_eq!(
+            parse_request(post("ssid=home&password=12345678").as_bytes()).unwrap(),
+            ProvisioningRequest::Submit(Credentials::new("home", "12345678").unwrap())
+        );
+        assert_eq!(ProvisioningRequest::Form.response(), FORM_RESPONSE);
+
+        // Requests arrive in pieces, they're only parsed once the whole body is there
+        let request = post("ssid=home&password=");
+        for end in 0..request.len() {
+            assert_eq!(
+                parse_request(&request.as_bytes()[..end]).unwrap_err(),
+                FormError::Incomplete
+            );
+        }
+        assert!(parse_request(request.as_bytes()).is_ok());
+
+        for malformed in [
+            "GET\r\n\r\n",
+            "GET / HTTP/1.1\r\nno colon\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 4096\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n",
+        ] {
+            assert_eq!(
+                parse_request(malformed.as_bytes()).unwrap_err(),
+                FormError::Malformed
+            );
+        }
+        // Headers that never end aren't waited on forever
+        let endless = [b'a'; REQUEST_SIZE];
+        assert_eq!(parse_request(&endless).unwrap_err(), FormError::Malformed);
+    }
+
+    #[test]
+    fn forms() {
+        assert_eq!(
+            parse_form(b"password=correct+horse%21&ssid=Caf%C3%A9+wifi&submit=").unwrap(),
+            Credentials::new("Café wifi", "correct horse!").unwrap()
+        );
+        // Open networks have no password
+        assert!(parse_form(b"ssid=open").unwrap().is_open());
+        assert!(parse_form(b"ssid=open&password=").unwrap().is_open());
+
+        for (body, err) in [
+            ("password=12345678", CredentialsError::InvalidSsid),
+            ("ssid=&password=12345678", CredentialsError::InvalidSsid),
+            ("ssid=%zz", CredentialsError::InvalidSsid),
+            ("ssid=%C3", CredentialsError::InvalidSsid),
+            (
+                "ssid=home&password=1234567",
+                CredentialsError::InvalidPassword,
+            ),
+            (
+                "ssid=home&password=12345678%",
+                CredentialsError::InvalidPassword,
+            ),
+        ] {
+            assert_eq!(
+                parse_form(body.as_bytes()).unwrap_err(),
+                FormError::Credentials(err),
+                "{body}"
+            );
+        }
+
+        // Fields are limited to what Wi-Fi allows once decoded
+        let longest = format!(
+            "ssid={}&password={}",
+            "%41".repeat(SSID_SIZE),
+            "p".repeat(PASSWORD_SIZE)
+        );
+        assert!(parse_form(longest.as_bytes()).is_ok());
+        let long_ssid = format!("ssid={}", "s".repeat(SSID_SIZE + 1));
+        assert_eq!(
+            parse_form(long_ssid.as_bytes()).unwrap_err(),
+            FormError::Credentials(CredentialsError::InvalidSsid)
+        );
+        let long_password = format!("ssid=home&password={}", "p".repeat(PASSWORD_SIZE + 1));
+        assert_eq!(
+            parse_form(long_password.as_bytes()).unwrap_err(),
+            FormError::Credentials(CredentialsError::InvalidPassword)
+        );
+    }
+}
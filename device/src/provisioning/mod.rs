@@ -0,0 +1,15 @@
+//! Wi-Fi credentials are kept in flash, when the device can't connect with them it opens an
+//! access point serving a form to enter new ones.
+
+mod credentials;
+mod form;
+
+pub use credentials::*;
+pub use form::*;
+
+/// Network the device opens while it has no working credentials
+pub const AP_SSID: &str = "Companion Setup";
+/// The device's address on its own network, there's no DHCP so phones need a static address in
+/// the same /24
+pub const AP_ADDRESS: [u8; 4] = [192, 168, 2, 1];
+pub const AP_PREFIX_LEN: u8 = 24;
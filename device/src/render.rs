@@ -0,0 +1,479 @@
+//! Draws the server state on a black and white display, pixels that are `On` are drawn black.
+//!
+//! Nothing here clears the display, it's expected to be cleared to `Off` before drawing.
+
+use crate::provisioning::{AP_ADDRESS, AP_SSID};
+use crate::state::{ServerState, TickStats, TICK_SIZE};
+use core::fmt::Write;
+use embedded_graphics::mono_font::{MonoFont, MonoTextStyle};
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{Line, PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Baseline, Text};
+use heapless::String;
+
+/// Share of the display's width the message takes, the chart takes the rest
+const MESSAGE_WIDTH_PERCENT: u32 = 60;
+/// Empty space around every section
+const MARGIN: i32 = 2;
+/// Characters of a tick type's name shown next to its bar
+const CHART_NAME_CHARS: usize = 5;
+/// Pixels between rows of the chart
+const ROW_SPACING: u32 = 2;
+
+/// Splits text into lines of at most `width` characters, breaking on spaces when possible
+pub struct Wrap<'a> {
+    rest: &'a str,
+    width: usize,
+}
+
+pub fn wrap(text: &str, width: usize) -> Wrap<'_> {
+    Wrap {
+        rest: text,
+        width: width.max(1),
+    }
+}
+
+impl<'a> Iterator for Wrap<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let rest = self.rest.trim_start_matches(' ');
+        if rest.is_empty() {
+            return None;
+        }
+
+        let (paragraph, next) = match rest.find('\n') {
+            Some(end) => (&rest[..end], &rest[end + 1..]),
+            None => (rest, ""),
+        };
+        // Byte index of the first character that doesn't fit
+        let Some((limit, _)) = paragraph.char_indices().nth(self.width) else {
+            self.rest = next;
+            return Some(paragraph.trim_end());
+        };
+
+        // A space right after the last character that fits is still a clean break, words longer
+        // than a line are cut
+        let end = match paragraph[limit..].starts_with(' ') {
+            true => limit,
+            false => match paragraph[..limit].rfind(' ') {
+                Some(space) if space > 0 => space,
+                _ => limit,
+            },
+        };
+        self.rest = &rest[end..];
+        Some(paragraph[..end].trim_end())
+    }
+}
+
+fn char_width(font: &MonoFont) -> u32 {
+    font.character_size.width + font.character_spacing
+}
+
+fn line_height(font: &MonoFont) -> u32 {
+    font.character_size.height
+}
+
+/// How many characters of the font fit in the width
+fn chars_in(width: u32, font: &MonoFont) -> usize {
+    ((width + font.character_spacing) / char_width(font)) as usize
+}
+
+fn text<D>(display: &mut D, text: &str, position: Point, font: &MonoFont) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let style = MonoTextStyle::new(font, BinaryColor::On);
+    Text::with_baseline(text, position, style, Baseline::Top).draw(display)?;
+    Ok(())
+}
+
+/// Shown instead of the dashboard while the pair is paused
+pub fn draw_paused<D>(display: &mut D, font: &MonoFont) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let area = display.bounding_box();
+    let label = "Paused";
+    let width = label.len() as u32 * char_width(font);
+    let position = area.center() - Point::new(width as i32 / 2, line_height(font) as i32 / 2);
+    text(display, label, position, font)
+}
+
+/// Shown while the device has no working Wi-Fi credentials, tells how to reach the setup form
+pub fn draw_provisioning<D>(display: &mut D, font: &MonoFont) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let [a, b, c, d] = AP_ADDRESS;
+    let mut url: String<32> = String::new();
+    write!(url, "http://{a}.{b}.{c}.{d}").ok();
+    // There's no DHCP on the device's network
+    let mut static_ip: String<32> = String::new();
+    write!(static_ip, "like {a}.{b}.{c}.{}", d.wrapping_add(1)).ok();
+
+    let area = display.bounding_box();
+    let lines = [
+        "Wi-Fi setup",
+        "",
+        "Join the network",
+        AP_SSID,
+        "and open",
+        url.as_str(),
+        "",
+        "Set a static IP on your phone",
+        static_ip.as_str(),
+    ];
+    let mut position = area.top_left + Point::new(MARGIN, MARGIN);
+    for line in lines {
+        text(display, line, position, font)?;
+        position.y += line_height(font) as i32;
+    }
+    Ok(())
+}
+
+/// The message on the left with the latest tick under it and the tick counts charted on the right
+pub fn draw_dashboard<D>(
+    display: &mut D,
+    state: &ServerState,
+    font: &MonoFont,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let area = display.bounding_box();
+    let message_width = area.size.width * MESSAGE_WIDTH_PERCENT / 100;
+    let line = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+
+    // The latest tick takes the message's last line
+    let latest_y = area.top_left.y + area.size.height as i32 - MARGIN - line_height(font) as i32;
+    let message_area = Rectangle::new(
+        area.top_left + Point::new(MARGIN, MARGIN),
+        Size::new(
+            message_width.saturating_sub(2 * MARGIN as u32),
+            (latest_y - MARGIN * 2 - area.top_left.y).max(0) as u32,
+        ),
+    );
+    draw_message(display, &state.message, message_area, font)?;
+
+    Line::new(
+        Point::new(area.top_left.x, latest_y - MARGIN),
+        Point::new(area.top_left.x + message_width as i32, latest_y - MARGIN),
+    )
+    .into_styled(line)
+    .draw(display)?;
+    draw_latest_tick(
+        display,
+        state,
+        Point::new(area.top_left.x + MARGIN, latest_y),
+        font,
+    )?;
+
+    let divider_x = area.top_left.x + message_width as i32;
+    Line::new(
+        Point::new(divider_x, area.top_left.y),
+        Point::new(divider_x, area.top_left.y + area.size.height as i32 - 1),
+    )
+    .into_styled(line)
+    .draw(display)?;
+
+    let chart_area = Rectangle::new(
+        Point::new(divider_x + 1 + MARGIN, area.top_left.y + MARGIN),
+        Size::new(
+            area.size
+                .width
+                .saturating_sub(message_width + 1 + 2 * MARGIN as u32),
+            area.size.height.saturating_sub(2 * MARGIN as u32),
+        ),
+    );
+    draw_chart(display, state, chart_area, font)
+}
+
+/// Word wraps the message inside the area, lines that don't fit are left out
+pub fn draw_message<D>(
+    display: &mut D,
+    message: &str,
+    area: Rectangle,
+    font: &MonoFont,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let max_lines = (area.size.height / line_height(font)) as usize;
+    let width = chars_in(area.size.width, font);
+    for (i, line) in wrap(message, width).take(max_lines).enumerate() {
+        let position = area.top_left + Point::new(0, (i as u32 * line_height(font)) as i32);
+        text(display, line, position, font)?;
+    }
+    Ok(())
+}
+
+/// The type and time of the latest tick today
+pub fn draw_latest_tick<D>(
+    display: &mut D,
+    state: &ServerState,
+    position: Point,
+    font: &MonoFont,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let mut line: String<{ TICK_SIZE + 16 }> = String::new();
+    match state.tick_history.last() {
+        Some(tick) => {
+            line.push_str("Last: ").ok();
+            push_tick_name(&mut line, state, tick.type_id);
+            write!(line, " {:02}:{:02}", tick.time.hour, tick.time.minute).ok();
+        }
+        None => {
+            line.push_str("No ticks today").ok();
+        }
+    }
+    text(display, &line, position, font)
+}
+
+fn push_tick_name<const N: usize>(line: &mut String<N>, state: &ServerState, id: u8) {
+    match state.tick_name(id) {
+        Some(name) => {
+            for c in name.chars() {
+                if line.push(c).is_err() {
+                    break;
+                }
+            }
+        }
+        None => {
+            write!(line, "#{id}").ok();
+        }
+    }
+}
+
+/// Total ticks of the tick type at `index` over every bucket
+fn total(stats: &TickStats, index: usize) -> u32 {
+    stats
+        .buckets
+        .iter()
+        .filter_map(|bucket| bucket.get(index))
+        .map(|count| *count as u32)
+        .sum()
+}
+
+/// A row for every tick type with its name, a bar as long as its count and the count itself
+pub fn draw_chart<D>(
+    display: &mut D,
+    state: &ServerState,
+    area: Rectangle,
+    font: &MonoFont,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let stats = &state.tick_stats;
+    text(display, "Last 7 days", area.top_left, font)?;
+
+    let row_height = line_height(font) + ROW_SPACING;
+    let max_rows = (area.size.height / row_height).saturating_sub(1) as usize;
+    let max = (0..stats.type_ids.len())
+        .map(|index| total(stats, index))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    // The count takes up to three characters after the bar
+    let name_width = CHART_NAME_CHARS as u32 * char_width(font);
+    let count_width = 3 * char_width(font);
+    let bar_width = area
+        .size
+        .width
+        .saturating_sub(name_width + count_width + 2 * MARGIN as u32);
+
+    for (index, id) in stats.type_ids.iter().enumerate().take(max_rows) {
+        let y = area.top_left.y + ((index as u32 + 1) * row_height) as i32;
+
+        let mut name: String<{ TICK_SIZE + 4 }> = String::new();
+        push_tick_name(&mut name, state, *id);
+        let name = match name.char_indices().nth(CHART_NAME_CHARS) {
+            Some((end, _)) => &name[..end],
+            None => &name,
+        };
+        text(display, name, Point::new(area.top_left.x, y), font)?;
+
+        let count = total(stats, index);
+        let length = bar_width * count.min(max) / max;
+        let bar_x = area.top_left.x + (name_width + MARGIN as u32) as i32;
+        if length > 0 {
+            Rectangle::new(
+                Point::new(bar_x, y + 1),
+                Size::new(length, line_height(font).saturating_sub(2)),
+            )
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+            .draw(display)?;
+        }
+
+        let mut label: String<10> = String::new();
+        write!(label, "{count}").ok();
+        let label_x = bar_x + (length + MARGIN as u32) as i32;
+        text(display, &label, Point::new(label_x, y), font)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::{HistoryTick, StatsBucket, TickType};
+    use companion_proto::Time;
+    use embedded_graphics::mono_font::ascii::FONT_6X10;
+    use std::path::PathBuf;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// The e-paper's size once rotated
+    const WIDTH: u32 = 250;
+    const HEIGHT: u32 = 122;
+
+    /// In memory display that can be compared against a text image, `#` is on and `.` is off
+    struct Framebuffer {
+        pixels: Vec<bool>,
+    }
+
+    impl Framebuffer {
+        fn new() -> Self {
+            Self {
+                pixels: vec![false; (WIDTH * HEIGHT) as usize],
+            }
+        }
+
+        fn to_text(&self) -> std::string::String {
+            self.pixels
+                .chunks(WIDTH as usize)
+                .map(|row| {
+                    let mut line: std::string::String =
+                        row.iter().map(|on| if *on { '#' } else { '.' }).collect();
+                    line.push('\n');
+                    line
+                })
+                .collect()
+        }
+    }
+
+    impl OriginDimensions for Framebuffer {
+        fn size(&self) -> Size {
+            Size::new(WIDTH, HEIGHT)
+        }
+    }
+
+    impl DrawTarget for Framebuffer {
+        type Color = BinaryColor;
+        type Error = core::convert::Infallible;
+
+        fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+        where
+            I: IntoIterator<Item = Pixel<Self::Color>>,
+        {
+            for Pixel(point, color) in pixels {
+                if point.x < 0 || point.y < 0 || point.x >= WIDTH as i32 || point.y >= HEIGHT as i32
+                {
+                    continue;
+                }
+                self.pixels[(point.y as u32 * WIDTH + point.x as u32) as usize] = color.is_on();
+            }
+            Ok(())
+        }
+    }
+
+    /// Compares the framebuffer with its golden image, `UPDATE_GOLDEN=1` writes it instead
+    fn assert_golden(framebuffer: &Framebuffer, name: &str) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(name);
+        let rendered = framebuffer.to_text();
+        if std::env::var("UPDATE_GOLDEN").is_ok() {
+            std::fs::write(&path, &rendered).unwrap();
+            return;
+        }
+
+        let golden = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            golden == rendered,
+            "{name} doesn't match its golden image, rendered:\n{rendered}"
+        );
+    }
+
+    fn state() -> ServerState {
+        let mut state = ServerState::empty();
+        state.message = "Good morning! Don't forget the umbrella, it's supposed to rain all \
+            afternoon. Supercalifragilisticexpialidocious"
+            .try_into()
+            .unwrap();
+        for (id, name) in [(1, "hug"), (2, "thinking of you"), (3, "coffee")] {
+            state
+                .ticks
+                .push(TickType {
+                    id,
+                    name: name.try_into().unwrap(),
+                })
+                .unwrap();
+        }
+        for (type_id, hour, minute) in [(1, 8, 5), (2, 9, 30), (1, 13, 7)] {
+            state
+                .tick_history
+                .push(HistoryTick {
+                    type_id,
+                    time: Time { hour, minute },
+                })
+                .unwrap();
+        }
+        // The 4th type is missing from the tick types so it's shown by its id
+        state.tick_stats.type_ids = [1, 2, 3, 4].as_slice().try_into().unwrap();
+        for counts in [[3, 1, 0, 1], [5, 2, 0, 0], [2, 4, 0, 0]] {
+            let bucket: StatsBucket = counts.as_slice().try_into().unwrap();
+            state.tick_stats.buckets.push(bucket).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn wrapping() {
+        let lines: Vec<&str> = wrap("the quick  brown fox\n\njumps overthelazydog", 9).collect();
+        assert_eq!(
+            lines,
+            vec!["the quick", "brown fox", "", "jumps", "overthela", "zydog"]
+        );
+        let lines: Vec<&str> = wrap("exactly 9 fits", 9).collect();
+        assert_eq!(lines, vec!["exactly 9", "fits"]);
+        assert_eq!(wrap("", 9).count(), 0);
+        assert_eq!(
+            wrap("ñandú ñandú", 5).collect::<Vec<_>>(),
+            vec!["ñandú", "ñandú"]
+        );
+    }
+
+    #[test]
+    fn dashboard_golden() {
+        let mut framebuffer = Framebuffer::new();
+        draw_dashboard(&mut framebuffer, &state(), &FONT_6X10).unwrap();
+        assert_golden(&framebuffer, "dashboard.txt");
+    }
+
+    #[test]
+    fn empty_golden() {
+        let mut framebuffer = Framebuffer::new();
+        draw_dashboard(&mut framebuffer, &ServerState::empty(), &FONT_6X10).unwrap();
+        assert_golden(&framebuffer, "empty.txt");
+    }
+
+    #[test]
+    fn paused_golden() {
+        let mut framebuffer = Framebuffer::new();
+        draw_paused(&mut framebuffer, &FONT_6X10).unwrap();
+        assert_golden(&framebuffer, "paused.txt");
+    }
+
+    #[test]
+    fn provisioning_golden() {
+        let mut framebuffer = Framebuffer::new();
+        draw_provisioning(&mut framebuffer, &FONT_6X10).unwrap();
+        assert_golden(&framebuffer, "provisioning.txt");
+    }
+}
@@ -0,0 +1,32 @@
+use crate::state::Etag;
+
+/// Why a response couldn't be fetched, the state keeps what it had and the query is retried
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    /// The request couldn't be sent or its response read, ex: the connection dropped
+    Request,
+    /// The body doesn't fit in the buffer it's read into
+    TooLarge,
+}
+
+/// Where the state's responses come from, the device fetches them from the server and tests
+/// answer them directly
+// Everything runs on a single executor, the futures don't need to be `Send`
+#[allow(async_fn_in_trait)]
+pub trait Fetch {
+    /// The body of `query`, padded with zeros up to `RX` bytes
+    async fn query<const RX: usize>(
+        &mut self,
+        response_buffer: &mut [u8],
+        query: &str,
+    ) -> Result<[u8; RX], QueryError>;
+
+    /// Same as [`Fetch::query`] but returns nothing when the response still matches the etag,
+    /// the etag is only replaced once the new response was read
+    async fn query_if_changed<const RX: usize>(
+        &mut self,
+        response_buffer: &mut [u8],
+        query: &str,
+        etag: &mut Etag,
+    ) -> Result<Option<[u8; RX]>, QueryError>;
+}
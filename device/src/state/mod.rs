@@ -1,20 +1,32 @@
+// Queries are only made on the device, host builds only use the state for rendering
+#![cfg_attr(not(target_arch = "xtensa"), allow(dead_code))]
+
+mod fetch;
+#[cfg(target_arch = "xtensa")]
+mod query;
 mod server_state;
-mod tick_history;
-mod time;
-
-use embassy_net::dns::DnsSocket;
-use embassy_net::tcp::client::TcpClient;
-use esp_wifi::wifi::{WifiDevice, WifiStaDevice};
-use reqwless::client::HttpClient;
-use reqwless::request::Method;
+
+pub use companion_proto::{DecodeError, HistoryTick};
+pub use fetch::*;
+use heapless::String;
+#[cfg(target_arch = "xtensa")]
+pub use query::*;
 pub use server_state::*;
-pub use tick_history::*;
-pub use time::*;
 
 // Message queries
-pub const MESSAGE_QUERY: &str = "http://24.144.124.202:3000/message";
-pub const TICK_QUERY: &str = "http://24.144.124.202:3000/ticks";
-pub const TICK_HISTORY_QUERY: &str = "http://24.144.124.202:3000/compressed_tick_history";
+pub const MESSAGE_QUERY: &str = "http://24.144.124.202:3000/pair/default/message";
+pub const TICK_QUERY: &str = "http://24.144.124.202:3000/pair/default/compressed_ticks";
+pub const TICK_HISTORY_QUERY: &str =
+    "http://24.144.124.202:3000/pair/default/compressed_tick_history";
+pub const TICK_STATS_QUERY: &str =
+    "http://24.144.124.202:3000/pair/default/compressed_tick_stats?bucket=day";
+// Waits for the pair's revision to go past the one appended to it
+pub const CHANGES_QUERY: &str = "http://24.144.124.202:3000/pair/default/changes?timeout=60&since=";
+pub const CHANGES_QUERY_SIZE: usize = CHANGES_QUERY.len() + 20;
+
+// Etags are a quoted 16 character hash
+pub const ETAG_SIZE: usize = 18;
+pub type Etag = String<ETAG_SIZE>;
 
 // Message size
 pub const MESSAGE_SIZE: usize = 1024;
@@ -22,35 +34,18 @@ pub const MESSAGE_SIZE: usize = 1024;
 // Tick info
 pub const TICK_RX_ALLOC: usize = 1024;
 pub const TICK_SIZE: usize = 25;
-pub const TICK_ALLOC: usize = 10;
+// The server leaves tick types past these out of the stats
+pub const TICK_ALLOC: usize = companion_proto::DEVICE_TICK_TYPES;
+pub type TickType = companion_proto::TickType<TICK_SIZE>;
 
 // Tick history
 pub const TICK_HISTORY_RX_ALLOC: usize = 2048;
-// We calculate size by getting tick history alloc substracting 2 (returned ticks) and dividing by 3 (tick size)
-pub const TICK_HISTORY_SIZE: usize = (TICK_HISTORY_RX_ALLOC - 2) / 3;
-
-type Client<'a, 'b, 'c, 'd, 'e, const WIFIRX: usize> = HttpClient<
-    'a,
-    TcpClient<'b, WifiDevice<'c, WifiStaDevice>, 1, WIFIRX>,
-    DnsSocket<'d, WifiDevice<'e, WifiStaDevice>>,
->;
-
-pub async fn query<const RX: usize, const WIFIRX: usize>(
-    client: &mut Client<'_, '_, '_, '_, '_, WIFIRX>,
-    response_buffer: &mut [u8],
-    query: &str,
-) -> [u8; RX] {
-    let mut query = client.request(Method::GET, query).await.unwrap();
-
-    let response = query.send(response_buffer).await.unwrap();
-
-    let mut body_buffer = [0; RX];
-    response
-        .body()
-        .reader()
-        .read_to_end(&mut body_buffer)
-        .await
-        .unwrap();
-
-    body_buffer
-}
+// We calculate size by getting tick history alloc substracting 4 (format version, active flag and returned ticks) and dividing by 3 (tick size)
+pub const TICK_HISTORY_SIZE: usize = (TICK_HISTORY_RX_ALLOC - 4) / 3;
+
+// Tick stats, one bucket for each day of the week
+pub const TICK_STATS_BUCKETS: usize = 7;
+pub const TICK_STATS_RX_ALLOC: usize =
+    companion_proto::tick_stats_size(TICK_ALLOC, TICK_STATS_BUCKETS);
+pub type TickStats = companion_proto::TickStats<TICK_ALLOC, TICK_STATS_BUCKETS>;
+pub type StatsBucket = companion_proto::StatsBucket<TICK_ALLOC>;
@@ -0,0 +1,104 @@
+use crate::state::{Etag, Fetch, QueryError};
+use embassy_net::dns::DnsSocket;
+use embassy_net::tcp::client::TcpClient;
+use esp_wifi::wifi::{WifiDevice, WifiStaDevice};
+use log::warn;
+use reqwless::client::HttpClient;
+use reqwless::request::{Method, RequestBuilder};
+use reqwless::response::Status;
+
+pub type Client<'a, 'b, 'c, 'd, 'e, const WIFIRX: usize> = HttpClient<
+    'a,
+    TcpClient<'b, WifiDevice<'c, WifiStaDevice>, 1, WIFIRX>,
+    DnsSocket<'d, WifiDevice<'e, WifiStaDevice>>,
+>;
+
+impl From<reqwless::Error> for QueryError {
+    fn from(err: reqwless::Error) -> Self {
+        match err {
+            reqwless::Error::BufferTooSmall => QueryError::TooLarge,
+            err => {
+                warn!("Request failed: {:?}", err);
+                QueryError::Request
+            }
+        }
+    }
+}
+
+pub async fn query<const RX: usize, const WIFIRX: usize>(
+    client: &mut Client<'_, '_, '_, '_, '_, WIFIRX>,
+    response_buffer: &mut [u8],
+    query: &str,
+) -> Result<[u8; RX], QueryError> {
+    let mut query = client.request(Method::GET, query).await?;
+
+    let response = query.send(response_buffer).await?;
+
+    let mut body_buffer = [0; RX];
+    response
+        .body()
+        .reader()
+        .read_to_end(&mut body_buffer)
+        .await?;
+
+    Ok(body_buffer)
+}
+
+/// Same as [`query`] but returns nothing when the response still matches the etag, the etag is
+/// updated to the one of the new response once it was read
+pub async fn query_if_changed<const RX: usize, const WIFIRX: usize>(
+    client: &mut Client<'_, '_, '_, '_, '_, WIFIRX>,
+    response_buffer: &mut [u8],
+    query: &str,
+    etag: &mut Etag,
+) -> Result<Option<[u8; RX]>, QueryError> {
+    let previous = etag.clone();
+    let headers = [("If-None-Match", previous.as_str())];
+    let mut query = client
+        .request(Method::GET, query)
+        .await?
+        .headers(if previous.is_empty() { &[] } else { &headers });
+
+    let response = query.send(response_buffer).await?;
+    if matches!(response.status, Status::NotModified) {
+        return Ok(None);
+    }
+
+    let mut new_etag = Etag::new();
+    for (name, value) in response.headers() {
+        if name.eq_ignore_ascii_case("etag") {
+            let value = core::str::from_utf8(value).unwrap_or_default();
+            new_etag.push_str(value).ok();
+        }
+    }
+
+    // A body that can't be read keeps the previous etag so it's fetched again
+    let mut body_buffer = [0; RX];
+    response
+        .body()
+        .reader()
+        .read_to_end(&mut body_buffer)
+        .await?;
+    *etag = new_etag;
+
+    Ok(Some(body_buffer))
+}
+
+impl<const WIFIRX: usize> Fetch for Client<'_, '_, '_, '_, '_, WIFIRX> {
+    async fn query<const RX: usize>(
+        &mut self,
+        response_buffer: &mut [u8],
+        query: &str,
+    ) -> Result<[u8; RX], QueryError> {
+        self::query(self, response_buffer, query).await
+    }
+
+    async fn query_if_changed<const RX: usize>(
+        &mut self,
+        response_buffer: &mut [u8],
+        query: &str,
+        etag: &mut Etag,
+    ) -> Result<Option<[u8; RX]>, QueryError> {
+        self::query_if_changed(self, response_buffer, query, etag).await
+    }
+}
@@ -1,58 +1,437 @@
 use crate::state::{
-    query, Client, TickHistory, MESSAGE_QUERY, MESSAGE_SIZE, TICK_ALLOC, TICK_HISTORY_QUERY,
-    TICK_HISTORY_RX_ALLOC, TICK_HISTORY_SIZE, TICK_QUERY, TICK_RX_ALLOC, TICK_SIZE,
+    DecodeError, Etag, Fetch, HistoryTick, QueryError, TickStats, TickType, CHANGES_QUERY,
+    CHANGES_QUERY_SIZE, MESSAGE_QUERY, MESSAGE_SIZE, TICK_ALLOC, TICK_HISTORY_QUERY,
+    TICK_HISTORY_RX_ALLOC, TICK_HISTORY_SIZE, TICK_QUERY, TICK_RX_ALLOC, TICK_STATS_QUERY,
+    TICK_STATS_RX_ALLOC,
 };
+use companion_proto::{decode_tick_history, decode_tick_stats, decode_tick_types};
+use core::fmt::Write;
 use heapless::{String, Vec};
-use log::debug;
-
-type Tick = String<TICK_SIZE>;
+use log::{debug, warn};
 
+#[derive(Default)]
 pub struct ServerState {
+    /// The pair is paused while this is off, nothing but the paused screen should be shown
+    pub active: bool,
     pub message: String<MESSAGE_SIZE>,
-    pub ticks: Vec<Tick, TICK_ALLOC>,
-    pub tick_history: Vec<TickHistory, TICK_HISTORY_SIZE>,
+    pub ticks: Vec<TickType, TICK_ALLOC>,
+    pub tick_history: Vec<HistoryTick, TICK_HISTORY_SIZE>,
+    /// Daily tick counts for the bar chart
+    pub tick_stats: TickStats,
+    /// The pair's revision the stats were fetched at
+    pub revision: Option<u64>,
+    /// The history changes when the day rolls over even if the revision doesn't
+    pub tick_history_etag: Etag,
+    pub ticks_etag: Etag,
+    /// The message changes when it expires even if the revision doesn't
+    pub message_etag: Etag,
 }
 
 impl ServerState {
-    pub async fn new<const WIFIRX: usize>(
-        client: &mut Client<'_, '_, '_, '_, '_, WIFIRX>,
-        response_buffer: &mut [u8],
-    ) -> Self {
-        let _raw_ticks: [u8; TICK_RX_ALLOC] = query(client, response_buffer, TICK_QUERY).await;
-
+    /// The state before anything was fetched
+    pub fn empty() -> Self {
         Self {
-            message: String::new(),
-            ticks: Vec::new(),
-            tick_history: Vec::new(),
+            active: true,
+            ..Default::default()
         }
     }
 
-    pub async fn update<const WIFIRX: usize>(
+    /// Name of a tick type, if it's known
+    pub fn tick_name(&self, id: u8) -> Option<&str> {
+        self.ticks
+            .iter()
+            .find(|tick| tick.id == id)
+            .map(|tick| tick.name.as_str())
+    }
+
+    /// Reads the compressed tick types, the state is left as is when they can't be read
+    pub fn read_ticks(&mut self, raw_ticks: &[u8]) -> Result<(), DecodeError> {
+        self.ticks = decode_tick_types(raw_ticks)?;
+        Ok(())
+    }
+
+    /// Reads the compressed tick stats, the state is left as is when they can't be read
+    pub fn read_tick_stats(&mut self, raw_stats: &[u8]) -> Result<(), DecodeError> {
+        (self.active, self.tick_stats) = decode_tick_stats(raw_stats)?;
+        Ok(())
+    }
+
+    /// Reads the compressed tick history, the state is left as is when it can't be read
+    pub fn read_tick_history(&mut self, raw_ticks: &[u8]) -> Result<(), DecodeError> {
+        // The history is refetched whenever quiet hours start or end
+        (self.active, self.tick_history) = decode_tick_history(raw_ticks)?;
+        Ok(())
+    }
+}
+
+impl ServerState {
+    pub async fn new(client: &mut impl Fetch, response_buffer: &mut [u8]) -> Self {
+        let mut state = Self::empty();
+        // Tick types that can't be fetched now are fetched on the first update
+        match client
+            .query_if_changed::<TICK_RX_ALLOC>(response_buffer, TICK_QUERY, &mut state.ticks_etag)
+            .await
+        {
+            Ok(Some(raw_ticks)) => {
+                if let Err(err) = state.read_ticks(&raw_ticks) {
+                    warn!("Invalid tick types: {:?}", err);
+                    state.ticks_etag.clear();
+                }
+            }
+            Ok(None) => {}
+            Err(err) => warn!("Could not fetch tick types: {:?}", err),
+        }
+        state
+    }
+
+    /// Waits for the pair to change and fetches whatever changed, returns whether anything did.
+    /// Responses that couldn't be fetched keep the previous state and are fetched again on the
+    /// next update
+    pub async fn update(
         &mut self,
-        client: &mut Client<'_, '_, '_, '_, '_, WIFIRX>,
+        client: &mut impl Fetch,
         response_buffer: &mut [u8],
-    ) {
-        let raw_message: [u8; MESSAGE_SIZE] = query(client, response_buffer, MESSAGE_QUERY).await;
-        let message = core::str::from_utf8(&raw_message).unwrap();
-        self.message = message.parse().unwrap();
-        debug!("Message: {}", self.message);
-
-        let raw_ticks: [u8; TICK_HISTORY_RX_ALLOC] =
-            query(client, response_buffer, TICK_HISTORY_QUERY).await;
-        let mut iterator = raw_ticks.iter();
-        let size_bytes: [u8; 2] = [*iterator.next().unwrap(), *iterator.next().unwrap()];
-        let size = u16::from_be_bytes(size_bytes);
-        debug!("Tick History Size: {size}");
-        self.tick_history.clear();
-        for _ in 0..size {
-            let tick = TickHistory::read(&mut iterator);
-            debug!(
-                "\n\tId: {}\n\tTime: {}:{}\n",
-                tick.type_id, tick.time.hour, tick.time.minute
-            );
-            self.tick_history
-                .push(tick)
-                .expect("Too many ticks returned");
+    ) -> Result<bool, QueryError> {
+        let mut changes_query: String<CHANGES_QUERY_SIZE> = String::new();
+        write!(
+            changes_query,
+            "{CHANGES_QUERY}{}",
+            self.revision.unwrap_or(0)
+        )
+        .unwrap();
+        let raw_revision: [u8; 20] = client.query(response_buffer, &changes_query).await?;
+        let revision = core::str::from_utf8(&raw_revision)
+            .ok()
+            .and_then(|revision| revision.trim_end_matches('\0').parse().ok());
+        debug!("Revision: {:?}", revision);
+
+        let mut changed = false;
+        if revision.is_none() || revision != self.revision {
+            let raw_stats: [u8; TICK_STATS_RX_ALLOC] =
+                client.query(response_buffer, TICK_STATS_QUERY).await?;
+            // Tick types are renamed and added without the stats changing
+            let raw_ticks: Option<[u8; TICK_RX_ALLOC]> = client
+                .query_if_changed(response_buffer, TICK_QUERY, &mut self.ticks_etag)
+                .await?;
+
+            match self.read_tick_stats(&raw_stats) {
+                Ok(()) => debug!("Tick Stats Buckets: {}", self.tick_stats.buckets.len()),
+                // The revision still moves on, the same revision would answer with the same stats
+                // and `/changes` would return right away instead of waiting for the next change
+                Err(err) => warn!("Invalid tick stats: {:?}", err),
+            }
+            if let Some(raw_ticks) = raw_ticks {
+                match self.read_ticks(&raw_ticks) {
+                    Ok(()) => debug!("Tick Types: {}", self.ticks.len()),
+                    Err(err) => {
+                        warn!("Invalid tick types: {:?}", err);
+                        self.ticks_etag.clear();
+                    }
+                }
+            }
+            // Only moves on once both were fetched, otherwise they're fetched again
+            self.revision = revision;
+            changed = true;
+        }
+
+        let raw_message: Option<[u8; MESSAGE_SIZE]> = client
+            .query_if_changed(response_buffer, MESSAGE_QUERY, &mut self.message_etag)
+            .await?;
+        if let Some(raw_message) = raw_message {
+            // The body is padded with zeros up to the buffer's size
+            match core::str::from_utf8(&raw_message) {
+                Ok(message) => {
+                    self.message = message.trim_end_matches('\0').parse().unwrap_or_default();
+                    debug!("Message: {}", self.message);
+                    changed = true;
+                }
+                Err(err) => {
+                    warn!("Invalid message: {:?}", err);
+                    self.message_etag.clear();
+                }
+            }
+        }
+
+        let raw_ticks: Option<[u8; TICK_HISTORY_RX_ALLOC]> = client
+            .query_if_changed(
+                response_buffer,
+                TICK_HISTORY_QUERY,
+                &mut self.tick_history_etag,
+            )
+            .await?;
+        if let Some(raw_ticks) = raw_ticks {
+            match self.read_tick_history(&raw_ticks) {
+                Ok(()) => {
+                    debug!("Active: {}", self.active);
+                    debug!("Tick History Size: {}", self.tick_history.len());
+                    changed = true;
+                }
+                Err(err) => {
+                    // Fetched again on the next update instead of waiting for it to change
+                    warn!("Invalid tick history: {:?}", err);
+                    self.tick_history_etag.clear();
+                }
+            }
+        }
+
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::TICK_STATS_BUCKETS;
+    use companion_proto::{
+        encode_tick_history, encode_tick_stats, encode_tick_types, Time, FORMAT_VERSION,
+    };
+    use core::future::Future;
+    use core::task::{Context, Poll, Waker};
+
+    fn tick(type_id: u8, hour: u8, minute: u8) -> HistoryTick {
+        HistoryTick {
+            type_id,
+            time: Time { hour, minute },
+        }
+    }
+
+    struct Response {
+        query: &'static str,
+        etag: &'static str,
+        body: Result<std::vec::Vec<u8>, QueryError>,
+    }
+
+    /// Answers each query with the response set for it, like the server would
+    #[derive(Default)]
+    struct FakeServer {
+        responses: std::vec::Vec<Response>,
+    }
+
+    impl FakeServer {
+        fn answer(
+            &mut self,
+            query: &'static str,
+            etag: &'static str,
+            body: Result<std::vec::Vec<u8>, QueryError>,
+        ) {
+            self.responses.retain(|response| response.query != query);
+            self.responses.push(Response { query, etag, body });
+        }
+
+        fn response<const RX: usize>(
+            &self,
+            query: &str,
+        ) -> (&'static str, Result<[u8; RX], QueryError>) {
+            let response = self
+                .responses
+                .iter()
+                .find(|response| query.starts_with(response.query))
+                .unwrap();
+            let body = response.body.clone().and_then(|body| {
+                // The client can't read bodies past its buffer
+                let mut buffer = [0; RX];
+                buffer
+                    .get_mut(..body.len())
+                    .ok_or(QueryError::TooLarge)?
+                    .copy_from_slice(&body);
+                Ok(buffer)
+            });
+            (response.etag, body)
+        }
+    }
+
+    impl Fetch for FakeServer {
+        async fn query<const RX: usize>(
+            &mut self,
+            _response_buffer: &mut [u8],
+            query: &str,
+        ) -> Result<[u8; RX], QueryError> {
+            self.response(query).1
+        }
+
+        async fn query_if_changed<const RX: usize>(
+            &mut self,
+            _response_buffer: &mut [u8],
+            query: &str,
+            etag: &mut Etag,
+        ) -> Result<Option<[u8; RX]>, QueryError> {
+            let (new_etag, body) = self.response(query);
+            if etag == new_etag {
+                return Ok(None);
+            }
+            let body = body?;
+            *etag = new_etag.parse().unwrap();
+            Ok(Some(body))
+        }
+    }
+
+    /// The fake server answers right away so the futures never have to wait
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = core::pin::pin!(future);
+        let mut context = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
+    fn update(state: &mut ServerState, server: &mut FakeServer) -> Result<bool, QueryError> {
+        block_on(state.update(server, &mut [0; 16]))
+    }
+
+    fn server() -> FakeServer {
+        let mut server = FakeServer::default();
+        server.answer(CHANGES_QUERY, "", Ok(b"1".to_vec()));
+        server.answer(
+            TICK_STATS_QUERY,
+            "",
+            Ok(encode_tick_stats(true, &[1], &[std::vec![3]])),
+        );
+        server.answer(TICK_QUERY, "\"t1\"", Ok(encode_tick_types(&[(1, "Walk")])));
+        server.answer(MESSAGE_QUERY, "\"m1\"", Ok(b"Hello".to_vec()));
+        server.answer(
+            TICK_HISTORY_QUERY,
+            "\"h1\"",
+            Ok(encode_tick_history(true, &[tick(1, 7, 30)])),
+        );
+        server
+    }
+
+    #[test]
+    fn update_keeps_state_on_failed_fetch() {
+        let mut server = server();
+        let mut state = block_on(ServerState::new(&mut server, &mut [0; 16]));
+        assert_eq!(state.tick_name(1), Some("Walk"));
+        assert_eq!(update(&mut state, &mut server), Ok(true));
+        assert_eq!(state.revision, Some(1));
+        assert_eq!(state.message, "Hello");
+        assert_eq!(state.tick_history.len(), 1);
+
+        // Stats past the buffer keep the revision so they're fetched again
+        server.answer(CHANGES_QUERY, "", Ok(b"2".to_vec()));
+        server.answer(
+            TICK_STATS_QUERY,
+            "",
+            Ok(std::vec![0; TICK_STATS_RX_ALLOC + 1]),
+        );
+        server.answer(TICK_QUERY, "\"t2\"", Ok(encode_tick_types(&[(1, "Run")])));
+        assert_eq!(update(&mut state, &mut server), Err(QueryError::TooLarge));
+        assert_eq!(state.revision, Some(1));
+        assert_eq!(state.tick_stats.buckets, [[3]]);
+        assert_eq!(state.tick_name(1), Some("Walk"));
+        assert_eq!(state.ticks_etag, "\"t1\"");
+
+        // A history past the buffer keeps the previous one and its etag
+        server.answer(
+            TICK_STATS_QUERY,
+            "",
+            Ok(encode_tick_stats(true, &[1], &[std::vec![4]])),
+        );
+        server.answer(
+            TICK_HISTORY_QUERY,
+            "\"h2\"",
+            Ok(std::vec![0; TICK_HISTORY_RX_ALLOC + 1]),
+        );
+        assert_eq!(update(&mut state, &mut server), Err(QueryError::TooLarge));
+        assert_eq!(state.revision, Some(2));
+        assert_eq!(state.tick_stats.buckets, [[4]]);
+        assert_eq!(state.tick_name(1), Some("Run"));
+        assert_eq!(state.tick_history.len(), 1);
+        assert_eq!(state.tick_history_etag, "\"h1\"");
+
+        // Nothing changes while the server can't be reached
+        server.answer(CHANGES_QUERY, "", Err(QueryError::Request));
+        assert_eq!(update(&mut state, &mut server), Err(QueryError::Request));
+        assert_eq!(state.revision, Some(2));
+        assert_eq!(state.message, "Hello");
+
+        server.answer(CHANGES_QUERY, "", Ok(b"2".to_vec()));
+        server.answer(
+            TICK_HISTORY_QUERY,
+            "\"h2\"",
+            Ok(encode_tick_history(false, &[tick(1, 7, 30), tick(1, 9, 0)])),
+        );
+        assert_eq!(update(&mut state, &mut server), Ok(true));
+        assert!(!state.active);
+        assert_eq!(state.tick_history.len(), 2);
+        assert_eq!(state.tick_history_etag, "\"h2\"");
+        assert_eq!(update(&mut state, &mut server), Ok(false));
+    }
+
+    #[test]
+    fn update_skips_unreadable_responses() {
+        let mut server = server();
+        let mut state = ServerState::empty();
+        assert_eq!(update(&mut state, &mut server), Ok(true));
+
+        // Stats that can't be read still move the revision on, the history is read again
+        server.answer(CHANGES_QUERY, "", Ok(b"2".to_vec()));
+        server.answer(TICK_STATS_QUERY, "", Ok(std::vec![FORMAT_VERSION + 1]));
+        server.answer(
+            TICK_HISTORY_QUERY,
+            "\"h2\"",
+            Ok(encode_tick_history(true, &[tick(2, 99, 0)])),
+        );
+        assert_eq!(update(&mut state, &mut server), Ok(true));
+        assert_eq!(state.revision, Some(2));
+        assert_eq!(state.tick_stats.buckets, [[3]]);
+        assert_eq!(state.tick_history.len(), 1);
+        assert!(state.tick_history_etag.is_empty());
+    }
+
+    #[test]
+    fn read_compressed_responses() {
+        let mut state = ServerState::empty();
+        let raw_ticks = encode_tick_history(false, &[tick(1, 7, 30)]);
+        state.read_tick_history(&raw_ticks).unwrap();
+        assert!(!state.active);
+        assert_eq!(state.tick_history.len(), 1);
+
+        // Nothing changes when a response can't be read
+        let invalid = encode_tick_history(true, &[tick(1, 7, 30), tick(2, 99, 0)]);
+        assert_eq!(
+            state.read_tick_history(&invalid).unwrap_err(),
+            DecodeError::InvalidTime {
+                hour: 99,
+                minute: 0
+            }
+        );
+        assert!(!state.active);
+        assert_eq!(state.tick_history.len(), 1);
+
+        assert_eq!(
+            state.read_tick_stats(&[FORMAT_VERSION, 1, 0]).unwrap_err(),
+            DecodeError::Truncated
+        );
+        assert_eq!(
+            state.read_tick_stats(&[FORMAT_VERSION + 1]).unwrap_err(),
+            DecodeError::UnsupportedVersion(FORMAT_VERSION + 1)
+        );
+        assert!(!state.active);
+        state
+            .read_tick_stats(&encode_tick_stats(true, &[4], &[std::vec![3]]))
+            .unwrap();
+        assert!(state.active);
+        assert_eq!(state.tick_stats.buckets, [[3]]);
+    }
+
+    #[test]
+    fn read_tick_stats_past_tick_alloc() {
+        // Pairs can have more tick types than the device has room for
+        let type_ids: std::vec::Vec<u8> = (0..TICK_ALLOC as u8 + 1).collect();
+        let buckets: std::vec::Vec<_> = (0..TICK_STATS_BUCKETS as u16)
+            .map(|day| type_ids.iter().map(|id| day * 100 + *id as u16).collect())
+            .collect();
+        let mut state = ServerState::empty();
+        state
+            .read_tick_stats(&encode_tick_stats(false, &type_ids, &buckets))
+            .unwrap();
+        assert!(!state.active);
+        assert_eq!(state.tick_stats.type_ids, type_ids[..TICK_ALLOC]);
+        assert_eq!(state.tick_stats.buckets.len(), TICK_STATS_BUCKETS);
+        for (decoded, bucket) in state.tick_stats.buckets.iter().zip(&buckets) {
+            assert_eq!(decoded, &bucket[..TICK_ALLOC]);
         }
     }
 }